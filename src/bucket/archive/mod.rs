@@ -1,6 +1,6 @@
 use crate::unix_timestamp::UnixTimestamp;
 
-use super::{bucket_compression::BucketCompression, bucket_guid::BucketGuid, bucket_path::BucketRelativePath};
+use super::{bucket_compression::BucketCompression, bucket_guid::BucketGuid, bucket_path::BucketObjectPath};
 
 
 pub struct BucketMetadata {
@@ -16,14 +16,14 @@ pub struct BucketObjectMetadata {
     pub created_at: UnixTimestamp,
     pub size: u64,
     pub encoding: ObjectEncoding,
-    pub path: BucketRelativePath,
+    pub path: BucketObjectPath,
     pub hashes: ObjectHashes,
 }
 
 
 pub struct VirtualZipMetadate {
     pub compression_level: u8,
-    pub compressed_parts: Vec<BucketRelativePath>,
+    pub compressed_parts: Vec<BucketObjectPath>,
     pub compression_algorithm: BucketCompression,
 }
 
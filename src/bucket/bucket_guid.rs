@@ -1,7 +1,13 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use uuid::{Builder, Uuid};
+#[cfg(feature = "unix_timestamp")]
+use crate::unix_timestamp::UnixTimestamp;
 
 // BucketGuid is a combination between user_id and bucket_id.
 // Max character length of 63 for aws s3 bucket name https://docs.aws.amazon.com/AmazonS3/latest/userguide/bucketnamingrules.html.
@@ -14,12 +20,14 @@ pub struct BucketGuid {
 /// Enum to specify different formats for displaying/parsing BucketGuid,
 /// It will use the specified underlying UUID format, but will combine the UUIDs in different format depending on whether it is using
 /// Hyphenated or Simple
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BucketGuidFormat {
     Hyphenated(UuidFormat),
     Simple(UuidFormat),
 }
 
 /// Enum to specify different formats for displaying/parsing UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UuidFormat {
     Hyphenated,
     Simple,
@@ -36,6 +44,49 @@ impl UuidFormat {
             UuidFormat::Urn => write!(f, "{}", uuid.urn()),
         }
     }
+
+    pub const ALL: [UuidFormat; 4] = [UuidFormat::Hyphenated, UuidFormat::Simple, UuidFormat::Braced, UuidFormat::Urn];
+
+    /// Length of a UUID in this format.
+    pub const fn encoded_len(&self) -> usize {
+        match self {
+            UuidFormat::Hyphenated => uuid::fmt::Hyphenated::LENGTH,
+            UuidFormat::Simple => uuid::fmt::Simple::LENGTH,
+            UuidFormat::Braced => uuid::fmt::Braced::LENGTH,
+            UuidFormat::Urn => uuid::fmt::Urn::LENGTH,
+        }
+    }
+
+    /// Parses a UUID that is exactly in this format, hex digits may be upper or lower case.
+    pub fn parse_uuid(&self, s: &str) -> Result<Uuid, BucketGuidParseError> {
+        // Every format has its own length, and `Uuid::try_parse` only accepts the layout that matches the length.
+        if s.len() != self.encoded_len() {
+            return Err(BucketGuidParseError::InvalidLength);
+        }
+        Uuid::try_parse(s).map_err(BucketGuidParseError::UuidParserFailed)
+    }
+}
+
+impl BucketGuidFormat {
+    /// Every combination of `BucketGuidFormat` and `UuidFormat`, no two of them have the same length.
+    pub const ALL: [BucketGuidFormat; 8] = [
+        BucketGuidFormat::Hyphenated(UuidFormat::Hyphenated),
+        BucketGuidFormat::Hyphenated(UuidFormat::Simple),
+        BucketGuidFormat::Hyphenated(UuidFormat::Braced),
+        BucketGuidFormat::Hyphenated(UuidFormat::Urn),
+        BucketGuidFormat::Simple(UuidFormat::Hyphenated),
+        BucketGuidFormat::Simple(UuidFormat::Simple),
+        BucketGuidFormat::Simple(UuidFormat::Braced),
+        BucketGuidFormat::Simple(UuidFormat::Urn),
+    ];
+
+    /// Length of a `BucketGuid` in this format.
+    pub const fn encoded_len(&self) -> usize {
+        match self {
+            BucketGuidFormat::Hyphenated(uuid_format) => uuid_format.encoded_len() * 2 + 1,
+            BucketGuidFormat::Simple(uuid_format) => uuid_format.encoded_len() * 2,
+        }
+    }
 }
 
 impl fmt::Display for BucketGuid {
@@ -53,6 +104,15 @@ impl BucketGuid {
             slice
     }
 
+    /// Inverse of `to_bytes`.
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let (user_id, bucket_id) = bytes.split_at(16);
+        Self {
+            user_id: Uuid::from_slice(user_id).expect("16 bytes"),
+            bucket_id: Uuid::from_slice(bucket_id).expect("16 bytes"),
+        }
+    }
+
     /// Format the BucketGuid using the specified format.
     pub fn fmt_with(&self, f: &mut fmt::Formatter<'_>, format: BucketGuidFormat) -> fmt::Result {
         match format {
@@ -67,6 +127,52 @@ impl BucketGuid {
             }
         }
     }
+
+    /// Formats the BucketGuid using the specified format.
+    pub fn to_string_with(&self, format: BucketGuidFormat) -> String {
+        struct Formatted<'a>(&'a BucketGuid, BucketGuidFormat);
+
+        impl fmt::Display for Formatted<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt_with(f, self.1)
+            }
+        }
+
+        Formatted(self, format).to_string()
+    }
+
+    /// Parses a BucketGuid that is exactly in `format`.
+    pub fn parse_with(s: &str, format: BucketGuidFormat) -> Result<Self, BucketGuidParseError> {
+        if s.len() != format.encoded_len() || !s.is_ascii() {
+            return Err(BucketGuidParseError::InvalidLength);
+        }
+        let (user_id, bucket_id, uuid_format) = match format {
+            BucketGuidFormat::Hyphenated(uuid_format) => {
+                let (user_id, rest) = s.split_at(uuid_format.encoded_len());
+                let bucket_id = rest.strip_prefix('-').ok_or(BucketGuidParseError::MissingSeparator)?;
+                (user_id, bucket_id, uuid_format)
+            }
+            BucketGuidFormat::Simple(uuid_format) => {
+                let (user_id, bucket_id) = s.split_at(uuid_format.encoded_len());
+                (user_id, bucket_id, uuid_format)
+            }
+        };
+        Ok(Self {
+            user_id: uuid_format.parse_uuid(user_id)?,
+            bucket_id: uuid_format.parse_uuid(bucket_id)?,
+        })
+    }
+
+    /// Parses a BucketGuid in any `BucketGuidFormat`, the format is detected from the length.
+    /// Surrounding whitespace is ignored.
+    pub fn parse_lenient(s: &str) -> Result<Self, BucketGuidParseError> {
+        let s = s.trim();
+        let format = BucketGuidFormat::ALL
+            .into_iter()
+            .find(|format| format.encoded_len() == s.len())
+            .ok_or(BucketGuidParseError::InvalidLength)?;
+        Self::parse_with(s, format)
+    }
 }
 
 
@@ -75,13 +181,31 @@ impl BucketGuid {
         Self { user_id, bucket_id }
     }
 
+    /// Generates a new user and bucket, the bucket_id is time-ordered, see `generate_for_user`.
     pub fn generate() -> Self {
+        Self::generate_for_user(Uuid::new_v4())
+    }
+
+    /// Generates a new bucket of `user_id`, the bucket_id is a UUIDv7.
+    /// Bucket ids generated later sort after earlier ones, so inserts append to B-tree indexes instead of fragmenting them.
+    pub fn generate_for_user(user_id: Uuid) -> Self {
         Self {
-            user_id: Uuid::new_v4(),
-            bucket_id: Uuid::new_v4(),
+            user_id,
+            bucket_id: next_v7(),
         }
     }
 
+    /// When the bucket was created, `None` if the bucket_id isn't time-ordered (v4 from before v7 generation).
+    #[cfg(feature = "unix_timestamp")]
+    pub fn created_at(&self) -> Option<UnixTimestamp> {
+        let (seconds, nanos) = self.bucket_id.get_timestamp()?.to_unix();
+        let created_at = time::OffsetDateTime::from_unix_timestamp(seconds as i64)
+            .ok()?
+            .replace_nanosecond(nanos)
+            .ok()?;
+        Some(UnixTimestamp(created_at))
+    }
+
     // Define the size of a ``BucketGuid`` in bytes.
     pub const fn size() -> usize {
         // Since each UUID is 16 bytes, the total length is 32 bytes
@@ -90,17 +214,59 @@ impl BucketGuid {
 }
 
 
+/// Millisecond of the last v7 bucket_id, and the counter within it.
+static V7_STATE: Mutex<(u64, u16)> = Mutex::new((0, 0));
+
+/// Largest value of the 12 bit counter in `rand_a`.
+const V7_COUNTER_MAX: u16 = 0x0FFF;
+
+/// UUIDv7 with a per-process monotonic counter (RFC 9562 method 1), ids from this process are strictly increasing.
+/// The counter starts at a random value below half its range every millisecond, when it overflows the timestamp
+/// is advanced by a millisecond instead of going back.
+fn next_v7() -> Uuid {
+    let mut random = [0u8; 10];
+    rand::thread_rng().fill_bytes(&mut random);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64);
+
+    let mut state = V7_STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (last_millis, counter) = *state;
+    *state = if now > last_millis {
+        (now, u16::from_be_bytes([random[0], random[1]]) & (V7_COUNTER_MAX >> 1))
+    } else if counter < V7_COUNTER_MAX {
+        (last_millis, counter + 1)
+    } else {
+        (last_millis + 1, 0)
+    };
+    let (millis, counter) = *state;
+    drop(state);
+
+    random[..2].copy_from_slice(&counter.to_be_bytes());
+    Builder::from_unix_timestamp_millis(millis, &random).into_uuid()
+}
+
+/// Sorts by bucket_id first, so time-ordered buckets sort by creation time. Buckets with v4 ids sort in an arbitrary but stable order.
+impl Ord for BucketGuid {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bucket_id
+            .cmp(&other.bucket_id)
+            .then_with(|| self.user_id.cmp(&other.user_id))
+    }
+}
+
+impl PartialOrd for BucketGuid {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Lenient, accepts every `BucketGuidFormat`, see `BucketGuid::parse_lenient`.
+/// Use `BucketGuid::parse_with` to only accept one format.
 impl FromStr for BucketGuid {
     type Err = BucketGuidParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // +1 to accommodate for optional hyphen `-` in the middle between user_id and bucket_id.
-        if s.len() != BucketGuid::size() || s.len() != (BucketGuid::size() + 1) {
-         return Err(BucketGuidParseError::InvalidLength)
-        };
-        let user_id = Uuid::parse_str(&s[..36]).map_err(BucketGuidParseError::UuidParserFailed)?;
-        let bucket_id = Uuid::parse_str(&s[36..]).map_err(BucketGuidParseError::UuidParserFailed)?;
-        Ok(Self { user_id, bucket_id })
-
+        Self::parse_lenient(s)
     }
 }
 
@@ -111,9 +277,14 @@ pub enum BucketGuidParseError {
 
     #[error("Failed to parse UUID: {0}")]
     UuidParserFailed(#[source] uuid::Error),
+
+    #[error("Missing `-` between user_id and bucket_id.")]
+    MissingSeparator,
 }
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -161,4 +332,78 @@ mod tests {
     fn test_size() {
         assert_eq!(BucketGuid::size(), 32);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_display_round_trips() {
+        let bucket_guid = BucketGuid::generate();
+        assert_eq!(BucketGuid::from_str(&bucket_guid.to_string()), Ok(bucket_guid.clone()));
+        assert_eq!(
+            BucketGuid::parse_with(&bucket_guid.to_string(), BucketGuidFormat::Hyphenated(UuidFormat::Simple)),
+            Ok(bucket_guid)
+        );
+    }
+
+    #[test]
+    fn test_strict_rejects_other_formats() {
+        let user_id = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap();
+        let bucket_id = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let bucket_guid = BucketGuid::new(user_id, bucket_id);
+        let simple = "550e8400e29b41d4a716446655440000_67e5504410b1426f9247bb680e5fe0c8";
+        assert_eq!(
+            BucketGuid::parse_with(simple, BucketGuidFormat::Hyphenated(UuidFormat::Simple)),
+            Err(BucketGuidParseError::MissingSeparator)
+        );
+        assert_eq!(
+            BucketGuid::parse_with(&bucket_guid.to_string(), BucketGuidFormat::Simple(UuidFormat::Simple)),
+            Err(BucketGuidParseError::InvalidLength)
+        );
+        // Same length as a hyphenated UUID, but not hyphenated.
+        let misplaced = "550e8400e-29b-41d4-a716-446655440000-67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert!(matches!(
+            BucketGuid::parse_with(misplaced, BucketGuidFormat::Hyphenated(UuidFormat::Hyphenated)),
+            Err(BucketGuidParseError::UuidParserFailed(_))
+        ));
+        assert_eq!(
+            BucketGuid::from_str("  550E8400E29B41D4A716446655440000-67E5504410B1426F9247BB680E5FE0C8\n"),
+            Ok(bucket_guid)
+        );
+        assert_eq!(BucketGuid::from_str(""), Err(BucketGuidParseError::InvalidLength));
+    }
+
+    fn bucket_guid_format() -> impl Strategy<Value = BucketGuidFormat> {
+        (0..BucketGuidFormat::ALL.len()).prop_map(|index| BucketGuidFormat::ALL[index])
+    }
+
+    #[test]
+    fn test_v7_bucket_ids_are_monotonic() {
+        let user_id = Uuid::new_v4();
+        let guids: Vec<BucketGuid> = (0..10_000).map(|_| BucketGuid::generate_for_user(user_id)).collect();
+        assert!(guids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(guids.iter().all(|guid| guid.bucket_id.get_version_num() == 7 && guid.user_id == user_id));
+    }
+
+    #[cfg(feature = "unix_timestamp")]
+    #[test]
+    fn test_created_at() {
+        let before = time::OffsetDateTime::now_utc() - time::Duration::milliseconds(1);
+        let created_at = BucketGuid::generate().created_at().unwrap().0;
+        assert!(created_at >= before && created_at <= time::OffsetDateTime::now_utc() + time::Duration::seconds(1));
+        assert_eq!(BucketGuid::new(Uuid::new_v4(), Uuid::new_v4()).created_at(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_format_round_trips(user_id in any::<u128>(), bucket_id in any::<u128>(), format in bucket_guid_format()) {
+            let bucket_guid = BucketGuid::new(Uuid::from_u128(user_id), Uuid::from_u128(bucket_id));
+            let formatted = bucket_guid.to_string_with(format);
+            prop_assert_eq!(formatted.len(), format.encoded_len());
+            prop_assert_eq!(BucketGuid::parse_with(&formatted, format), Ok(bucket_guid.clone()));
+            prop_assert_eq!(BucketGuid::from_str(&formatted), Ok(bucket_guid));
+        }
+
+        #[test]
+        fn prop_parse_never_panics(input in "\\PC*") {
+            let _ = BucketGuid::from_str(&input);
+        }
+    }
+}
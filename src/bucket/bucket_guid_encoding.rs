@@ -0,0 +1,324 @@
+/*
+* Compact encodings of `BucketGuid`, the default `Display` is 65 characters which is too long for a subdomain label (63) or a share URL.
+*
+* | Encoding              | Length |
+* |-----------------------|--------|
+* | Binary                | 32     |
+* | Crockford base32      | 52     |
+* | Crockford with check  | 53     |
+* | Base58                | <= 44  |
+* | Base58Check           | <= 49  |
+* | Short alias           | 16     |
+*
+* The serde adapters in this module pick the encoding per field: `#[serde(with = "bucket_guid_encoding::base58")]`.
+*/
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::bucket::bucket_guid::BucketGuid;
+use crate::encoding::crockford::{self, CrockfordError};
+
+/// Derivation context of the short alias, changing it changes every alias.
+const SHORT_ALIAS_CONTEXT: &str = "bucketdrive.co 2024-11-01 bucket short alias v1";
+
+/// Bytes of the hash kept in a short alias, 80 bits is 16 Crockford symbols.
+const SHORT_ALIAS_BYTES: usize = 10;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BucketGuidDecodeError {
+    #[error("Decoded {0} bytes, a BucketGuid is 32 bytes")]
+    InvalidLength(usize),
+    #[error(transparent)]
+    Crockford(#[from] CrockfordError),
+    #[error(transparent)]
+    Base58(#[from] bs58::decode::Error),
+}
+
+fn from_decoded(bytes: Vec<u8>) -> Result<BucketGuid, BucketGuidDecodeError> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| BucketGuidDecodeError::InvalidLength(bytes.len()))?;
+    Ok(BucketGuid::from_bytes(&bytes))
+}
+
+impl BucketGuid {
+    /// Crockford base32 of the 32 bytes, upper case. Decoding ignores case, so it can be lower cased for a subdomain.
+    pub fn to_crockford(&self) -> String {
+        crockford::encode(&self.to_bytes())
+    }
+
+    /// `to_crockford` with a trailing check symbol that catches typos.
+    pub fn to_crockford_with_check(&self) -> String {
+        crockford::encode_with_check(&self.to_bytes())
+    }
+
+    pub fn from_crockford(encoded: &str) -> Result<Self, BucketGuidDecodeError> {
+        from_decoded(crockford::decode(encoded)?)
+    }
+
+    pub fn from_crockford_with_check(encoded: &str) -> Result<Self, BucketGuidDecodeError> {
+        from_decoded(crockford::decode_with_check(encoded)?)
+    }
+
+    /// Bitcoin alphabet base58, the shortest text encoding.
+    pub fn to_base58(&self) -> String {
+        bs58::encode(self.to_bytes()).into_string()
+    }
+
+    /// Base58Check, base58 with a 4 byte double SHA-256 checksum.
+    pub fn to_base58_check(&self) -> String {
+        bs58::encode(self.to_bytes()).with_check().into_string()
+    }
+
+    pub fn from_base58(encoded: &str) -> Result<Self, BucketGuidDecodeError> {
+        from_decoded(bs58::decode(encoded).into_vec()?)
+    }
+
+    pub fn from_base58_check(encoded: &str) -> Result<Self, BucketGuidDecodeError> {
+        from_decoded(bs58::decode(encoded).with_check(None).into_vec()?)
+    }
+
+    /// Short alias of this guid, see `ShortAlias`.
+    pub fn short_alias(&self) -> ShortAlias {
+        let hash = blake3::derive_key(SHORT_ALIAS_CONTEXT, &self.to_bytes());
+        let mut alias = [0u8; SHORT_ALIAS_BYTES];
+        alias.copy_from_slice(&hash[..SHORT_ALIAS_BYTES]);
+        ShortAlias(alias)
+    }
+}
+
+/// A 16 character alias derived from a `BucketGuid`, the same guid always gets the same alias.
+/// It's a truncated hash, so it can't be decoded back, resolve it through a `ShortAliasIndex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShortAlias([u8; SHORT_ALIAS_BYTES]);
+
+impl Display for ShortAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&crockford::encode(&self.0).to_ascii_lowercase())
+    }
+}
+
+impl FromStr for ShortAlias {
+    type Err = BucketGuidDecodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = crockford::decode(s)?;
+        let length = bytes.len();
+        Ok(Self(bytes.try_into().map_err(|_| BucketGuidDecodeError::InvalidLength(length))?))
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Short alias {alias} of {guid} is already used by {existing}")]
+pub struct ShortAliasCollision {
+    pub alias: ShortAlias,
+    pub guid: BucketGuid,
+    pub existing: BucketGuid,
+}
+
+/// Resolves short aliases, and refuses a guid whose alias is already taken by another guid.
+#[derive(Debug, Default, Clone)]
+pub struct ShortAliasIndex {
+    aliases: HashMap<ShortAlias, BucketGuid>,
+}
+
+impl ShortAliasIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the guid and returns its alias, adding the same guid again is a no-op.
+    pub fn insert(&mut self, guid: &BucketGuid) -> Result<ShortAlias, ShortAliasCollision> {
+        let alias = guid.short_alias();
+        match self.aliases.get(&alias) {
+            Some(existing) if existing != guid => Err(ShortAliasCollision {
+                alias,
+                guid: guid.clone(),
+                existing: existing.clone(),
+            }),
+            Some(_) => Ok(alias),
+            None => {
+                self.aliases.insert(alias, guid.clone());
+                Ok(alias)
+            }
+        }
+    }
+
+    pub fn resolve(&self, alias: &ShortAlias) -> Option<&BucketGuid> {
+        self.aliases.get(alias)
+    }
+
+    pub fn remove(&mut self, guid: &BucketGuid) -> bool {
+        let alias = guid.short_alias();
+        if self.aliases.get(&alias) == Some(guid) {
+            self.aliases.remove(&alias);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Implements a serde adapter module that writes the guid as a string.
+macro_rules! string_adapter {
+    ($name:ident, $encode:ident, $decode:ident) => {
+        pub mod $name {
+            use serde::{Deserialize, Deserializer, Serializer};
+
+            use crate::bucket::bucket_guid::BucketGuid;
+
+            pub fn serialize<S: Serializer>(guid: &BucketGuid, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&guid.$encode())
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BucketGuid, D::Error> {
+                let encoded = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+                BucketGuid::$decode(&encoded).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+string_adapter!(crockford_base32, to_crockford, from_crockford);
+string_adapter!(crockford_base32_check, to_crockford_with_check, from_crockford_with_check);
+string_adapter!(base58, to_base58, from_base58);
+string_adapter!(base58_check, to_base58_check, from_base58_check);
+
+/// Serde adapter that writes the guid as 32 raw bytes.
+pub mod bytes {
+    use std::fmt;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    use crate::bucket::bucket_guid::BucketGuid;
+
+    pub fn serialize<S: Serializer>(guid: &BucketGuid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&guid.to_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BucketGuid, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = BucketGuid;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("32 bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+            let bytes: [u8; 32] = value.try_into().map_err(|_| E::invalid_length(value.len(), &self))?;
+            Ok(BucketGuid::from_bytes(&bytes))
+        }
+
+        // Formats without a byte type, like JSON, write bytes as a sequence.
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = [0u8; 32];
+            for (index, byte) in bytes.iter_mut().enumerate() {
+                *byte = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(index, &self))?;
+            }
+            if seq.next_element::<u8>()?.is_some() {
+                return Err(de::Error::invalid_length(33, &self));
+            }
+            Ok(BucketGuid::from_bytes(&bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Encoded {
+        #[serde(with = "bytes")]
+        binary: BucketGuid,
+        #[serde(with = "crockford_base32_check")]
+        crockford: BucketGuid,
+        #[serde(with = "base58")]
+        base58: BucketGuid,
+    }
+
+    #[test]
+    fn test_round_trips() {
+        for _ in 0..32 {
+            let guid = BucketGuid::generate();
+            assert_eq!(guid.to_crockford().len(), 52);
+            assert_eq!(guid.to_crockford_with_check().len(), 53);
+            assert!(guid.to_base58().len() <= 44);
+            assert_eq!(BucketGuid::from_crockford(&guid.to_crockford().to_ascii_lowercase()), Ok(guid.clone()));
+            assert_eq!(BucketGuid::from_crockford_with_check(&guid.to_crockford_with_check()), Ok(guid.clone()));
+            assert_eq!(BucketGuid::from_base58(&guid.to_base58()), Ok(guid.clone()));
+            assert_eq!(BucketGuid::from_base58_check(&guid.to_base58_check()), Ok(guid.clone()));
+        }
+    }
+
+    #[test]
+    fn test_checksums_catch_typos() {
+        let guid = BucketGuid::generate();
+        let mut crockford = guid.to_crockford_with_check().into_bytes();
+        crockford[3] = if crockford[3] == b'A' { b'B' } else { b'A' };
+        assert_eq!(
+            BucketGuid::from_crockford_with_check(std::str::from_utf8(&crockford).unwrap()),
+            Err(BucketGuidDecodeError::Crockford(CrockfordError::CheckSymbolMismatch))
+        );
+
+        let mut base58 = guid.to_base58_check().into_bytes();
+        base58[3] = if base58[3] == b'A' { b'B' } else { b'A' };
+        assert!(matches!(
+            BucketGuid::from_base58_check(std::str::from_utf8(&base58).unwrap()),
+            Err(BucketGuidDecodeError::Base58(_))
+        ));
+        assert_eq!(BucketGuid::from_base58("2g"), Err(BucketGuidDecodeError::InvalidLength(1)));
+    }
+
+    #[test]
+    fn test_serde_adapters() {
+        let guid = BucketGuid::generate();
+        let encoded = Encoded {
+            binary: guid.clone(),
+            crockford: guid.clone(),
+            base58: guid.clone(),
+        };
+        let bincode = bincode::serialize(&encoded).unwrap();
+        assert_eq!(bincode::deserialize::<Encoded>(&bincode).unwrap(), encoded);
+        let json = serde_json::to_string(&encoded).unwrap();
+        assert!(json.contains(&guid.to_base58()));
+        assert_eq!(serde_json::from_str::<Encoded>(&json).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_short_alias() {
+        let guid = BucketGuid::generate();
+        let alias = guid.short_alias();
+        assert_eq!(alias, guid.short_alias());
+        assert_eq!(alias.to_string().len(), 16);
+        assert_eq!(alias.to_string().parse::<ShortAlias>(), Ok(alias));
+
+        let mut index = ShortAliasIndex::new();
+        assert_eq!(index.insert(&guid), Ok(alias));
+        assert_eq!(index.insert(&guid), Ok(alias));
+        assert_eq!(index.resolve(&alias), Some(&guid));
+
+        // Force a collision, a real one needs around 2^40 guids.
+        let other = BucketGuid::generate();
+        index.aliases.insert(other.short_alias(), guid.clone());
+        assert_eq!(
+            index.insert(&other),
+            Err(ShortAliasCollision {
+                alias: other.short_alias(),
+                guid: other.clone(),
+                existing: guid.clone(),
+            })
+        );
+        assert!(index.remove(&guid));
+        assert_eq!(index.resolve(&alias), None);
+    }
+}
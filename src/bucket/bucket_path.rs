@@ -1,10 +1,13 @@
 use crate::bucket::bucket_guid::BucketGuid;
-use std::fmt::Debug;
+use crate::bucket::bucket_path_matcher::BucketPathPatternSet;
+use std::fmt::{self, Debug, Display};
 use std::str::FromStr;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use unicode_normalization::{is_nfkc_quick, IsNormalized, UnicodeNormalization};
 
 // Path implementation for bucket.
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BucketAbsolutePath {
     pub bucket_guid: BucketGuid,
     /// Relative path from BucketGuid, they are combined inorder to create absolute path.
@@ -21,75 +24,66 @@ impl BucketAbsolutePath {
     }
 }
 
+/// Paths of one bucket grouped under a common directory, the paths are relative to the prefix.
+/// Used to send listings without repeating the prefix for every entry.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BucketCommonPrefixedPath {
     prefix: BucketAbsolutePath, 
     paths: Vec<BucketRelativePath>,
 }
 
-/// Must contain a file.
-/// More restrictive than ``BucketRelativePath`` which only restrict the path to start with a forward slash.
-#[derive(Debug, Eq, PartialEq)]
-struct BucketRelativeAbsolutePath {
-    pub path: String,
-}
-
-#[derive(thiserror::Error, Debug, PartialEq)]
-pub enum BucketRelativeAbsolutePathError {
-    #[error("PathMustStartWithForwardSlash")]
-    PathMustStartWithForwardSlash,
-    #[error("Path contains invalid character at position {position}: '{invalid_char}'")]
-    PathContainsInvalidCharacter { position: usize, invalid_char: char },
-    #[error("Path mustn't be longer than {0}", BUCKET_RELATIVE_PATH_MAX_LENGTH)]
-    RelativePathTooLong,
-    #[error("Path does not contain file.something")]
-    PathNotAbsolute,
-}
-
-impl FromStr for BucketRelativeAbsolutePath {
-    type Err = BucketRelativeAbsolutePathError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // Check if the path starts with a forward slash '/'
-        if !s.starts_with('/') {
-            return Err(BucketRelativeAbsolutePathError::PathMustStartWithForwardSlash);
-        };
-        // check path max length
-        if s.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
-            return Err(BucketRelativeAbsolutePathError::RelativePathTooLong);
+impl BucketCommonPrefixedPath {
+    /// `paths` are relative to `prefix`, fails when a joined path is too long.
+    pub fn new(prefix: BucketAbsolutePath, paths: Vec<BucketRelativePath>) -> Result<Self, BucketRelativePathParserError> {
+        for path in &paths {
+            prefix.relative_path.join_path(path)?;
         }
-        // Get the last segment
-        let file_name = match s.rsplit_once('/') {
-            Some((_, name)) if !name.is_empty() => name,
-            _ => return Err(BucketRelativeAbsolutePathError::PathNotAbsolute),
-        };
+        Ok(Self { prefix, paths })
+    }
 
-        // Check if there's a valid extension
-        if !file_name.contains('.') || file_name.ends_with('.') {
-            return Err(BucketRelativeAbsolutePathError::PathNotAbsolute);
+    /// Groups the paths of a bucket under their deepest common directory.
+    pub fn from_paths(bucket_guid: BucketGuid, paths: &[BucketRelativePath]) -> Self {
+        let mut prefix = paths.first().and_then(|path| path.parent()).unwrap_or_else(BucketRelativePath::root);
+        while !paths.iter().all(|path| path.starts_with(&prefix)) {
+            prefix = prefix.parent().unwrap_or_else(BucketRelativePath::root);
         }
-        // Check for invalid characters
-        for (index, c) in s.chars().enumerate() {
-            if !(c.is_alphanumeric() || c == '/' || c.is_numeric() || c == '-' || c == '_' || c == '.') {
-                return Err(BucketRelativeAbsolutePathError::PathContainsInvalidCharacter {
-                    position: index,
-                    invalid_char: c,
-                });
-            }
+        let paths = paths.iter().filter_map(|path| path.strip_prefix(&prefix)).collect();
+        Self {
+            prefix: BucketAbsolutePath::new(bucket_guid, prefix),
+            paths,
         }
+    }
 
-        Ok(Self {
-            path: s.to_string(),
-        })
+    pub fn prefix(&self) -> &BucketAbsolutePath {
+        &self.prefix
+    }
+
+    pub fn paths(&self) -> &[BucketRelativePath] {
+        &self.paths
+    }
+
+    /// The paths joined with the prefix, relative to the bucket root.
+    pub fn full_paths(&self) -> impl Iterator<Item = BucketRelativePath> + '_ {
+        // The lengths were checked when the paths were added.
+        self.paths.iter().map(|path| self.prefix.relative_path.join_path(path).unwrap())
+    }
+
+    /// Keeps only the paths whose full path matches one of `patterns`.
+    pub fn retain_matching(&mut self, patterns: &BucketPathPatternSet) {
+        let prefix = &self.prefix.relative_path;
+        self.paths.retain(|path| prefix.join_path(path).is_ok_and(|full_path| patterns.is_match(&full_path)));
     }
 }
 
 /// The relative path from the bucket guid,
-/// Every relative path starts with ``/``
-/// Only alphanumeric and numbers are allowed and "-", "_"
+/// Every relative path starts with ``/``, ``/`` alone is the bucket root.
+/// Segments are separated by a single ``/`` and can't be empty, ``.`` or ``..``, so a path can never point outside the bucket.
+/// Only alphanumeric characters and "-", "_", "." are allowed, the path is stored NFC normalized,
+/// and characters that look like other characters (compatibility forms, mixed Latin/Greek/Cyrillic in a segment) are rejected.
 /// Relative path can be combined with BucketGuid to create an BucketAbsolutePath.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, SerializeDisplay, DeserializeFromStr)]
 pub struct BucketRelativePath  {
-    pub path: String,
+    path: String,
 }
 
 pub const BUCKET_RELATIVE_PATH_MAX_LENGTH: usize = 1024 - BucketGuid::size();
@@ -103,6 +97,69 @@ pub enum BucketRelativePathParserError {
     PathContainsInvalidCharacter { position: usize, invalid_char: char },
     #[error("Path mustn't be longer than {0}", BUCKET_RELATIVE_PATH_MAX_LENGTH)]
     RelativePathTooLong,
+    #[error("Path contains an empty segment at position {position}")]
+    EmptySegment { position: usize },
+    #[error("Path contains a '.' or '..' segment at position {position}")]
+    DotSegment { position: usize },
+    #[error("Path contains a confusable character at position {position}: '{invalid_char}'")]
+    ConfusableCharacter { position: usize, invalid_char: char },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+/// Scripts whose letters are commonly mistaken for each other, a segment must only use one of them.
+fn confusable_script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Some(Script::Greek),
+        '\u{0400}'..='\u{052F}' => Some(Script::Cyrillic),
+        _ => None,
+    }
+}
+
+/// Validates the segments of `path`, positions are character positions counted from `offset`.
+/// Every segment is preceded by a `/`, `path` is NFC normalized.
+fn validate_segments(path: &str, offset: usize) -> Result<(), BucketRelativePathParserError> {
+    let mut position = offset;
+    for segment in path.split('/').skip(1) {
+        // Position of the segment, after its leading '/'.
+        position += 1;
+        if segment.is_empty() {
+            return Err(BucketRelativePathParserError::EmptySegment { position });
+        }
+        if segment == "." || segment == ".." {
+            return Err(BucketRelativePathParserError::DotSegment { position });
+        }
+        let mut segment_script = None;
+        for (index, c) in segment.chars().enumerate() {
+            if !(c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+                return Err(BucketRelativePathParserError::PathContainsInvalidCharacter {
+                    position: position + index,
+                    invalid_char: c,
+                });
+            }
+            // Compatibility characters, like fullwidth letters or ligatures, look like the characters they decompose to.
+            let confusable = !is_nfkc_quick(std::iter::once(c)).eq(&IsNormalized::Yes)
+                || match (segment_script, confusable_script(c)) {
+                    (Some(script), Some(other)) => script != other,
+                    _ => false,
+                };
+            if confusable {
+                return Err(BucketRelativePathParserError::ConfusableCharacter {
+                    position: position + index,
+                    invalid_char: c,
+                });
+            }
+            segment_script = segment_script.or(confusable_script(c));
+        }
+        position += segment.chars().count();
+    }
+    Ok(())
 }
 
 impl FromStr for BucketRelativePath {
@@ -114,26 +171,274 @@ impl FromStr for BucketRelativePath {
             return Err(BucketRelativePathParserError::PathMustStartWithForwardSlash);
         }
 
+        let path: String = s.nfc().collect();
         // check path max length
-        if s.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
+        if path.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
             return Err(BucketRelativePathParserError::RelativePathTooLong);
         }
-        // Check for invalid characters
-        for (index, c) in s.chars().enumerate() {
-            if !(c.is_alphanumeric() || c == '/' || c.is_numeric() || c == '-' || c == '_') {
-                return Err(BucketRelativePathParserError::PathContainsInvalidCharacter {
-                    position: index,
-                    invalid_char: c,
-                });
-            }
+        if path != "/" {
+            validate_segments(&path, 0)?;
         }
 
-        Ok(BucketRelativePath {
-            path: s.to_string(),
+        Ok(BucketRelativePath { path })
+    }
+}
+
+impl BucketRelativePath {
+    /// The bucket root, ``/``.
+    pub fn root() -> Self {
+        Self { path: "/".to_string() }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.path == "/"
+    }
+
+    /// The segments of the path, none for the root.
+    pub fn segments(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.path.split('/').filter(|segment| !segment.is_empty())
+    }
+
+    /// Appends `relative`, one or more segments separated by ``/`` without a leading ``/``.
+    /// Every segment is validated like in `from_str`, so the result always stays inside the bucket.
+    pub fn join(&self, relative: &str) -> Result<Self, BucketRelativePathParserError> {
+        let relative: String = relative.nfc().collect();
+        let base = if self.is_root() { "" } else { self.path.as_str() };
+        let path = format!("{}/{}", base, relative);
+        if path.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
+            return Err(BucketRelativePathParserError::RelativePathTooLong);
+        }
+        validate_segments(&path[base.len()..], base.chars().count())?;
+        // Scripts are only checked within a segment, so the existing segments don't need to be checked again.
+        Ok(Self { path })
+    }
+
+    /// Appends `other` below this path, only fails when the result is too long.
+    pub fn join_path(&self, other: &BucketRelativePath) -> Result<Self, BucketRelativePathParserError> {
+        if other.is_root() {
+            return Ok(self.clone());
+        }
+        let path = if self.is_root() {
+            other.path.clone()
+        } else {
+            format!("{}{}", self.path, other.path)
+        };
+        if path.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
+            return Err(BucketRelativePathParserError::RelativePathTooLong);
+        }
+        Ok(Self { path })
+    }
+
+    /// The path without its last segment, `None` for the root.
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_root() {
+            return None;
+        }
+        let (parent, _) = self.path.rsplit_once('/')?;
+        Some(if parent.is_empty() {
+            Self::root()
+        } else {
+            Self { path: parent.to_string() }
+        })
+    }
+
+    /// The last segment, `None` for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        self.segments().next_back()
+    }
+
+    /// The file name without its extension.
+    pub fn file_stem(&self) -> Option<&str> {
+        let file_name = self.file_name()?;
+        Some(match file_name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => file_name,
+        })
+    }
+
+    /// The part of the file name after the last ``.``, a leading ``.`` (``/.hidden``) doesn't start an extension.
+    pub fn extension(&self) -> Option<&str> {
+        match self.file_name()?.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => Some(extension),
+            _ => None,
+        }
+    }
+
+    /// Whether `prefix` is this path or one of its ancestors, compares whole segments: ``/a/bc`` doesn't start with ``/a/b``.
+    pub fn starts_with(&self, prefix: &BucketRelativePath) -> bool {
+        prefix.is_root()
+            || self.path == prefix.path
+            || (self.path.starts_with(&prefix.path) && self.path.as_bytes()[prefix.path.len()] == b'/')
+    }
+
+    /// The rest of the path below `prefix`, as a path from the root. `None` if `prefix` isn't an ancestor.
+    pub fn strip_prefix(&self, prefix: &BucketRelativePath) -> Option<Self> {
+        if !self.starts_with(prefix) {
+            return None;
+        }
+        if prefix.is_root() {
+            return Some(self.clone());
+        }
+        let rest = &self.path[prefix.path.len()..];
+        Some(if rest.is_empty() {
+            Self::root()
+        } else {
+            Self { path: rest.to_string() }
         })
     }
 }
 
+impl Display for BucketRelativePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+
+/// A directory in a bucket, any relative path including the root.
+/// Parses with or without a trailing ``/`` and is displayed with one (``/docs/``), so it can't be mistaken for an object.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, SerializeDisplay, DeserializeFromStr)]
+pub struct BucketDirPath(BucketRelativePath);
+
+impl BucketDirPath {
+    pub fn root() -> Self {
+        Self(BucketRelativePath::root())
+    }
+
+    pub fn as_relative_path(&self) -> &BucketRelativePath {
+        &self.0
+    }
+
+    /// The sub directory `relative`, see `BucketRelativePath::join`.
+    pub fn join_dir(&self, relative: &str) -> Result<BucketDirPath, BucketRelativePathParserError> {
+        self.0.join(relative).map(Self)
+    }
+
+    /// The object `relative` in this directory, the last segment must be a file name with an extension.
+    pub fn join_object(&self, relative: &str) -> Result<BucketObjectPath, BucketObjectPathError> {
+        BucketObjectPath::try_from(self.0.join(relative)?)
+    }
+
+    /// Whether `object` is in this directory or one of its sub directories.
+    pub fn contains(&self, object: &BucketObjectPath) -> bool {
+        object.0.starts_with(&self.0)
+    }
+}
+
+impl FromStr for BucketDirPath {
+    type Err = BucketRelativePathParserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only `/` itself is the root, the trailing `/` of `//` is an empty segment rather than a separator.
+        if let Some(path) = s.strip_suffix('/').and_then(|stripped| BucketRelativePath::from_str(stripped).ok()) {
+            if !path.is_root() {
+                return Ok(Self(path));
+            }
+        }
+        BucketRelativePath::from_str(s).map(Self)
+    }
+}
+
+impl Display for BucketDirPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_root() {
+            f.write_str("/")
+        } else {
+            write!(f, "{}/", self.0)
+        }
+    }
+}
+
+impl From<BucketRelativePath> for BucketDirPath {
+    fn from(value: BucketRelativePath) -> Self {
+        Self(value)
+    }
+}
+
+impl From<BucketDirPath> for BucketRelativePath {
+    fn from(value: BucketDirPath) -> Self {
+        value.0
+    }
+}
+
+/// An object (file) in a bucket, must end with a file name with an extension, like ``/a/b.txt``.
+/// Shares the validation rules of ``BucketRelativePath``.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, SerializeDisplay, DeserializeFromStr)]
+pub struct BucketObjectPath(BucketRelativePath);
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum BucketObjectPathError {
+    #[error(transparent)]
+    InvalidPath(#[from] BucketRelativePathParserError),
+    #[error("Path does not contain file.something")]
+    PathNotAbsolute,
+}
+
+impl BucketObjectPath {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn as_relative_path(&self) -> &BucketRelativePath {
+        &self.0
+    }
+
+    /// The directory the object is in.
+    pub fn parent(&self) -> BucketDirPath {
+        // An object path is never the root, so it always has a parent.
+        BucketDirPath(self.0.parent().unwrap_or_else(BucketRelativePath::root))
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.0.file_name().unwrap_or_default()
+    }
+
+    pub fn extension(&self) -> &str {
+        self.0.extension().unwrap_or_default()
+    }
+
+    /// The absolute path of the object in `bucket_guid`.
+    pub fn in_bucket(&self, bucket_guid: BucketGuid) -> BucketAbsolutePath {
+        BucketAbsolutePath::new(bucket_guid, self.0.clone())
+    }
+}
+
+impl TryFrom<BucketRelativePath> for BucketObjectPath {
+    type Error = BucketObjectPathError;
+
+    fn try_from(value: BucketRelativePath) -> Result<Self, Self::Error> {
+        // The extension must be preceded by a stem and followed by at least one character.
+        match value.extension() {
+            Some(extension) if !extension.is_empty() => Ok(Self(value)),
+            _ => Err(BucketObjectPathError::PathNotAbsolute),
+        }
+    }
+}
+
+impl FromStr for BucketObjectPath {
+    type Err = BucketObjectPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(BucketRelativePath::from_str(s)?)
+    }
+}
+
+impl Display for BucketObjectPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl From<BucketObjectPath> for BucketRelativePath {
+    fn from(value: BucketObjectPath) -> Self {
+        value.0
+    }
+}
+
 
 // ... existing code ...
 
@@ -164,7 +469,15 @@ mod tests {
         let test_cases = vec![
             ("no_leading_slash", BucketRelativePathParserError::PathMustStartWithForwardSlash),
             ("/@invalid", BucketRelativePathParserError::PathContainsInvalidCharacter { position: 1, invalid_char: '@' }),
-            ("/path/with space", BucketRelativePathParserError::PathContainsInvalidCharacter { position: 9, invalid_char: ' ' }),
+            ("/path/with space", BucketRelativePathParserError::PathContainsInvalidCharacter { position: 10, invalid_char: ' ' }),
+            ("//double", BucketRelativePathParserError::EmptySegment { position: 1 }),
+            ("/trailing/", BucketRelativePathParserError::EmptySegment { position: 10 }),
+            ("/a/../b", BucketRelativePathParserError::DotSegment { position: 3 }),
+            ("/.", BucketRelativePathParserError::DotSegment { position: 1 }),
+            // Fullwidth 'Ａ'.
+            ("/\u{FF21}", BucketRelativePathParserError::ConfusableCharacter { position: 1, invalid_char: '\u{FF21}' }),
+            // Cyrillic 'а' in a latin word.
+            ("/p\u{0430}ypal", BucketRelativePathParserError::ConfusableCharacter { position: 2, invalid_char: '\u{0430}' }),
         ];
 
         for (path, expected_err) in test_cases {
@@ -175,7 +488,7 @@ mod tests {
     }
 
     #[test]
-    fn test_bucket_relative_absolute_path_valid() {
+    fn test_bucket_object_path_valid() {
         let valid_paths = vec![
             "/file.txt",
             "/path/to/file.ext",
@@ -183,22 +496,25 @@ mod tests {
         ];
 
         for path in valid_paths {
-            assert!(BucketRelativeAbsolutePath::from_str(path).is_ok());
+            assert!(BucketObjectPath::from_str(path).is_ok());
         }
     }
 
     #[test]
-    fn test_bucket_relative_absolute_path_invalid() {
+    fn test_bucket_object_path_invalid() {
         let test_cases = vec![
-            ("no_leading_slash.txt", BucketRelativeAbsolutePathError::PathMustStartWithForwardSlash),
-            ("/no_extension", BucketRelativeAbsolutePathError::PathNotAbsolute),
-            ("/invalid.", BucketRelativeAbsolutePathError::PathNotAbsolute),
-            ("/@invalid.txt", BucketRelativeAbsolutePathError::PathMustStartWithForwardSlash),
-            ("/path/with space.txt", BucketRelativeAbsolutePathError::PathContainsInvalidCharacter { position: 9, invalid_char: ' ' }),
+            ("no_leading_slash.txt", BucketRelativePathParserError::PathMustStartWithForwardSlash.into()),
+            ("/no_extension", BucketObjectPathError::PathNotAbsolute),
+            ("/invalid.", BucketObjectPathError::PathNotAbsolute),
+            ("/.hidden", BucketObjectPathError::PathNotAbsolute),
+            ("/", BucketObjectPathError::PathNotAbsolute),
+            ("/dir/", BucketRelativePathParserError::EmptySegment { position: 5 }.into()),
+            ("/@invalid.txt", BucketRelativePathParserError::PathContainsInvalidCharacter { position: 1, invalid_char: '@' }.into()),
+            ("/path/with space.txt", BucketRelativePathParserError::PathContainsInvalidCharacter { position: 10, invalid_char: ' ' }.into()),
         ];
 
         for (path, expected_err) in test_cases {
-            let result = BucketRelativeAbsolutePath::from_str(path);
+            let result = BucketObjectPath::from_str(path);
             assert!(result.is_err());
             assert_eq!(result.unwrap_err(), expected_err);
         }
@@ -215,8 +531,8 @@ mod tests {
         ));
 
         assert!(matches!(
-            BucketRelativeAbsolutePath::from_str(&format!("{}.txt", long_path)),
-            Err(BucketRelativeAbsolutePathError::RelativePathTooLong)
+            BucketObjectPath::from_str(&format!("{}.txt", long_path)),
+            Err(BucketObjectPathError::InvalidPath(BucketRelativePathParserError::RelativePathTooLong))
         ));
     }
 
@@ -231,7 +547,130 @@ mod tests {
         let absolute_path = BucketAbsolutePath::new(guid.clone(), relative_path);
         
         assert_eq!(absolute_path.bucket_guid, guid);
-        assert_eq!(absolute_path.relative_path.path, "/test/path");
+        assert_eq!(absolute_path.relative_path.as_str(), "/test/path");
     }
-    
-}
\ No newline at end of file
+
+    #[test]
+    fn test_bucket_relative_path_normalization() {
+        // 'e' followed by a combining acute accent is stored as 'é'.
+        let path = BucketRelativePath::from_str("/caf\u{0065}\u{0301}").unwrap();
+        assert_eq!(path.as_str(), "/caf\u{00E9}");
+        assert_eq!(path, BucketRelativePath::from_str("/caf\u{00E9}").unwrap());
+        assert!(BucketRelativePath::from_str("/").unwrap().is_root());
+        assert!(BucketRelativePath::from_str("/\u{043F}\u{0440}\u{0438}\u{0432}\u{0435}\u{0442}/hello").is_ok());
+    }
+
+    #[test]
+    fn test_bucket_relative_path_segments() {
+        let path = BucketRelativePath::from_str("/docs/2024/report.final.pdf").unwrap();
+        assert_eq!(path.segments().collect::<Vec<_>>(), vec!["docs", "2024", "report.final.pdf"]);
+        assert_eq!(path.file_name(), Some("report.final.pdf"));
+        assert_eq!(path.file_stem(), Some("report.final"));
+        assert_eq!(path.extension(), Some("pdf"));
+        assert_eq!(path.parent().unwrap().as_str(), "/docs/2024");
+        assert_eq!(path.parent().unwrap().parent().unwrap().parent(), Some(BucketRelativePath::root()));
+        assert_eq!(BucketRelativePath::root().parent(), None);
+        assert_eq!(BucketRelativePath::root().segments().count(), 0);
+        assert_eq!(BucketRelativePath::from_str("/.hidden").unwrap().extension(), None);
+        assert_eq!(path.to_string(), "/docs/2024/report.final.pdf");
+    }
+
+    #[test]
+    fn test_bucket_relative_path_join_cannot_escape() {
+        let docs = BucketRelativePath::from_str("/docs").unwrap();
+        assert_eq!(docs.join("2024/report.pdf").unwrap().as_str(), "/docs/2024/report.pdf");
+        assert_eq!(BucketRelativePath::root().join("docs").unwrap(), docs);
+        assert_eq!(docs.join(".."), Err(BucketRelativePathParserError::DotSegment { position: 6 }));
+        assert_eq!(docs.join("a/../../etc"), Err(BucketRelativePathParserError::DotSegment { position: 8 }));
+        assert_eq!(docs.join("/etc"), Err(BucketRelativePathParserError::EmptySegment { position: 6 }));
+        assert_eq!(docs.join(""), Err(BucketRelativePathParserError::EmptySegment { position: 6 }));
+        // Positions are characters, not bytes.
+        let cafe = BucketRelativePath::from_str("/caf\u{00E9}").unwrap();
+        assert_eq!(cafe.join("menu.txt").unwrap().as_str(), "/caf\u{00E9}/menu.txt");
+        assert_eq!(cafe.join(".."), Err(BucketRelativePathParserError::DotSegment { position: 6 }));
+        assert!(matches!(docs.join("a\\b"), Err(BucketRelativePathParserError::PathContainsInvalidCharacter { invalid_char: '\\', .. })));
+    }
+
+    #[test]
+    fn test_bucket_relative_path_prefix() {
+        let path = BucketRelativePath::from_str("/a/bc/d").unwrap();
+        let a = BucketRelativePath::from_str("/a").unwrap();
+        let a_b = BucketRelativePath::from_str("/a/b").unwrap();
+        assert!(path.starts_with(&a));
+        assert!(path.starts_with(&BucketRelativePath::root()));
+        assert!(path.starts_with(&path));
+        assert!(!path.starts_with(&a_b));
+        assert_eq!(path.strip_prefix(&a).unwrap().as_str(), "/bc/d");
+        assert_eq!(path.strip_prefix(&path), Some(BucketRelativePath::root()));
+        assert_eq!(path.strip_prefix(&a_b), None);
+    }
+
+    #[test]
+    fn test_dir_and_object_paths() {
+        let docs = BucketDirPath::from_str("/docs/").unwrap();
+        assert_eq!(docs, BucketDirPath::from_str("/docs").unwrap());
+        assert_eq!(docs.to_string(), "/docs/");
+        assert_eq!(BucketDirPath::from_str("/").unwrap(), BucketDirPath::root());
+        assert_eq!(BucketDirPath::from_str("//"), Err(BucketRelativePathParserError::EmptySegment { position: 1 }));
+        assert_eq!(BucketDirPath::from_str("/docs//"), Err(BucketRelativePathParserError::EmptySegment { position: 6 }));
+        assert_eq!(BucketDirPath::root().to_string(), "/");
+
+        let report = docs.join_object("2024/report.pdf").unwrap();
+        assert_eq!(report.as_str(), "/docs/2024/report.pdf");
+        assert_eq!(report.file_name(), "report.pdf");
+        assert_eq!(report.extension(), "pdf");
+        assert_eq!(report.parent().to_string(), "/docs/2024/");
+        assert!(docs.contains(&report));
+        assert!(!BucketDirPath::from_str("/doc").unwrap().contains(&report));
+        assert_eq!(docs.join_object("2024"), Err(BucketObjectPathError::PathNotAbsolute));
+        assert!(docs.join_object("../secret.txt").is_err());
+
+        let relative: BucketRelativePath = report.clone().into();
+        assert_eq!(BucketObjectPath::try_from(relative.clone()).unwrap(), report);
+        assert_eq!(BucketDirPath::from(relative).to_string(), "/docs/2024/report.pdf/");
+
+        let guid = BucketGuid::generate();
+        assert_eq!(report.in_bucket(guid.clone()).relative_path.as_str(), "/docs/2024/report.pdf");
+    }
+
+    #[test]
+    fn test_path_serde() {
+        let report = BucketObjectPath::from_str("/docs/report.pdf").unwrap();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(json, "\"/docs/report.pdf\"");
+        assert_eq!(serde_json::from_str::<BucketObjectPath>(&json).unwrap(), report);
+        assert!(serde_json::from_str::<BucketObjectPath>("\"/docs\"").is_err());
+
+        let docs = report.parent();
+        let json = serde_json::to_string(&docs).unwrap();
+        assert_eq!(json, "\"/docs/\"");
+        assert_eq!(serde_json::from_str::<BucketDirPath>(&json).unwrap(), docs);
+        assert!(serde_json::from_str::<BucketRelativePath>("\"/a/../b\"").is_err());
+    }
+
+    #[test]
+    fn test_common_prefixed_path() {
+        let guid = BucketGuid::generate();
+        let paths: Vec<BucketRelativePath> = ["/docs/2024/a.pdf", "/docs/2024/q1/b.pdf", "/docs/2023/c.txt"]
+            .into_iter()
+            .map(|path| BucketRelativePath::from_str(path).unwrap())
+            .collect();
+        let mut grouped = BucketCommonPrefixedPath::from_paths(guid.clone(), &paths);
+        assert_eq!(grouped.prefix().relative_path.as_str(), "/docs");
+        assert_eq!(grouped.paths().iter().map(|path| path.as_str()).collect::<Vec<_>>(), vec!["/2024/a.pdf", "/2024/q1/b.pdf", "/2023/c.txt"]);
+        assert_eq!(grouped.full_paths().collect::<Vec<_>>(), paths);
+
+        let pdfs = BucketPathPatternSet::new(["/docs/2024/**/*.pdf".parse().unwrap()]);
+        grouped.retain_matching(&pdfs);
+        assert_eq!(grouped.full_paths().collect::<Vec<_>>(), paths[..2]);
+
+        let single = BucketCommonPrefixedPath::from_paths(guid.clone(), &paths[..1]);
+        assert_eq!(single.prefix().relative_path.as_str(), "/docs/2024");
+        assert_eq!(single.full_paths().next().unwrap(), paths[0]);
+        assert!(BucketCommonPrefixedPath::from_paths(guid.clone(), &[]).paths().is_empty());
+
+        let prefix = BucketAbsolutePath::new(guid, BucketRelativePath::from_str("/a").unwrap());
+        let too_long = BucketRelativePath::from_str(&format!("/{}", "b".repeat(BUCKET_RELATIVE_PATH_MAX_LENGTH - 1))).unwrap();
+        assert_eq!(BucketCommonPrefixedPath::new(prefix, vec![too_long]), Err(BucketRelativePathParserError::RelativePathTooLong));
+    }
+}
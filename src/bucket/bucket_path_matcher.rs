@@ -0,0 +1,551 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::bucket::bucket_path::{BucketDirPath, BucketRelativePath, BUCKET_RELATIVE_PATH_MAX_LENGTH};
+
+// Glob matching for bucket paths, used by listing filters, lifecycle rules, share scopes and permission scoping.
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum BucketPathPatternError {
+    #[error("Pattern must start with a forward slash '/'")]
+    PatternMustStartWithForwardSlash,
+    #[error("Pattern mustn't be longer than {0}", BUCKET_RELATIVE_PATH_MAX_LENGTH)]
+    PatternTooLong,
+    #[error("Pattern contains an empty segment at position {position}")]
+    EmptySegment { position: usize },
+    #[error("Pattern contains a '.' or '..' segment at position {position}")]
+    DotSegment { position: usize },
+    #[error("Pattern contains invalid character at position {position}: '{invalid_char}'")]
+    PatternContainsInvalidCharacter { position: usize, invalid_char: char },
+    #[error("'**' must be a whole segment, at position {position}")]
+    DoubleStarNotASegment { position: usize },
+    #[error("Character class at position {position} isn't closed")]
+    UnclosedCharacterClass { position: usize },
+    #[error("Character range at position {position} is reversed")]
+    InvalidCharacterRange { position: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GlobToken {
+    Literal(char),
+    /// `?`, one character.
+    AnyChar,
+    /// `*`, zero or more characters within a segment.
+    AnyChars,
+    /// `[a-z_]` or `[!a-z_]`.
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+impl GlobToken {
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            GlobToken::Literal(literal) => *literal == c,
+            GlobToken::AnyChar => true,
+            GlobToken::AnyChars => false,
+            GlobToken::Class { negated, ranges } => ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SegmentPattern {
+    /// `**`, zero or more segments.
+    AnySegments,
+    Literal(String),
+    Glob(Vec<GlobToken>),
+}
+
+impl SegmentPattern {
+    /// Whether a single segment matches, `AnySegments` is handled by the caller.
+    fn matches_segment(&self, segment: &str) -> bool {
+        match self {
+            SegmentPattern::AnySegments => true,
+            SegmentPattern::Literal(literal) => literal == segment,
+            SegmentPattern::Glob(tokens) => {
+                let chars: Vec<char> = segment.chars().collect();
+                wildcard_match(tokens, &chars, |token| matches!(token, GlobToken::AnyChars), |token, c| token.matches_char(*c))
+            }
+        }
+    }
+}
+
+/// Greedy wildcard matching with backtracking to the last wildcard, linear for a single wildcard and never exponential.
+/// Used for `*` over characters and for `**` over segments.
+fn wildcard_match<TToken, TItem>(
+    tokens: &[TToken],
+    items: &[TItem],
+    is_wildcard: impl Fn(&TToken) -> bool,
+    matches: impl Fn(&TToken, &TItem) -> bool,
+) -> bool {
+    let (mut token, mut item) = (0, 0);
+    // Position of the last wildcard and the item it's currently matched up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while item < items.len() {
+        match tokens.get(token) {
+            Some(current) if is_wildcard(current) => {
+                backtrack = Some((token, item));
+                token += 1;
+            }
+            Some(current) if matches(current, &items[item]) => {
+                token += 1;
+                item += 1;
+            }
+            _ => match backtrack {
+                // Let the wildcard consume one more item and retry.
+                Some((wildcard, consumed)) => {
+                    backtrack = Some((wildcard, consumed + 1));
+                    token = wildcard + 1;
+                    item = consumed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[token..].iter().all(is_wildcard)
+}
+
+fn is_path_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+/// Parses one segment, `position` is the character position of the segment in the pattern.
+fn parse_segment(segment: &str, position: usize) -> Result<SegmentPattern, BucketPathPatternError> {
+    if segment.is_empty() {
+        return Err(BucketPathPatternError::EmptySegment { position });
+    }
+    if segment == "." || segment == ".." {
+        return Err(BucketPathPatternError::DotSegment { position });
+    }
+    if segment == "**" {
+        return Ok(SegmentPattern::AnySegments);
+    }
+
+    let chars: Vec<char> = segment.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        match c {
+            '*' if chars.get(index + 1) == Some(&'*') => {
+                return Err(BucketPathPatternError::DoubleStarNotASegment { position: position + index });
+            }
+            '*' => tokens.push(GlobToken::AnyChars),
+            '?' => tokens.push(GlobToken::AnyChar),
+            '[' => {
+                let start = index;
+                index += 1;
+                let negated = matches!(chars.get(index), Some('!') | Some('^'));
+                if negated {
+                    index += 1;
+                }
+                let mut ranges = Vec::new();
+                // A ']' right after the opening bracket is a literal.
+                let mut first = true;
+                loop {
+                    let low = match chars.get(index) {
+                        None => return Err(BucketPathPatternError::UnclosedCharacterClass { position: position + start }),
+                        Some(']') if !first => break,
+                        Some(low) => *low,
+                    };
+                    first = false;
+                    let high = match (chars.get(index + 1), chars.get(index + 2)) {
+                        (Some('-'), Some(high)) if *high != ']' => {
+                            index += 2;
+                            *high
+                        }
+                        _ => low,
+                    };
+                    if low > high {
+                        return Err(BucketPathPatternError::InvalidCharacterRange { position: position + index - 2 });
+                    }
+                    ranges.push((low, high));
+                    index += 1;
+                }
+                tokens.push(GlobToken::Class { negated, ranges });
+            }
+            c if is_path_char(c) => tokens.push(GlobToken::Literal(c)),
+            invalid_char => {
+                return Err(BucketPathPatternError::PatternContainsInvalidCharacter {
+                    position: position + index,
+                    invalid_char,
+                });
+            }
+        }
+        index += 1;
+    }
+
+    if tokens.iter().all(|token| matches!(token, GlobToken::Literal(_))) {
+        Ok(SegmentPattern::Literal(segment.to_string()))
+    } else {
+        Ok(SegmentPattern::Glob(tokens))
+    }
+}
+
+/// A compiled pattern over bucket paths.
+///
+/// Segments are matched one by one:
+/// - ``*`` matches any characters within a segment, ``?`` one character,
+/// - ``[a-z_]`` one character of the class, ``[!a-z]`` one character not in it,
+/// - ``**`` as a whole segment matches zero or more segments,
+/// - a trailing ``/`` is a prefix, ``/docs/`` is the same as ``/docs/**`` and matches ``/docs`` and everything below it.
+///
+/// The pattern is NFC normalized like ``BucketRelativePath``, so both are compared in the same form.
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub struct BucketPathPattern {
+    pattern: String,
+    segments: Vec<SegmentPattern>,
+    literal_prefix: String,
+}
+
+impl BucketPathPattern {
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the pattern has no wildcards and only matches one path.
+    pub fn is_literal(&self) -> bool {
+        self.segments.iter().all(|segment| matches!(segment, SegmentPattern::Literal(_)))
+    }
+
+    pub fn is_match(&self, path: &BucketRelativePath) -> bool {
+        let segments: Vec<&str> = path.segments().collect();
+        wildcard_match(
+            &self.segments,
+            &segments,
+            |pattern| matches!(pattern, SegmentPattern::AnySegments),
+            |pattern, segment| pattern.matches_segment(segment),
+        )
+    }
+
+    /// Whether a path in `dir` or below it could match, used to skip whole directories while listing.
+    pub fn may_match_in(&self, dir: &BucketDirPath) -> bool {
+        let mut patterns = self.segments.iter();
+        for segment in dir.as_relative_path().segments() {
+            match patterns.next() {
+                // '**' can consume the rest of the directory.
+                Some(SegmentPattern::AnySegments) => return true,
+                Some(pattern) if pattern.matches_segment(segment) => continue,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// The longest string every matching path starts with, a storage listing with this prefix contains all matches.
+    /// Not always a whole segment, ``/docs/rep*.pdf`` gives ``/docs/rep``.
+    pub fn literal_prefix(&self) -> &str {
+        &self.literal_prefix
+    }
+
+    /// The deepest directory every matching path is in or is equal to.
+    pub fn literal_dir(&self) -> BucketDirPath {
+        let mut dir = BucketRelativePath::root();
+        for segment in &self.segments {
+            match segment {
+                // Literal segments are valid path segments, the length is checked when parsing.
+                SegmentPattern::Literal(literal) => match dir.join(literal) {
+                    Ok(joined) => dir = joined,
+                    Err(_) => break,
+                },
+                _ => break,
+            }
+        }
+        dir.into()
+    }
+}
+
+impl FromStr for BucketPathPattern {
+    type Err = BucketPathPatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err(BucketPathPatternError::PatternMustStartWithForwardSlash);
+        }
+        let pattern: String = s.nfc().collect();
+        if pattern.len() > BUCKET_RELATIVE_PATH_MAX_LENGTH {
+            return Err(BucketPathPatternError::PatternTooLong);
+        }
+
+        let mut segments = Vec::new();
+        if pattern != "/" {
+            let (body, prefix) = match pattern.strip_suffix('/') {
+                Some(body) => (body, true),
+                None => (pattern.as_str(), false),
+            };
+            let mut position = 0;
+            for segment in body.split('/').skip(1) {
+                position += 1;
+                let parsed = parse_segment(segment, position)?;
+                // '**/**' is the same as '**'.
+                if !(parsed == SegmentPattern::AnySegments && segments.last() == Some(&SegmentPattern::AnySegments)) {
+                    segments.push(parsed);
+                }
+                position += segment.chars().count();
+            }
+            if prefix && segments.last() != Some(&SegmentPattern::AnySegments) {
+                segments.push(SegmentPattern::AnySegments);
+            }
+        }
+
+        let mut literal_prefix = String::new();
+        for segment in &segments {
+            match segment {
+                SegmentPattern::Literal(literal) => {
+                    literal_prefix.push('/');
+                    literal_prefix.push_str(literal);
+                }
+                SegmentPattern::Glob(tokens) => {
+                    literal_prefix.push('/');
+                    literal_prefix.extend(tokens.iter().map_while(|token| match token {
+                        GlobToken::Literal(c) => Some(*c),
+                        _ => None,
+                    }));
+                    break;
+                }
+                SegmentPattern::AnySegments => break,
+            }
+        }
+        if literal_prefix.is_empty() {
+            literal_prefix.push('/');
+        }
+
+        Ok(Self {
+            pattern,
+            segments,
+            literal_prefix,
+        })
+    }
+}
+
+impl Display for BucketPathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Many patterns compiled together, a path is only tested against the patterns whose literal prefix it starts with.
+#[derive(Debug, Clone, Default)]
+pub struct BucketPathPatternSet {
+    patterns: Vec<BucketPathPattern>,
+    /// Pattern indexes grouped by literal prefix, sorted by prefix.
+    groups: Vec<(String, Vec<usize>)>,
+}
+
+impl BucketPathPatternSet {
+    pub fn new(patterns: impl IntoIterator<Item = BucketPathPattern>) -> Self {
+        let patterns: Vec<BucketPathPattern> = patterns.into_iter().collect();
+        let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+        let mut by_prefix: Vec<(&str, usize)> = patterns
+            .iter()
+            .enumerate()
+            .map(|(index, pattern)| (pattern.literal_prefix(), index))
+            .collect();
+        by_prefix.sort();
+        for (prefix, index) in by_prefix {
+            match groups.last_mut() {
+                Some((last, indexes)) if last == prefix => indexes.push(index),
+                _ => groups.push((prefix.to_string(), vec![index])),
+            }
+        }
+        Self { patterns, groups }
+    }
+
+    pub fn patterns(&self) -> &[BucketPathPattern] {
+        &self.patterns
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn is_match(&self, path: &BucketRelativePath) -> bool {
+        self.candidates(path).any(|index| self.patterns[index].is_match(path))
+    }
+
+    /// Indexes of all patterns matching `path`, in ascending order.
+    pub fn matches(&self, path: &BucketRelativePath) -> Vec<usize> {
+        let mut matches: Vec<usize> = self.candidates(path).filter(|index| self.patterns[*index].is_match(path)).collect();
+        matches.sort_unstable();
+        matches
+    }
+
+    /// Whether a path in `dir` or below it could match any pattern.
+    pub fn may_match_in(&self, dir: &BucketDirPath) -> bool {
+        self.patterns.iter().any(|pattern| pattern.may_match_in(dir))
+    }
+
+    /// The fewest listing prefixes that together contain every match, prefixes covered by a shorter one are dropped.
+    pub fn literal_prefixes(&self) -> Vec<&str> {
+        let mut prefixes: Vec<&str> = Vec::new();
+        // Sorted, so a covering prefix always comes before the prefixes it covers.
+        for (prefix, _) in &self.groups {
+            if !prefixes.last().is_some_and(|last| prefix.starts_with(last)) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+
+    /// Binary-searches the groups for every prefix of `path`.
+    /// Literal prefixes can end inside a segment (`/docs/rep*.pdf`), so every char boundary is tried, not only `/`.
+    fn candidates<'a>(&'a self, path: &'a BucketRelativePath) -> impl Iterator<Item = usize> + 'a {
+        let path = path.as_str();
+        path.char_indices()
+            .map(|(end, _)| end)
+            .skip(1)
+            .chain(std::iter::once(path.len()))
+            .filter_map(move |end| self.groups.binary_search_by(|(prefix, _)| prefix.as_str().cmp(&path[..end])).ok())
+            .flat_map(move |group| self.groups[group].1.iter().copied())
+    }
+}
+
+impl FromIterator<BucketPathPattern> for BucketPathPatternSet {
+    fn from_iter<T: IntoIterator<Item = BucketPathPattern>>(iter: T) -> Self {
+        Self::new(iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn path(path: &str) -> BucketRelativePath {
+        BucketRelativePath::from_str(path).unwrap()
+    }
+
+    fn pattern(pattern: &str) -> BucketPathPattern {
+        BucketPathPattern::from_str(pattern).unwrap()
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let cases = [
+            ("/docs/*.pdf", "/docs/report.pdf", true),
+            ("/docs/*.pdf", "/docs/2024/report.pdf", false),
+            ("/docs/**/*.pdf", "/docs/report.pdf", true),
+            ("/docs/**/*.pdf", "/docs/2024/q1/report.pdf", true),
+            ("/docs/**/*.pdf", "/docs/report.txt", false),
+            ("/docs/", "/docs", true),
+            ("/docs/", "/docs/a/b", true),
+            ("/docs/", "/docsx/a", false),
+            ("/**", "/", true),
+            ("/", "/", true),
+            ("/", "/a", false),
+            ("/img-[0-9][0-9].png", "/img-42.png", true),
+            ("/img-[0-9][0-9].png", "/img-4a.png", false),
+            ("/[!.]*", "/visible", true),
+            ("/[!.]*", "/.hidden", false),
+            ("/a?c", "/abc", true),
+            ("/a?c", "/ac", false),
+            ("/*a*a*a*", "/banana", true),
+            ("/**/logs/**", "/x/y/logs/z.txt", true),
+            ("/**/logs/**", "/x/y/log/z.txt", false),
+        ];
+        for (glob, candidate, expected) in cases {
+            assert_eq!(pattern(glob).is_match(&path(candidate)), expected, "{} {}", glob, candidate);
+        }
+    }
+
+    #[test]
+    fn test_pattern_errors() {
+        let cases = [
+            ("docs/*", BucketPathPatternError::PatternMustStartWithForwardSlash),
+            ("/docs//a", BucketPathPatternError::EmptySegment { position: 6 }),
+            ("/docs/../a", BucketPathPatternError::DotSegment { position: 6 }),
+            ("/docs/a**", BucketPathPatternError::DoubleStarNotASegment { position: 7 }),
+            ("/docs/[a-z", BucketPathPatternError::UnclosedCharacterClass { position: 6 }),
+            ("/docs/[z-a]", BucketPathPatternError::InvalidCharacterRange { position: 7 }),
+            ("/docs/a b", BucketPathPatternError::PatternContainsInvalidCharacter { position: 7, invalid_char: ' ' }),
+        ];
+        for (glob, expected) in cases {
+            assert_eq!(BucketPathPattern::from_str(glob), Err(expected), "{}", glob);
+        }
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(pattern("/docs/2024/rep*.pdf").literal_prefix(), "/docs/2024/rep");
+        assert_eq!(pattern("/docs/2024/rep*.pdf").literal_dir().to_string(), "/docs/2024/");
+        assert_eq!(pattern("/docs/**/*.pdf").literal_prefix(), "/docs");
+        assert_eq!(pattern("/docs/").literal_prefix(), "/docs");
+        assert_eq!(pattern("/*.pdf").literal_prefix(), "/");
+        assert_eq!(pattern("/**").literal_prefix(), "/");
+        assert_eq!(pattern("/docs/a.txt").literal_prefix(), "/docs/a.txt");
+        assert!(pattern("/docs/a.txt").is_literal());
+        assert!(!pattern("/docs/").is_literal());
+    }
+
+    #[test]
+    fn test_may_match_in() {
+        let pdfs = pattern("/docs/*/reports/*.pdf");
+        assert!(pdfs.may_match_in(&BucketDirPath::root()));
+        assert!(pdfs.may_match_in(&BucketDirPath::from_str("/docs/2024").unwrap()));
+        assert!(pdfs.may_match_in(&BucketDirPath::from_str("/docs/2024/reports").unwrap()));
+        assert!(!pdfs.may_match_in(&BucketDirPath::from_str("/images").unwrap()));
+        assert!(!pdfs.may_match_in(&BucketDirPath::from_str("/docs/2024/drafts").unwrap()));
+        assert!(pattern("/docs/**/x").may_match_in(&BucketDirPath::from_str("/docs/a/b/c").unwrap()));
+    }
+
+    #[test]
+    fn test_pattern_set() {
+        let set: BucketPathPatternSet = ["/docs/**/*.pdf", "/docs/", "/images/*.png", "/images/logo.png", "/tmp/*"]
+            .into_iter()
+            .map(pattern)
+            .collect();
+        assert_eq!(set.matches(&path("/docs/a/b.pdf")), vec![0, 1]);
+        assert_eq!(set.matches(&path("/images/logo.png")), vec![2, 3]);
+        assert_eq!(set.matches(&path("/images/logo.jpg")), Vec::<usize>::new());
+        assert!(set.is_match(&path("/tmp/x")));
+        assert!(!set.is_match(&path("/tmp/x/y")));
+        assert_eq!(set.literal_prefixes(), vec!["/docs", "/images/", "/tmp/"]);
+        assert!(set.may_match_in(&BucketDirPath::from_str("/images").unwrap()));
+        assert!(!set.may_match_in(&BucketDirPath::from_str("/videos").unwrap()));
+    }
+
+    #[test]
+    fn test_pattern_set_prefix_inside_segment() {
+        let set: BucketPathPatternSet = ["/docs/rep*.pdf", "/docs/r*", "/docs/*", "/dö*"].into_iter().map(pattern).collect();
+        assert_eq!(set.matches(&path("/docs/report.pdf")), vec![0, 1, 2]);
+        assert_eq!(set.matches(&path("/docs/readme")), vec![1, 2]);
+        assert_eq!(set.matches(&path("/dösen")), vec![3]);
+        assert_eq!(set.matches(&path("/do")), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_pattern_serde() {
+        let glob = pattern("/docs/**/*.pdf");
+        let json = serde_json::to_string(&glob).unwrap();
+        assert_eq!(json, "\"/docs/**/*.pdf\"");
+        assert_eq!(serde_json::from_str::<BucketPathPattern>(&json).unwrap(), glob);
+    }
+
+    proptest! {
+        #[test]
+        fn test_literal_patterns_match_themselves(segments in prop::collection::vec("[a-z0-9_-]{1,8}", 1..6)) {
+            let literal = format!("/{}", segments.join("/"));
+            let literal_pattern = pattern(&literal);
+            prop_assert!(literal_pattern.is_literal());
+            prop_assert!(literal_pattern.is_match(&path(&literal)));
+            prop_assert!(pattern("/**").is_match(&path(&literal)));
+            let glob = pattern(&format!("{}*", literal));
+            prop_assert!(literal.starts_with(glob.literal_prefix()));
+        }
+
+        #[test]
+        fn test_pattern_set_matches_like_each_pattern(segments in prop::collection::vec("[ab]{1,3}", 1..4)) {
+            let set: BucketPathPatternSet = ["/a*", "/a/b*", "/ab/*", "/**/b", "/b", "/*/a?", "/**"].into_iter().map(pattern).collect();
+            let path = path(&format!("/{}", segments.join("/")));
+            let expected: Vec<usize> = (0..set.len()).filter(|index| set.patterns()[*index].is_match(&path)).collect();
+            prop_assert_eq!(set.matches(&path), expected);
+        }
+    }
+}
@@ -1,22 +1,55 @@
-use std::fmt;
-use std::fmt::Display;
 use std::num::ParseIntError;
 use std::str::FromStr;
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumString};
 
+/// `Display` writes the canonical spelling, which is what is stored, never change it.
+/// `FromStr` also accepts the spellings in `LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS`.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
-#[strum(serialize_all = "kebab-case")] 
 pub enum EncryptionAlgorithm {
+    #[strum(serialize = "none")]
     None,
+    #[strum(serialize = "aes256")]
     Aes256,
+    #[strum(serialize = "cha-cha20-poly1305")]
     ChaCha20Poly1305,
+    /// 192-bit nonces, random nonces are safe.
+    #[strum(serialize = "x-cha-cha20-poly1305")]
     XChaCha20Poly1305,
+    /// Nonce-misuse resistant, a repeated nonce only reveals whether two messages are equal.
+    #[strum(serialize = "aes256-gcm-siv")]
+    Aes256GcmSiv,
     // Must start with 'custom-' and then the name of the encryption. with a max length of 64 characters entirely.
+    // The name is stored without the 'custom-' prefix, use `EncryptionAlgorithm::custom` so `Display` parses back.
     #[strum(serialize = "custom-{0}")]
     Custom(String),
 }
 
+const CUSTOM_PREFIX: &str = "custom-";
+const CUSTOM_MAX_LENGTH: usize = 64;
+
+/// Canonical spellings, as written by `Display`.
+const ENCRYPTION_ALGORITHM_SPELLINGS: &[(&str, EncryptionAlgorithm)] = &[
+    ("none", EncryptionAlgorithm::None),
+    ("aes256", EncryptionAlgorithm::Aes256),
+    ("cha-cha20-poly1305", EncryptionAlgorithm::ChaCha20Poly1305),
+    ("x-cha-cha20-poly1305", EncryptionAlgorithm::XChaCha20Poly1305),
+    ("aes256-gcm-siv", EncryptionAlgorithm::Aes256GcmSiv),
+];
+
+/// Spellings written by older versions, or by hand, that are still accepted when parsing.
+pub const LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS: &[(&str, EncryptionAlgorithm)] = &[
+    ("aes-256", EncryptionAlgorithm::Aes256),
+    ("aes_256", EncryptionAlgorithm::Aes256),
+    ("aes-256-gcm", EncryptionAlgorithm::Aes256),
+    ("cha-cha-20-poly-1305", EncryptionAlgorithm::ChaCha20Poly1305),
+    ("chacha20-poly1305", EncryptionAlgorithm::ChaCha20Poly1305),
+    ("chacha20poly1305", EncryptionAlgorithm::ChaCha20Poly1305),
+    ("x-cha-cha-20-poly-1305", EncryptionAlgorithm::XChaCha20Poly1305),
+    ("xchacha20-poly1305", EncryptionAlgorithm::XChaCha20Poly1305),
+    ("xchacha20poly1305", EncryptionAlgorithm::XChaCha20Poly1305),
+    ("aes-256-gcm-siv", EncryptionAlgorithm::Aes256GcmSiv),
+];
+
 
 
 #[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
@@ -31,28 +64,51 @@ pub enum EncryptionParsingError {
     FailedToParseVersion(#[from] ParseIntError),
 }
 
+/// Strips `CUSTOM_PREFIX` case-insensitively, `get` keeps non-ASCII input from splitting a character.
+fn strip_custom_prefix(s: &str) -> Option<&str> {
+    s.get(..CUSTOM_PREFIX.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(CUSTOM_PREFIX))
+        .map(|_| &s[CUSTOM_PREFIX.len()..])
+}
+
 impl FromStr for EncryptionAlgorithm {
     type Err = EncryptionParsingError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("custom-") {
-            if s.len() > 64 {
+        if let Some(mut name) = strip_custom_prefix(s) {
+            if s.len() > CUSTOM_MAX_LENGTH {
                 return Err(EncryptionParsingError::CustomFormatTooLong);
             }
-            return Ok(EncryptionAlgorithm::Custom(s.to_string()));
+            // Older versions kept the prefix in the name, so `Display` wrote it twice.
+            if let Some(stripped) = strip_custom_prefix(name) {
+                name = stripped;
+            }
+            return Self::custom(name);
         }
 
-        match s.to_lowercase().as_str() {
-            "none" => Ok(EncryptionAlgorithm::None),
-            "aes-256" => Ok(EncryptionAlgorithm::Aes256),
-            "cha-cha-20-poly-1305" => Ok(EncryptionAlgorithm::ChaCha20Poly1305),
-            "x-cha-cha-20-poly-1305" => Ok(EncryptionAlgorithm::XChaCha20Poly1305),
-            _ => Err(EncryptionParsingError::InvalidFormat),
-        }
+        ENCRYPTION_ALGORITHM_SPELLINGS
+            .iter()
+            .chain(LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS)
+            .find(|(spelling, _)| spelling.eq_ignore_ascii_case(s))
+            .map(|(_, algorithm)| algorithm.clone())
+            .ok_or(EncryptionParsingError::InvalidFormat)
     }
 }
 
 impl EncryptionAlgorithm {
+    /// Custom algorithm whose `Display` output parses back to it.
+    /// The name can't be empty, can't start with the 'custom-' prefix, and must fit in 64 characters with the prefix.
+    pub fn custom(name: impl Into<String>) -> Result<Self, EncryptionParsingError> {
+        let name = name.into();
+        if name.is_empty() || strip_custom_prefix(&name).is_some() {
+            return Err(EncryptionParsingError::InvalidFormat);
+        }
+        if CUSTOM_PREFIX.len() + name.len() > CUSTOM_MAX_LENGTH {
+            return Err(EncryptionParsingError::CustomFormatTooLong);
+        }
+        Ok(EncryptionAlgorithm::Custom(name))
+    }
+
     // Define constants for OIDs
     const AES256_OID: &'static str = "2.16.840.1.101.3.4.1.46";
 
@@ -63,7 +119,54 @@ impl EncryptionAlgorithm {
             EncryptionAlgorithm::Aes256 => Some(Self::AES256_OID),
             EncryptionAlgorithm::ChaCha20Poly1305 => None,
             EncryptionAlgorithm::XChaCha20Poly1305 => None,
+            EncryptionAlgorithm::Aes256GcmSiv => None,
             EncryptionAlgorithm::Custom(_) => None,
         }
     }
-}
\ No newline at end of file
+
+    /// Whether random nonces are safe, so objects can be written concurrently by many clients without coordinating nonces.
+    pub fn supports_random_nonces(&self) -> bool {
+        matches!(self, EncryptionAlgorithm::XChaCha20Poly1305 | EncryptionAlgorithm::Aes256GcmSiv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn test_non_ascii_input() {
+        assert_eq!(EncryptionAlgorithm::from_str("abcdef\u{e9}x"), Err(EncryptionParsingError::InvalidFormat));
+        assert_eq!(
+            EncryptionAlgorithm::from_str(&format!("{}\u{e9}t\u{e9}", CUSTOM_PREFIX)),
+            Ok(EncryptionAlgorithm::Custom("\u{e9}t\u{e9}".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_custom() {
+        assert_eq!(EncryptionAlgorithm::custom("rot13"), Ok(EncryptionAlgorithm::Custom("rot13".to_string())));
+        assert_eq!(EncryptionAlgorithm::custom(""), Err(EncryptionParsingError::InvalidFormat));
+        assert_eq!(EncryptionAlgorithm::custom("custom-x"), Err(EncryptionParsingError::InvalidFormat));
+        assert_eq!(EncryptionAlgorithm::custom("CUSTOM-x"), Err(EncryptionParsingError::InvalidFormat));
+        assert!(EncryptionAlgorithm::custom("a".repeat(CUSTOM_MAX_LENGTH - CUSTOM_PREFIX.len())).is_ok());
+        assert_eq!(
+            EncryptionAlgorithm::custom("a".repeat(CUSTOM_MAX_LENGTH - CUSTOM_PREFIX.len() + 1)),
+            Err(EncryptionParsingError::CustomFormatTooLong)
+        );
+        // The prefix can only be repeated once, a third copy would survive parsing and not display the same.
+        assert_eq!(
+            EncryptionAlgorithm::from_str("custom-custom-custom-x"),
+            Err(EncryptionParsingError::InvalidFormat)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_never_panics(input in "\\PC*") {
+            let _ = EncryptionAlgorithm::from_str(&input);
+        }
+    }
+}
@@ -1,7 +1,11 @@
 
 /*
 * The encryption has version control built in
-* The format is Encryption:Version,
+* The canonical format is version:role:algorithm, for example `1:C:x-cha-cha20-poly1305`.
+*   version    decimal u32, digits only
+*   role       `S` (server) or `C` (client), `server` and `client` are accepted when parsing
+*   algorithm  see `EncryptionAlgorithm`, which also accepts legacy spellings when parsing
+* Whatever `Display` writes must be accepted by `FromStr`.
 * None: uses no encryption.
 * AES256: uses server side encryption.
 * Zero-Knowledge: uses client side encryption.
@@ -9,21 +13,21 @@
 */
 use std::fmt;
 use std::fmt::Display;
-use std::num::ParseIntError;
 use std::str::FromStr;
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumString};
+use strum::EnumString;
 
-use super::encryption_algorithm::EncryptionAlgorithm;
+use super::encryption_algorithm::{EncryptionAlgorithm, EncryptionParsingError};
 
 
 
 #[derive(EnumString, PartialEq, Debug, Serialize, strum::Display, Clone, Eq, Deserialize)]
 #[repr(u8)]
+#[strum(ascii_case_insensitive)]
 pub enum Role {
-    #[strum(serialize = "S")]
+    #[strum(to_string = "S", serialize = "server")]
     Server,
-    #[strum(serialize = "C")]
+    #[strum(to_string = "C", serialize = "client")]
     Client,
 }
 
@@ -58,50 +62,176 @@ impl Display for BucketEncryptionScheme {
     }
 }
 
-#[derive(thiserror::Error, Debug, Display)]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
 pub enum BucketEncryptionParsingError {
+    #[error("Expected the format version:role:algorithm")]
     InvalidFormat,
+    #[error("Invalid role, expected S or C")]
     InvalidRole,
+    #[error("Invalid version, expected an unsigned 32-bit number")]
     InvalidVersion,
+    #[error(transparent)]
+    InvalidAlgorithm(#[from] EncryptionParsingError),
 }
+
 //TODO: https://github.com/P3KI/bendy
 impl FromStr for BucketEncryptionScheme
 {
     type Err = BucketEncryptionParsingError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() != 4 {
+        // The algorithm is last so a custom algorithm name is free to contain the delimiter.
+        let mut parts = s.splitn(3, ':');
+        let (Some(version), Some(role), Some(encryption)) = (parts.next(), parts.next(), parts.next()) else {
             return Err(BucketEncryptionParsingError::InvalidFormat);
-        }
-
-        let role = Role::from_str(parts[0]).map_err(|x| BucketEncryptionParsingError::InvalidRole)?;
-
-        let encryption = parts[1].to_string();
-        let signature = if parts[2].is_empty() {
-            None
-        } else {
-            Some(parts[2].to_string())
         };
 
-        let version = match parts[3].parse::<u32>() {
-            Ok(v) => v,
-            Err(_) => return Err(BucketEncryptionParsingError::InvalidVersion),
-        };
+        // `u32::from_str` accepts a leading '+', which `Display` never writes.
+        if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(BucketEncryptionParsingError::InvalidVersion);
+        }
+        let version = version.parse::<u32>().map_err(|_| BucketEncryptionParsingError::InvalidVersion)?;
+        let responsible = Role::from_str(role).map_err(|_| BucketEncryptionParsingError::InvalidRole)?;
+        let encryption = EncryptionAlgorithm::from_str(encryption)?;
 
         Ok(BucketEncryptionScheme {
-            responsible: role,
-            encryption: EncryptionAlgorithm::from_str(encryption.as_str()).unwrap(),
             version,
+            responsible,
+            encryption,
         })
     }
 }
 
 #[cfg(test)]
 mod bucket_encryption_tests {
-    use crate::bucket::encryption_algorithm::EncryptionParsingError;
+    use proptest::prelude::*;
+
+    use crate::bucket::encryption_algorithm::LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS;
 
     use super::*;
 
+    fn scheme(version: u32, responsible: Role, encryption: EncryptionAlgorithm) -> BucketEncryptionScheme {
+        BucketEncryptionScheme {
+            version,
+            responsible,
+            encryption,
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(scheme(1, Role::Server, EncryptionAlgorithm::Aes256).to_string(), "1:S:aes256");
+        assert_eq!(scheme(2, Role::Client, EncryptionAlgorithm::ChaCha20Poly1305).to_string(), "2:C:cha-cha20-poly1305");
+        assert_eq!(scheme(3, Role::Client, EncryptionAlgorithm::XChaCha20Poly1305).to_string(), "3:C:x-cha-cha20-poly1305");
+        assert_eq!(scheme(1, Role::Client, EncryptionAlgorithm::None).to_string(), "1:C:none");
+        assert_eq!(scheme(1, Role::Client, EncryptionAlgorithm::Custom("rot13".to_string())).to_string(), "1:C:custom-rot13");
+    }
+
+    /// Every spelling we have ever written or accepted, and what it must parse to.
+    #[test]
+    fn test_compatibility_matrix() {
+        let matrix = vec![
+            ("1:S:aes256", scheme(1, Role::Server, EncryptionAlgorithm::Aes256)),
+            ("1:S:aes-256", scheme(1, Role::Server, EncryptionAlgorithm::Aes256)),
+            ("1:s:AES-256", scheme(1, Role::Server, EncryptionAlgorithm::Aes256)),
+            ("1:server:aes256", scheme(1, Role::Server, EncryptionAlgorithm::Aes256)),
+            ("2:C:cha-cha20-poly1305", scheme(2, Role::Client, EncryptionAlgorithm::ChaCha20Poly1305)),
+            ("2:C:cha-cha-20-poly-1305", scheme(2, Role::Client, EncryptionAlgorithm::ChaCha20Poly1305)),
+            ("2:client:chacha20-poly1305", scheme(2, Role::Client, EncryptionAlgorithm::ChaCha20Poly1305)),
+            ("3:C:x-cha-cha20-poly1305", scheme(3, Role::Client, EncryptionAlgorithm::XChaCha20Poly1305)),
+            ("3:C:x-cha-cha-20-poly-1305", scheme(3, Role::Client, EncryptionAlgorithm::XChaCha20Poly1305)),
+            ("3:C:xchacha20poly1305", scheme(3, Role::Client, EncryptionAlgorithm::XChaCha20Poly1305)),
+            ("0:C:none", scheme(0, Role::Client, EncryptionAlgorithm::None)),
+            ("1:C:custom-rot13", scheme(1, Role::Client, EncryptionAlgorithm::Custom("rot13".to_string()))),
+            // Older versions stored the 'custom-' prefix in the name, so it was written twice.
+            ("1:C:custom-custom-rot13", scheme(1, Role::Client, EncryptionAlgorithm::Custom("rot13".to_string()))),
+            ("1:C:custom-a:b", scheme(1, Role::Client, EncryptionAlgorithm::Custom("a:b".to_string()))),
+        ];
+
+        for (input, expected) in matrix {
+            assert_eq!(BucketEncryptionScheme::from_str(input), Ok(expected), "{input}");
+        }
+    }
 
+    #[test]
+    fn test_invalid() {
+        let cases = vec![
+            ("", BucketEncryptionParsingError::InvalidFormat),
+            ("1:S", BucketEncryptionParsingError::InvalidFormat),
+            ("S:aes256:1", BucketEncryptionParsingError::InvalidVersion),
+            ("+1:S:aes256", BucketEncryptionParsingError::InvalidVersion),
+            ("-1:S:aes256", BucketEncryptionParsingError::InvalidVersion),
+            ("4294967296:S:aes256", BucketEncryptionParsingError::InvalidVersion),
+            ("1:X:aes256", BucketEncryptionParsingError::InvalidRole),
+            ("1:S:des", BucketEncryptionParsingError::InvalidAlgorithm(EncryptionParsingError::InvalidFormat)),
+            ("1:S:custom-", BucketEncryptionParsingError::InvalidAlgorithm(EncryptionParsingError::InvalidFormat)),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(BucketEncryptionScheme::from_str(input), Err(expected), "{input}");
+        }
+
+        let too_long = format!("1:S:custom-{}", "a".repeat(64));
+        assert_eq!(
+            BucketEncryptionScheme::from_str(&too_long),
+            Err(BucketEncryptionParsingError::InvalidAlgorithm(EncryptionParsingError::CustomFormatTooLong))
+        );
+    }
+
+    fn role() -> impl Strategy<Value = Role> {
+        prop_oneof![Just(Role::Server), Just(Role::Client)]
+    }
+
+    fn encryption_algorithm() -> impl Strategy<Value = EncryptionAlgorithm> {
+        prop_oneof![
+            Just(EncryptionAlgorithm::None),
+            Just(EncryptionAlgorithm::Aes256),
+            Just(EncryptionAlgorithm::ChaCha20Poly1305),
+            Just(EncryptionAlgorithm::XChaCha20Poly1305),
+            Just(EncryptionAlgorithm::Aes256GcmSiv),
+            // Every name the constructor accepts must round trip.
+            "[ -~]{0,64}"
+                .prop_filter_map("rejected by EncryptionAlgorithm::custom", |name| EncryptionAlgorithm::custom(name).ok()),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_display_round_trips(version in any::<u32>(), responsible in role(), encryption in encryption_algorithm()) {
+            let scheme = scheme(version, responsible, encryption);
+            prop_assert_eq!(BucketEncryptionScheme::from_str(&scheme.to_string()), Ok(scheme));
+        }
+
+        #[cfg(feature = "key")]
+        #[test]
+        fn prop_header_round_trips(version in any::<u32>(), responsible in role(), encryption in encryption_algorithm()) {
+            use crate::encryption::header::EncryptedObjectHeader;
+            use crate::key::kdf::KeyDeriveFunction;
+            use crate::key::KeyId;
+
+            let header = EncryptedObjectHeader {
+                scheme: scheme(version, responsible, encryption),
+                key_derive_function: KeyDeriveFunction::Argon2id,
+                nonce: vec![0; 19],
+                chunk_size: 64 * 1024,
+                key_id: KeyId::generate(),
+            };
+            let bytes = header.to_bytes().unwrap();
+            prop_assert_eq!(EncryptedObjectHeader::parse(&bytes), Ok((header, &[][..])));
+        }
+
+        #[test]
+        fn prop_legacy_spellings_normalize(version in any::<u32>(), responsible in role(), index in 0..LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS.len()) {
+            let (spelling, algorithm) = &LEGACY_ENCRYPTION_ALGORITHM_SPELLINGS[index];
+            let parsed = BucketEncryptionScheme::from_str(&format!("{version}:{responsible}:{spelling}")).unwrap();
+            prop_assert_eq!(&parsed.encryption, algorithm);
+            // Once parsed, the scheme is written back in the canonical format which must parse to the same value.
+            prop_assert_eq!(BucketEncryptionScheme::from_str(&parsed.to_string()), Ok(parsed));
+        }
+
+        #[test]
+        fn prop_parse_never_panics(input in "\\PC*") {
+            let _ = BucketEncryptionScheme::from_str(&input);
+        }
+    }
 }
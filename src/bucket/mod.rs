@@ -1,5 +1,7 @@
 pub mod bucket_guid;
+pub mod bucket_guid_encoding;
 pub mod bucket_path;
+pub mod bucket_path_matcher;
 pub mod bucket_visibility;
 pub mod bucket_feature_flags;
 pub mod bucket_permission;
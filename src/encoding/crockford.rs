@@ -0,0 +1,141 @@
+/*
+* Crockford base32, https://www.crockford.com/base32.html
+* Decoding is forgiving of the usual transcription mistakes: case is ignored, `O` reads as `0`, `I` and `L` read as `1`,
+* and hyphens or whitespace can be used to group symbols.
+* The optional check symbol is the value of the encoded bytes modulo 37.
+*/
+use super::{from_groups, to_groups};
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const CHECK_ALPHABET: &[u8; 37] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ*~$=U";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CrockfordError {
+    #[error("Invalid character {0:?}")]
+    InvalidCharacter(char),
+    #[error("Encoded length or padding is not valid")]
+    InvalidPadding,
+    #[error("Check symbol is missing")]
+    MissingCheckSymbol,
+    #[error("Check symbol does not match, the input contains a typo")]
+    CheckSymbolMismatch,
+}
+
+fn symbol_value(symbol: char) -> Result<u8, CrockfordError> {
+    let value = match symbol.to_ascii_uppercase() {
+        'O' => 0,
+        'I' | 'L' => 1,
+        upper => ALPHABET
+            .iter()
+            .position(|candidate| *candidate as char == upper)
+            .ok_or(CrockfordError::InvalidCharacter(symbol))?,
+    };
+    Ok(value as u8)
+}
+
+fn is_separator(symbol: char) -> bool {
+    symbol == '-' || symbol.is_whitespace()
+}
+
+fn check_value(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |remainder, byte| (remainder * 256 + *byte as usize) % 37)
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    to_groups(bytes, 5).into_iter().map(|group| ALPHABET[group as usize] as char).collect()
+}
+
+pub fn decode(encoded: &str) -> Result<Vec<u8>, CrockfordError> {
+    let groups = encoded
+        .chars()
+        .filter(|symbol| !is_separator(*symbol))
+        .map(|symbol| symbol_value(symbol).map(u16::from))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Padding is always less than 5 bits, anything longer was never produced by `encode`.
+    if groups.len() * 5 % 8 >= 5 {
+        return Err(CrockfordError::InvalidPadding);
+    }
+    from_groups(&groups, 5).ok_or(CrockfordError::InvalidPadding)
+}
+
+/// Encodes with a trailing check symbol, catches any single substituted or transposed symbol.
+pub fn encode_with_check(bytes: &[u8]) -> String {
+    let mut encoded = encode(bytes);
+    encoded.push(CHECK_ALPHABET[check_value(bytes)] as char);
+    encoded
+}
+
+pub fn decode_with_check(encoded: &str) -> Result<Vec<u8>, CrockfordError> {
+    let encoded = encoded.trim_end_matches(is_separator);
+    let check_symbol = encoded.chars().next_back().ok_or(CrockfordError::MissingCheckSymbol)?;
+    let bytes = decode(&encoded[..encoded.len() - check_symbol.len_utf8()])?;
+    let expected = CHECK_ALPHABET[check_value(&bytes)] as char;
+    let check_symbol = match check_symbol.to_ascii_uppercase() {
+        'O' => '0',
+        'I' | 'L' => '1',
+        upper => upper,
+    };
+    if check_symbol != expected {
+        return Err(CrockfordError::CheckSymbolMismatch);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for len in 0..40 {
+            let bytes: Vec<u8> = (0..len).map(|byte| (byte * 31 + 7) as u8).collect();
+            assert_eq!(decode(&encode(&bytes)).unwrap(), bytes);
+            assert_eq!(decode_with_check(&encode_with_check(&bytes)).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(&[0xFF]), "ZW");
+        assert_eq!(encode(b"foobar"), "CSQPYRK1E8");
+        // 0xFF = 255 = 6 * 37 + 33, the check symbol is `~`.
+        assert_eq!(encode_with_check(&[0xFF]), "ZW~");
+    }
+
+    #[test]
+    fn test_forgiving_decode() {
+        assert_eq!(decode("csqp-yrk1 e8").unwrap(), b"foobar");
+        assert_eq!(decode("CSQPYRKIE8").unwrap(), b"foobar");
+        assert_eq!(decode("CSQPYRKLE8").unwrap(), b"foobar");
+        assert_eq!(decode("0O").unwrap(), decode("00").unwrap());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(decode("CSQPYRKUE8"), Err(CrockfordError::InvalidCharacter('U')));
+        assert_eq!(decode("Z"), Err(CrockfordError::InvalidPadding));
+        assert_eq!(decode("ZZ"), Err(CrockfordError::InvalidPadding));
+        assert_eq!(decode_with_check(""), Err(CrockfordError::MissingCheckSymbol));
+        assert_eq!(decode_with_check("ZW0"), Err(CrockfordError::CheckSymbolMismatch));
+    }
+
+    #[test]
+    fn test_check_symbol_detects_typos() {
+        let encoded = encode_with_check(b"recovery share");
+        let symbols: Vec<char> = encoded.chars().collect();
+        for position in 0..symbols.len() - 1 {
+            let mut typo = symbols.clone();
+            typo[position] = if typo[position] == 'A' { 'B' } else { 'A' };
+            let typo: String = typo.into_iter().collect();
+            assert!(decode_with_check(&typo) != Ok(b"recovery share".to_vec()));
+
+            let mut transposed = symbols.clone();
+            transposed.swap(position, position + 1);
+            if transposed != symbols {
+                let transposed: String = transposed.into_iter().collect();
+                assert!(decode_with_check(&transposed) != Ok(b"recovery share".to_vec()));
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+/*
+* Text encodings meant to be read, printed and typed back in by humans.
+*/
+pub mod crockford;
+pub mod rs1024;
+
+/// Splits bytes into big-endian groups of `bits` bits, the last group is padded with zero bits.
+pub(crate) fn to_groups(bytes: &[u8], bits: u32) -> Vec<u16> {
+    debug_assert!((1..=16).contains(&bits));
+    let mut groups = Vec::with_capacity((bytes.len() * 8).div_ceil(bits as usize));
+    let mut accumulator: u32 = 0;
+    let mut accumulated_bits = 0;
+    for byte in bytes {
+        accumulator = (accumulator << 8) | *byte as u32;
+        accumulated_bits += 8;
+        while accumulated_bits >= bits {
+            accumulated_bits -= bits;
+            groups.push(((accumulator >> accumulated_bits) & ((1 << bits) - 1)) as u16);
+        }
+        accumulator &= (1 << accumulated_bits) - 1;
+    }
+    if accumulated_bits > 0 {
+        groups.push(((accumulator << (bits - accumulated_bits)) & ((1 << bits) - 1)) as u16);
+    }
+    groups
+}
+
+/// Inverse of `to_groups`, returns `None` if a group doesn't fit in `bits` or the padding bits aren't zero.
+/// Decodes to every whole byte, when the padding is a byte or more long the last byte is padding.
+pub(crate) fn from_groups(groups: &[u16], bits: u32) -> Option<Vec<u8>> {
+    debug_assert!((1..=16).contains(&bits));
+    let mut bytes = Vec::with_capacity(groups.len() * bits as usize / 8);
+    let mut accumulator: u32 = 0;
+    let mut accumulated_bits = 0;
+    for group in groups {
+        if (*group as u32) >> bits != 0 {
+            return None;
+        }
+        accumulator = (accumulator << bits) | *group as u32;
+        accumulated_bits += bits;
+        while accumulated_bits >= 8 {
+            accumulated_bits -= 8;
+            bytes.push((accumulator >> accumulated_bits) as u8);
+        }
+        accumulator &= (1 << accumulated_bits) - 1;
+    }
+    if accumulator != 0 {
+        return None;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups_round_trip() {
+        let bytes: Vec<u8> = (0..=40u8).map(|byte| byte.wrapping_mul(37)).collect();
+        for bits in [5, 10, 11] {
+            for len in 0..bytes.len() {
+                let groups = to_groups(&bytes[..len], bits);
+                assert_eq!(groups.len(), (len * 8).div_ceil(bits as usize));
+                let decoded = from_groups(&groups, bits).unwrap();
+                assert_eq!(&decoded[..len], &bytes[..len]);
+                assert!(decoded[len..].iter().all(|byte| *byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_groups_invalid() {
+        assert_eq!(from_groups(&[32], 5), None);
+        // 0b00000_00001, the second group has a non zero padding bit.
+        assert_eq!(from_groups(&[0, 1], 5), None);
+    }
+}
@@ -0,0 +1,68 @@
+/*
+* RS1024 checksum from SLIP-0039, a Reed-Solomon code over GF(1024) that appends three 10-bit words.
+* Detects any error affecting up to three words, and with high probability anything beyond that.
+*/
+
+/// Number of 10-bit words the checksum adds.
+pub const CHECKSUM_LENGTH: usize = 3;
+
+const GENERATOR: [u32; 10] = [
+    0xE0E040, 0x1C1C080, 0x3838100, 0x7070200, 0xE0E0009, 0x1C0C2412, 0x38086C24, 0x3090FC48, 0x21B1F890, 0x3F3F120,
+];
+
+fn polymod<'a>(values: impl IntoIterator<Item = &'a u16>) -> u32 {
+    let mut checksum: u32 = 1;
+    for value in values {
+        let top = checksum >> 20;
+        checksum = ((checksum & 0xFFFFF) << 10) ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+/// The customization string separates checksums of different formats, a valid checksum for one is invalid for the other.
+fn customization_values(customization: &[u8]) -> Vec<u16> {
+    customization.iter().map(|byte| *byte as u16).collect()
+}
+
+/// Creates the checksum for 10-bit `data` words.
+pub fn create_checksum(customization: &[u8], data: &[u16]) -> [u16; CHECKSUM_LENGTH] {
+    let values = customization_values(customization);
+    let polymod = polymod(values.iter().chain(data).chain(&[0; CHECKSUM_LENGTH])) ^ 1;
+    [(polymod >> 20) as u16 & 1023, (polymod >> 10) as u16 & 1023, polymod as u16 & 1023]
+}
+
+/// Verifies 10-bit words that end with their checksum.
+pub fn verify_checksum(customization: &[u8], data_with_checksum: &[u16]) -> bool {
+    data_with_checksum.len() >= CHECKSUM_LENGTH
+        && polymod(customization_values(customization).iter().chain(data_with_checksum)) == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_single_word_errors() {
+        let mut words: Vec<u16> = (0..20).map(|i| (i * 97 % 1024) as u16).collect();
+        words.extend_from_slice(&create_checksum(b"shamir", &words));
+        assert!(verify_checksum(b"shamir", &words));
+        assert!(!verify_checksum(b"other", &words));
+
+        for position in 0..words.len() {
+            for delta in [1, 2, 512, 1023] {
+                let mut corrupted = words.clone();
+                corrupted[position] ^= delta;
+                assert!(!verify_checksum(b"shamir", &corrupted));
+            }
+        }
+        // Swapping two neighbouring words is a common transcription error.
+        let mut swapped = words.clone();
+        swapped.swap(3, 4);
+        assert!(!verify_checksum(b"shamir", &swapped));
+    }
+}
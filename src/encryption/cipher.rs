@@ -0,0 +1,299 @@
+use aes_gcm::aead::{Aead, AeadInPlace, KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use generic_array::typenum::{Unsigned, U12, U24};
+use generic_array::ArrayLength;
+use secrecy::ExposeSecret;
+
+use crate::bucket::encryption_algorithm::EncryptionAlgorithm;
+use crate::key::derived_key::DerivedKey;
+use crate::key::Nonce;
+
+/// Size of the authentication tag appended by every supported AEAD cipher.
+pub const TAG_SIZE: usize = 16;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    #[error("Failed to encrypt the buffer")]
+    EncryptionFailed,
+    /// The ciphertext, tag or associated data has been tampered with, or the wrong key/nonce was used.
+    #[error("Failed to decrypt the buffer, authentication failed")]
+    DecryptionFailed,
+    #[error("Nonce must be {expected} bytes, got {actual}")]
+    InvalidNonceLength { expected: usize, actual: usize },
+    #[error("Encryption algorithm {0} is not supported")]
+    UnsupportedAlgorithm(EncryptionAlgorithm),
+}
+
+/// An authenticated cipher (AEAD) keyed with a `DerivedKey`.
+/// The nonce length is part of the type so a nonce generator for one algorithm can't be used with another.
+pub trait AeadCipher: Sized {
+    type NonceLength: ArrayLength;
+    const ALGORITHM: EncryptionAlgorithm;
+
+    fn new(key: &DerivedKey) -> Self;
+
+    /// Encrypts the plaintext and returns the ciphertext with the authentication tag appended.
+    fn encrypt(
+        &self,
+        nonce: &Nonce<Self::NonceLength>,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CipherError>;
+
+    /// Decrypts and authenticates ciphertext produced by `encrypt`.
+    fn decrypt(
+        &self,
+        nonce: &Nonce<Self::NonceLength>,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CipherError>;
+
+    /// Encrypts the buffer in place, the authentication tag is appended to the buffer.
+    fn encrypt_in_place(
+        &self,
+        nonce: &Nonce<Self::NonceLength>,
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), CipherError>;
+
+    /// Decrypts the buffer in place, the authentication tag is removed from the buffer.
+    fn decrypt_in_place(
+        &self,
+        nonce: &Nonce<Self::NonceLength>,
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), CipherError>;
+}
+
+/// Implements `AeadCipher` for a RustCrypto AEAD, they all share the same `aead` traits.
+macro_rules! impl_aead_cipher {
+    ($name:ident, $inner:ty, $nonce_length:ty, $algorithm:expr) => {
+        pub struct $name($inner);
+
+        impl AeadCipher for $name {
+            type NonceLength = $nonce_length;
+            const ALGORITHM: EncryptionAlgorithm = $algorithm;
+
+            fn new(key: &DerivedKey) -> Self {
+                Self(<$inner>::new_from_slice(key.key.expose_secret().as_slice())
+                    .expect("DerivedKey is always 256 bits"))
+            }
+
+            fn encrypt(
+                &self,
+                nonce: &Nonce<Self::NonceLength>,
+                plaintext: &[u8],
+                associated_data: &[u8],
+            ) -> Result<Vec<u8>, CipherError> {
+                let payload = Payload { msg: plaintext, aad: associated_data };
+                self.0
+                    .encrypt(nonce.as_slice().into(), payload)
+                    .map_err(|_| CipherError::EncryptionFailed)
+            }
+
+            fn decrypt(
+                &self,
+                nonce: &Nonce<Self::NonceLength>,
+                ciphertext: &[u8],
+                associated_data: &[u8],
+            ) -> Result<Vec<u8>, CipherError> {
+                let payload = Payload { msg: ciphertext, aad: associated_data };
+                self.0
+                    .decrypt(nonce.as_slice().into(), payload)
+                    .map_err(|_| CipherError::DecryptionFailed)
+            }
+
+            fn encrypt_in_place(
+                &self,
+                nonce: &Nonce<Self::NonceLength>,
+                associated_data: &[u8],
+                buffer: &mut Vec<u8>,
+            ) -> Result<(), CipherError> {
+                self.0
+                    .encrypt_in_place(nonce.as_slice().into(), associated_data, buffer)
+                    .map_err(|_| CipherError::EncryptionFailed)
+            }
+
+            fn decrypt_in_place(
+                &self,
+                nonce: &Nonce<Self::NonceLength>,
+                associated_data: &[u8],
+                buffer: &mut Vec<u8>,
+            ) -> Result<(), CipherError> {
+                self.0
+                    .decrypt_in_place(nonce.as_slice().into(), associated_data, buffer)
+                    .map_err(|_| CipherError::DecryptionFailed)
+            }
+        }
+    };
+}
+
+impl_aead_cipher!(Aes256GcmCipher, Aes256Gcm, U12, EncryptionAlgorithm::Aes256);
+impl_aead_cipher!(ChaCha20Poly1305Cipher, ChaCha20Poly1305, U12, EncryptionAlgorithm::ChaCha20Poly1305);
+impl_aead_cipher!(XChaCha20Poly1305Cipher, XChaCha20Poly1305, U24, EncryptionAlgorithm::XChaCha20Poly1305);
+impl_aead_cipher!(Aes256GcmSivCipher, Aes256GcmSiv, U12, EncryptionAlgorithm::Aes256GcmSiv);
+
+/// Ciphers that stay safe with random nonces, see `RandomNonceGenerator`.
+/// Implementing it for a cipher with short nonces and no misuse resistance, like AES-GCM, breaks confidentiality.
+pub trait RandomNonceSafe: AeadCipher {}
+
+impl RandomNonceSafe for XChaCha20Poly1305Cipher {}
+impl RandomNonceSafe for Aes256GcmSivCipher {}
+
+/// Cipher selected at runtime from an `EncryptionAlgorithm`, for when the algorithm is only known from stored metadata.
+/// Nonces are passed as slices and checked against the length the algorithm expects.
+pub enum EncryptionAlgorithmCipher {
+    // The AES key schedule is much larger than the ChaCha keys, boxed to keep the enum small.
+    Aes256(Box<Aes256GcmCipher>),
+    ChaCha20Poly1305(ChaCha20Poly1305Cipher),
+    XChaCha20Poly1305(XChaCha20Poly1305Cipher),
+    Aes256GcmSiv(Box<Aes256GcmSivCipher>),
+}
+
+impl EncryptionAlgorithmCipher {
+    pub fn new(algorithm: &EncryptionAlgorithm, key: &DerivedKey) -> Result<Self, CipherError> {
+        match algorithm {
+            EncryptionAlgorithm::Aes256 => Ok(Self::Aes256(Box::new(Aes256GcmCipher::new(key)))),
+            EncryptionAlgorithm::ChaCha20Poly1305 => Ok(Self::ChaCha20Poly1305(ChaCha20Poly1305Cipher::new(key))),
+            EncryptionAlgorithm::XChaCha20Poly1305 => Ok(Self::XChaCha20Poly1305(XChaCha20Poly1305Cipher::new(key))),
+            EncryptionAlgorithm::Aes256GcmSiv => Ok(Self::Aes256GcmSiv(Box::new(Aes256GcmSivCipher::new(key)))),
+            EncryptionAlgorithm::None | EncryptionAlgorithm::Custom(_) => {
+                Err(CipherError::UnsupportedAlgorithm(algorithm.clone()))
+            }
+        }
+    }
+
+    pub fn algorithm(&self) -> EncryptionAlgorithm {
+        match self {
+            Self::Aes256(_) => Aes256GcmCipher::ALGORITHM,
+            Self::ChaCha20Poly1305(_) => ChaCha20Poly1305Cipher::ALGORITHM,
+            Self::XChaCha20Poly1305(_) => XChaCha20Poly1305Cipher::ALGORITHM,
+            Self::Aes256GcmSiv(_) => Aes256GcmSivCipher::ALGORITHM,
+        }
+    }
+
+    /// Length of the nonce in bytes the selected algorithm expects.
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            Self::Aes256(_) => <Aes256GcmCipher as AeadCipher>::NonceLength::USIZE,
+            Self::ChaCha20Poly1305(_) => <ChaCha20Poly1305Cipher as AeadCipher>::NonceLength::USIZE,
+            Self::XChaCha20Poly1305(_) => <XChaCha20Poly1305Cipher as AeadCipher>::NonceLength::USIZE,
+            Self::Aes256GcmSiv(_) => <Aes256GcmSivCipher as AeadCipher>::NonceLength::USIZE,
+        }
+    }
+
+    fn nonce<TNonceLength: ArrayLength>(nonce: &[u8]) -> Result<Nonce<TNonceLength>, CipherError> {
+        generic_array::GenericArray::try_from_slice(nonce)
+            .map(|nonce| Nonce::new(nonce.clone()))
+            .map_err(|_| CipherError::InvalidNonceLength {
+                expected: TNonceLength::USIZE,
+                actual: nonce.len(),
+            })
+    }
+
+    pub fn encrypt(&self, nonce: &[u8], plaintext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match self {
+            Self::Aes256(cipher) => cipher.encrypt(&Self::nonce(nonce)?, plaintext, associated_data),
+            Self::ChaCha20Poly1305(cipher) => cipher.encrypt(&Self::nonce(nonce)?, plaintext, associated_data),
+            Self::XChaCha20Poly1305(cipher) => cipher.encrypt(&Self::nonce(nonce)?, plaintext, associated_data),
+            Self::Aes256GcmSiv(cipher) => cipher.encrypt(&Self::nonce(nonce)?, plaintext, associated_data),
+        }
+    }
+
+    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match self {
+            Self::Aes256(cipher) => cipher.decrypt(&Self::nonce(nonce)?, ciphertext, associated_data),
+            Self::ChaCha20Poly1305(cipher) => cipher.decrypt(&Self::nonce(nonce)?, ciphertext, associated_data),
+            Self::XChaCha20Poly1305(cipher) => cipher.decrypt(&Self::nonce(nonce)?, ciphertext, associated_data),
+            Self::Aes256GcmSiv(cipher) => cipher.decrypt(&Self::nonce(nonce)?, ciphertext, associated_data),
+        }
+    }
+
+    pub fn encrypt_in_place(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<(), CipherError> {
+        match self {
+            Self::Aes256(cipher) => cipher.encrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::ChaCha20Poly1305(cipher) => cipher.encrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::XChaCha20Poly1305(cipher) => cipher.encrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::Aes256GcmSiv(cipher) => cipher.encrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+        }
+    }
+
+    pub fn decrypt_in_place(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<(), CipherError> {
+        match self {
+            Self::Aes256(cipher) => cipher.decrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::ChaCha20Poly1305(cipher) => cipher.decrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::XChaCha20Poly1305(cipher) => cipher.decrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+            Self::Aes256GcmSiv(cipher) => cipher.decrypt_in_place(&Self::nonce(nonce)?, associated_data, buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use generic_array::GenericArray;
+
+    use crate::key::memory::secure_generic_array::SecreteGenericArray;
+
+    use super::*;
+
+    fn derived_key(byte: u8) -> DerivedKey {
+        DerivedKey {
+            key: SecreteGenericArray::new(GenericArray::from_array([byte; 32])),
+        }
+    }
+
+    fn round_trip<TCipher: AeadCipher>() {
+        let cipher = TCipher::new(&derived_key(7));
+        let nonce = Nonce::new(GenericArray::<u8, TCipher::NonceLength>::default());
+        let ciphertext = cipher.encrypt(&nonce, b"bucket object", b"/path/file.txt").unwrap();
+        assert_eq!(ciphertext.len(), b"bucket object".len() + TAG_SIZE);
+        assert_eq!(cipher.decrypt(&nonce, &ciphertext, b"/path/file.txt").unwrap(), b"bucket object");
+
+        // Wrong associated data must fail authentication.
+        assert_eq!(
+            cipher.decrypt(&nonce, &ciphertext, b"/other/file.txt"),
+            Err(CipherError::DecryptionFailed)
+        );
+        // Wrong key must fail authentication.
+        let other = TCipher::new(&derived_key(8));
+        assert_eq!(
+            other.decrypt(&nonce, &ciphertext, b"/path/file.txt"),
+            Err(CipherError::DecryptionFailed)
+        );
+
+        let mut buffer = b"in place".to_vec();
+        cipher.encrypt_in_place(&nonce, b"", &mut buffer).unwrap();
+        cipher.decrypt_in_place(&nonce, b"", &mut buffer).unwrap();
+        assert_eq!(buffer, b"in place");
+    }
+
+    #[test]
+    fn test_round_trip_every_algorithm() {
+        round_trip::<Aes256GcmCipher>();
+        round_trip::<ChaCha20Poly1305Cipher>();
+        round_trip::<XChaCha20Poly1305Cipher>();
+        round_trip::<Aes256GcmSivCipher>();
+    }
+
+    #[test]
+    fn test_runtime_cipher_checks_nonce_length() {
+        let cipher = EncryptionAlgorithmCipher::new(&EncryptionAlgorithm::XChaCha20Poly1305, &derived_key(1)).unwrap();
+        assert_eq!(cipher.nonce_size(), 24);
+        assert_eq!(
+            cipher.encrypt(&[0u8; 12], b"data", b""),
+            Err(CipherError::InvalidNonceLength { expected: 24, actual: 12 })
+        );
+        let ciphertext = cipher.encrypt(&[0u8; 24], b"data", b"").unwrap();
+        assert_eq!(cipher.decrypt(&[0u8; 24], &ciphertext, b"").unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_unsupported_algorithm() {
+        assert!(matches!(
+            EncryptionAlgorithmCipher::new(&EncryptionAlgorithm::None, &derived_key(1)),
+            Err(CipherError::UnsupportedAlgorithm(EncryptionAlgorithm::None))
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+/*
+* Every encrypted object starts with a small binary header describing how it was encrypted,
+* so a reader can pick the right decryptor without any out-of-band metadata.
+*
+* Layout of header version 1, all integers are big-endian:
+*   magic                 4 bytes   "BDEH"
+*   header version        u8
+*   scheme version        u32       `BucketEncryptionScheme::version`
+*   role                  u8        0 = server, 1 = client
+*   algorithm             u8        see `algorithm_id`, custom algorithms are followed by a u8 length and the UTF-8 name
+*   key derive function   u8        see `key_derive_function_id`
+*   nonce length          u8
+*   nonce                 nonce length bytes, the STREAM nonce prefix for chunked objects
+*   chunk size            u32       plaintext bytes per STREAM chunk, 1 to `MAX_CHUNK_SIZE`
+*   key id                16 bytes
+*
+* The encoded header is the associated data of every chunk, so changing any header field makes decryption fail.
+*/
+use uuid::Uuid;
+
+use crate::bucket::encryption_algorithm::EncryptionAlgorithm;
+use crate::bucket::encryption_scheme::{BucketEncryptionScheme, Role};
+use crate::key::kdf::KeyDeriveFunction;
+use crate::key::KeyId;
+
+use super::stream::MAX_CHUNK_SIZE;
+
+pub const ENCRYPTED_OBJECT_HEADER_MAGIC: [u8; 4] = *b"BDEH";
+pub const ENCRYPTED_OBJECT_HEADER_VERSION: u8 = 1;
+const CUSTOM_ALGORITHM_ID: u8 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedObjectHeader {
+    pub scheme: BucketEncryptionScheme,
+    pub key_derive_function: KeyDeriveFunction,
+    /// Nonce, or nonce prefix for chunked objects, used to encrypt the object.
+    pub nonce: Vec<u8>,
+    /// Plaintext bytes sealed in every chunk, see `StreamCipher`.
+    pub chunk_size: u32,
+    /// The key the object was encrypted with.
+    pub key_id: KeyId,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EncryptedObjectHeaderError {
+    #[error("Header is truncated")]
+    Truncated,
+    #[error("Header does not start with the encrypted object magic bytes")]
+    InvalidMagic,
+    #[error("Unsupported header version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown role id {0}")]
+    UnknownRole(u8),
+    #[error("Unknown encryption algorithm id {0}")]
+    UnknownAlgorithm(u8),
+    #[error("Custom encryption algorithm name is not valid")]
+    InvalidCustomAlgorithm,
+    #[error("Unknown key derive function id {0}")]
+    UnknownKeyDeriveFunction(u8),
+    #[error("Field {0} is longer than 255 bytes")]
+    FieldTooLong(&'static str),
+    #[error("Chunk size {0} must be between 1 and {max} bytes", max = MAX_CHUNK_SIZE)]
+    InvalidChunkSize(u32),
+}
+
+fn validate_chunk_size(chunk_size: u32) -> Result<u32, EncryptedObjectHeaderError> {
+    if chunk_size == 0 || chunk_size as usize > MAX_CHUNK_SIZE {
+        return Err(EncryptedObjectHeaderError::InvalidChunkSize(chunk_size));
+    }
+    Ok(chunk_size)
+}
+
+fn role_id(role: &Role) -> u8 {
+    match role {
+        Role::Server => 0,
+        Role::Client => 1,
+    }
+}
+
+fn role_from_id(id: u8) -> Result<Role, EncryptedObjectHeaderError> {
+    match id {
+        0 => Ok(Role::Server),
+        1 => Ok(Role::Client),
+        _ => Err(EncryptedObjectHeaderError::UnknownRole(id)),
+    }
+}
+
+/// Ids are part of the stored format, never reuse or renumber them.
+fn algorithm_id(algorithm: &EncryptionAlgorithm) -> u8 {
+    match algorithm {
+        EncryptionAlgorithm::None => 0,
+        EncryptionAlgorithm::Aes256 => 1,
+        EncryptionAlgorithm::ChaCha20Poly1305 => 2,
+        EncryptionAlgorithm::XChaCha20Poly1305 => 3,
+        EncryptionAlgorithm::Aes256GcmSiv => 4,
+        EncryptionAlgorithm::Custom(_) => CUSTOM_ALGORITHM_ID,
+    }
+}
+
+fn key_derive_function_id(key_derive_function: &KeyDeriveFunction) -> u8 {
+    match key_derive_function {
+        KeyDeriveFunction::Argon2id => 0,
+        KeyDeriveFunction::PBKDF2 => 1,
+    }
+}
+
+fn key_derive_function_from_id(id: u8) -> Result<KeyDeriveFunction, EncryptedObjectHeaderError> {
+    match id {
+        0 => Ok(KeyDeriveFunction::Argon2id),
+        1 => Ok(KeyDeriveFunction::PBKDF2),
+        _ => Err(EncryptedObjectHeaderError::UnknownKeyDeriveFunction(id)),
+    }
+}
+
+/// Cursor over the header bytes, every read fails with `Truncated` instead of panicking.
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], EncryptedObjectHeaderError> {
+        if self.bytes.len() < len {
+            return Err(EncryptedObjectHeaderError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, EncryptedObjectHeaderError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, EncryptedObjectHeaderError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn length_prefixed(&mut self) -> Result<&'a [u8], EncryptedObjectHeaderError> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+}
+
+fn push_length_prefixed(buffer: &mut Vec<u8>, field: &'static str, value: &[u8]) -> Result<(), EncryptedObjectHeaderError> {
+    let len = u8::try_from(value.len()).map_err(|_| EncryptedObjectHeaderError::FieldTooLong(field))?;
+    buffer.push(len);
+    buffer.extend_from_slice(value);
+    Ok(())
+}
+
+impl EncryptedObjectHeader {
+    /// Encodes the header, the encrypted payload follows directly after it.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncryptedObjectHeaderError> {
+        let mut buffer = Vec::with_capacity(32 + self.nonce.len());
+        buffer.extend_from_slice(&ENCRYPTED_OBJECT_HEADER_MAGIC);
+        buffer.push(ENCRYPTED_OBJECT_HEADER_VERSION);
+        buffer.extend_from_slice(&self.scheme.version.to_be_bytes());
+        buffer.push(role_id(&self.scheme.responsible));
+        buffer.push(algorithm_id(&self.scheme.encryption));
+        if let EncryptionAlgorithm::Custom(name) = &self.scheme.encryption {
+            EncryptionAlgorithm::custom(name.as_str()).map_err(|_| EncryptedObjectHeaderError::InvalidCustomAlgorithm)?;
+            push_length_prefixed(&mut buffer, "custom algorithm", name.as_bytes())?;
+        }
+        buffer.push(key_derive_function_id(&self.key_derive_function));
+        push_length_prefixed(&mut buffer, "nonce", &self.nonce)?;
+        buffer.extend_from_slice(&validate_chunk_size(self.chunk_size)?.to_be_bytes());
+        buffer.extend_from_slice(self.key_id.0.as_bytes());
+        Ok(buffer)
+    }
+
+    /// Parses the header at the start of an encrypted object, returns the header and the encrypted payload after it.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, &[u8]), EncryptedObjectHeaderError> {
+        let mut reader = HeaderReader { bytes };
+        if reader.take(ENCRYPTED_OBJECT_HEADER_MAGIC.len())? != ENCRYPTED_OBJECT_HEADER_MAGIC {
+            return Err(EncryptedObjectHeaderError::InvalidMagic);
+        }
+        let header_version = reader.u8()?;
+        if header_version != ENCRYPTED_OBJECT_HEADER_VERSION {
+            return Err(EncryptedObjectHeaderError::UnsupportedVersion(header_version));
+        }
+        let version = reader.u32()?;
+        let responsible = role_from_id(reader.u8()?)?;
+        let encryption = match reader.u8()? {
+            0 => EncryptionAlgorithm::None,
+            1 => EncryptionAlgorithm::Aes256,
+            2 => EncryptionAlgorithm::ChaCha20Poly1305,
+            3 => EncryptionAlgorithm::XChaCha20Poly1305,
+            4 => EncryptionAlgorithm::Aes256GcmSiv,
+            CUSTOM_ALGORITHM_ID => {
+                std::str::from_utf8(reader.length_prefixed()?)
+                    .ok()
+                    .and_then(|name| EncryptionAlgorithm::custom(name).ok())
+                    .ok_or(EncryptedObjectHeaderError::InvalidCustomAlgorithm)?
+            }
+            id => return Err(EncryptedObjectHeaderError::UnknownAlgorithm(id)),
+        };
+        let key_derive_function = key_derive_function_from_id(reader.u8()?)?;
+        let nonce = reader.length_prefixed()?.to_vec();
+        let chunk_size = validate_chunk_size(reader.u32()?)?;
+        let key_id = KeyId(Uuid::from_slice(reader.take(16)?).unwrap());
+
+        Ok((
+            Self {
+                scheme: BucketEncryptionScheme {
+                    version,
+                    responsible,
+                    encryption,
+                },
+                key_derive_function,
+                nonce,
+                chunk_size,
+                key_id,
+            },
+            reader.bytes,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(encryption: EncryptionAlgorithm) -> EncryptedObjectHeader {
+        EncryptedObjectHeader {
+            scheme: BucketEncryptionScheme {
+                version: 1,
+                responsible: Role::Client,
+                encryption,
+            },
+            key_derive_function: KeyDeriveFunction::Argon2id,
+            nonce: vec![1, 2, 3, 4, 5, 6, 7],
+            chunk_size: 64 * 1024,
+            key_id: KeyId(Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for encryption in [
+            EncryptionAlgorithm::None,
+            EncryptionAlgorithm::Aes256,
+            EncryptionAlgorithm::ChaCha20Poly1305,
+            EncryptionAlgorithm::XChaCha20Poly1305,
+            EncryptionAlgorithm::Aes256GcmSiv,
+            EncryptionAlgorithm::Custom("rot13".to_string()),
+        ] {
+            let header = header(encryption);
+            let mut bytes = header.to_bytes().unwrap();
+            bytes.extend_from_slice(b"payload");
+            let (parsed, payload) = EncryptedObjectHeader::parse(&bytes).unwrap();
+            assert_eq!(parsed, header);
+            assert_eq!(payload, b"payload");
+        }
+    }
+
+    #[test]
+    fn test_server_role_and_pbkdf2_round_trip() {
+        let mut header = header(EncryptionAlgorithm::Aes256);
+        header.scheme.responsible = Role::Server;
+        header.key_derive_function = KeyDeriveFunction::PBKDF2;
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(EncryptedObjectHeader::parse(&bytes).unwrap().0, header);
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let bytes = header(EncryptionAlgorithm::Aes256).to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                EncryptedObjectHeader::parse(&bytes[..len]),
+                Err(EncryptedObjectHeaderError::Truncated)
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        let bytes = header(EncryptionAlgorithm::Aes256).to_bytes().unwrap();
+
+        let mut invalid = bytes.clone();
+        invalid[0] = b'X';
+        assert_eq!(EncryptedObjectHeader::parse(&invalid), Err(EncryptedObjectHeaderError::InvalidMagic));
+
+        let mut invalid = bytes.clone();
+        invalid[4] = 2;
+        assert_eq!(EncryptedObjectHeader::parse(&invalid), Err(EncryptedObjectHeaderError::UnsupportedVersion(2)));
+
+        let mut invalid = bytes.clone();
+        invalid[9] = 7;
+        assert_eq!(EncryptedObjectHeader::parse(&invalid), Err(EncryptedObjectHeaderError::UnknownRole(7)));
+
+        let mut invalid = bytes.clone();
+        invalid[10] = 42;
+        assert_eq!(EncryptedObjectHeader::parse(&invalid), Err(EncryptedObjectHeaderError::UnknownAlgorithm(42)));
+
+        let mut invalid = bytes;
+        invalid[11] = 9;
+        assert_eq!(EncryptedObjectHeader::parse(&invalid), Err(EncryptedObjectHeaderError::UnknownKeyDeriveFunction(9)));
+    }
+
+    #[test]
+    fn test_chunk_size_out_of_range() {
+        let mut header = header(EncryptionAlgorithm::Aes256);
+        let bytes = header.to_bytes().unwrap();
+        let chunk_size_start = bytes.len() - 16 - 4;
+        for chunk_size in [0, u32::MAX] {
+            let mut forged = bytes.clone();
+            forged[chunk_size_start..chunk_size_start + 4].copy_from_slice(&chunk_size.to_be_bytes());
+            assert_eq!(EncryptedObjectHeader::parse(&forged), Err(EncryptedObjectHeaderError::InvalidChunkSize(chunk_size)));
+        }
+
+        header.chunk_size = MAX_CHUNK_SIZE as u32 + 1;
+        assert_eq!(header.to_bytes(), Err(EncryptedObjectHeaderError::InvalidChunkSize(MAX_CHUNK_SIZE as u32 + 1)));
+    }
+
+    #[test]
+    fn test_invalid_custom_algorithm() {
+        for name in ["", "custom-rot13"] {
+            let header = header(EncryptionAlgorithm::Custom(name.to_string()));
+            assert_eq!(header.to_bytes(), Err(EncryptedObjectHeaderError::InvalidCustomAlgorithm));
+        }
+    }
+
+    #[test]
+    fn test_nonce_too_long() {
+        let mut header = header(EncryptionAlgorithm::Aes256);
+        header.nonce = vec![0; 256];
+        assert_eq!(header.to_bytes(), Err(EncryptedObjectHeaderError::FieldTooLong("nonce")));
+    }
+}
@@ -0,0 +1,4 @@
+// Encryption of bucket objects, built on top of the keys in the `key` module.
+pub mod cipher;
+pub mod stream;
+pub mod header;
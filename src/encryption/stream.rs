@@ -0,0 +1,527 @@
+/*
+* Chunked streaming encryption, based on the STREAM construction (Hoang, Reyhanitabar, Rogaway, Vizár).
+* The plaintext is split into fixed size chunks, every chunk is sealed on its own with the nonce:
+*   nonce_prefix || chunk_counter (u32 big-endian) || last_chunk_flag (u8)
+* The nonce prefix is random per object and fills the remaining bytes of the algorithm nonce.
+* Reordering chunks breaks the counter, dropping chunks from the end breaks the last chunk flag.
+*
+* The last chunk is always shorter than `chunk_size` (it's empty when the plaintext is a multiple of the chunk size),
+* so a reader knows a chunk is the last one from its length alone, and the chunk layout of a ranged read can be computed
+* from the encrypted length.
+*/
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use rand::{CryptoRng, RngCore};
+
+use crate::bucket::encryption_scheme::BucketEncryptionScheme;
+use crate::key::derived_key::DerivedKey;
+
+use super::cipher::{CipherError, EncryptionAlgorithmCipher, TAG_SIZE};
+use super::header::{EncryptedObjectHeader, EncryptedObjectHeaderError};
+
+/// Default amount of plaintext sealed in every chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// Largest accepted chunk, readers allocate a whole chunk before it's authenticated.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// Bytes of the nonce used by the chunk counter and the last chunk flag.
+const NONCE_SUFFIX_SIZE: usize = 5;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum StreamError {
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+    #[error("Nonce prefix must be {expected} bytes, got {actual}")]
+    InvalidNoncePrefixLength { expected: usize, actual: usize },
+    #[error("Chunk size must be between 1 and {max} bytes", max = MAX_CHUNK_SIZE)]
+    InvalidChunkSize,
+    #[error("Stream ended before the last chunk")]
+    Truncated,
+    #[error("Stream contains data after the last chunk")]
+    TrailingData,
+    #[error("Stream contains more chunks than the chunk counter can address")]
+    TooManyChunks,
+    #[error("Requested range {start}..{end} is outside the plaintext of {len} bytes")]
+    RangeOutOfBounds { start: u64, end: u64, len: u64 },
+    #[error(transparent)]
+    Header(#[from] EncryptedObjectHeaderError),
+}
+
+impl From<StreamError> for io::Error {
+    fn from(value: StreamError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// The chunks covering a plaintext range, and where to find them in the encrypted stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRange {
+    /// Index of the first chunk that must be decrypted.
+    pub first_chunk: u32,
+    /// Index of the last chunk that must be decrypted.
+    pub last_chunk: u32,
+    /// Byte range of the chunks in the encrypted stream, this is what must be fetched from storage.
+    pub encrypted: Range<u64>,
+    /// The requested plaintext range, relative to the start of `first_chunk`.
+    pub plaintext: Range<usize>,
+    /// Whether `last_chunk` is the final chunk of the stream.
+    pub includes_final_chunk: bool,
+}
+
+/// Seals and opens the chunks of a single encrypted object.
+pub struct StreamCipher {
+    cipher: EncryptionAlgorithmCipher,
+    nonce_prefix: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl StreamCipher {
+    pub fn new(cipher: EncryptionAlgorithmCipher, nonce_prefix: &[u8], chunk_size: usize) -> Result<Self, StreamError> {
+        let expected = Self::nonce_prefix_size(&cipher);
+        if nonce_prefix.len() != expected {
+            return Err(StreamError::InvalidNoncePrefixLength { expected, actual: nonce_prefix.len() });
+        }
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(StreamError::InvalidChunkSize);
+        }
+        Ok(Self {
+            cipher,
+            nonce_prefix: nonce_prefix.to_vec(),
+            chunk_size,
+        })
+    }
+
+    /// Creates a stream cipher using the algorithm selected by the bucket encryption scheme.
+    pub fn from_scheme(
+        scheme: &BucketEncryptionScheme,
+        key: &DerivedKey,
+        nonce_prefix: &[u8],
+        chunk_size: usize,
+    ) -> Result<Self, StreamError> {
+        let cipher = EncryptionAlgorithmCipher::new(&scheme.encryption, key)?;
+        Self::new(cipher, nonce_prefix, chunk_size)
+    }
+
+    /// Creates the stream cipher an object header describes.
+    /// Use `header.to_bytes()` as the associated data, so the header can't be changed without breaking every chunk.
+    pub fn from_header(header: &EncryptedObjectHeader, key: &DerivedKey) -> Result<Self, StreamError> {
+        let chunk_size = usize::try_from(header.chunk_size).map_err(|_| StreamError::InvalidChunkSize)?;
+        Self::from_scheme(&header.scheme, key, &header.nonce, chunk_size)
+    }
+
+    /// Size of the random nonce prefix for the cipher, the rest of the nonce is used by the chunk counter.
+    pub fn nonce_prefix_size(cipher: &EncryptionAlgorithmCipher) -> usize {
+        cipher.nonce_size() - NONCE_SUFFIX_SIZE
+    }
+
+    /// Generates a random nonce prefix, a new prefix must be used for every object encrypted with the same key.
+    pub fn generate_nonce_prefix<TCryptoRng>(cipher: &EncryptionAlgorithmCipher, csprng: &mut TCryptoRng) -> Vec<u8>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        let mut nonce_prefix = vec![0u8; Self::nonce_prefix_size(cipher)];
+        csprng.fill_bytes(&mut nonce_prefix);
+        nonce_prefix
+    }
+
+    pub fn nonce_prefix(&self) -> &[u8] {
+        &self.nonce_prefix
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Size of a full chunk once encrypted.
+    pub fn encrypted_chunk_size(&self) -> usize {
+        self.chunk_size + TAG_SIZE
+    }
+
+    fn chunk_nonce(&self, index: u32, is_last: bool) -> Vec<u8> {
+        let mut nonce = Vec::with_capacity(self.nonce_prefix.len() + NONCE_SUFFIX_SIZE);
+        nonce.extend_from_slice(&self.nonce_prefix);
+        nonce.extend_from_slice(&index.to_be_bytes());
+        nonce.push(is_last as u8);
+        nonce
+    }
+
+    /// Encrypts a single chunk in place, every chunk except the last must be exactly `chunk_size` bytes.
+    pub fn encrypt_chunk(&self, index: u32, is_last: bool, associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<(), StreamError> {
+        debug_assert!(if is_last { buffer.len() < self.chunk_size } else { buffer.len() == self.chunk_size });
+        self.cipher.encrypt_in_place(&self.chunk_nonce(index, is_last), associated_data, buffer)?;
+        Ok(())
+    }
+
+    /// Decrypts a single chunk in place.
+    pub fn decrypt_chunk(&self, index: u32, is_last: bool, associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<(), StreamError> {
+        self.cipher.decrypt_in_place(&self.chunk_nonce(index, is_last), associated_data, buffer)?;
+        Ok(())
+    }
+
+    /// Number of chunks in a stream with the given plaintext length, including the final chunk.
+    fn chunk_count(&self, plaintext_len: u64) -> u64 {
+        plaintext_len / self.chunk_size as u64 + 1
+    }
+
+    /// Length of the encrypted stream for the given plaintext length.
+    pub fn encrypted_len(&self, plaintext_len: u64) -> u64 {
+        plaintext_len + self.chunk_count(plaintext_len) * TAG_SIZE as u64
+    }
+
+    /// Length of the plaintext for the given encrypted stream length.
+    pub fn plaintext_len(&self, encrypted_len: u64) -> Result<u64, StreamError> {
+        let full_chunks = encrypted_len / self.encrypted_chunk_size() as u64;
+        let last_chunk = encrypted_len % self.encrypted_chunk_size() as u64;
+        if last_chunk < TAG_SIZE as u64 {
+            return Err(StreamError::Truncated);
+        }
+        Ok(full_chunks * self.chunk_size as u64 + last_chunk - TAG_SIZE as u64)
+    }
+
+    /// Computes which chunks must be fetched and decrypted to read the plaintext `range`.
+    pub fn chunk_range(&self, range: Range<u64>, encrypted_len: u64) -> Result<ChunkRange, StreamError> {
+        let plaintext_len = self.plaintext_len(encrypted_len)?;
+        if range.start > range.end || range.end > plaintext_len {
+            return Err(StreamError::RangeOutOfBounds { start: range.start, end: range.end, len: plaintext_len });
+        }
+        let chunk_size = self.chunk_size as u64;
+        let final_chunk = self.chunk_count(plaintext_len) - 1;
+        let first_chunk = range.start / chunk_size;
+        // The end is exclusive, an empty range still decrypts the chunk it starts in to authenticate it.
+        let last_chunk = (range.end.saturating_sub(1) / chunk_size).max(first_chunk).min(final_chunk);
+        let encrypted_chunk_size = self.encrypted_chunk_size() as u64;
+        let encrypted_end = ((last_chunk + 1) * encrypted_chunk_size).min(encrypted_len);
+        let offset = first_chunk * chunk_size;
+        Ok(ChunkRange {
+            first_chunk: u32::try_from(first_chunk).map_err(|_| StreamError::TooManyChunks)?,
+            last_chunk: u32::try_from(last_chunk).map_err(|_| StreamError::TooManyChunks)?,
+            encrypted: first_chunk * encrypted_chunk_size..encrypted_end,
+            plaintext: (range.start - offset) as usize..(range.end - offset) as usize,
+            includes_final_chunk: last_chunk == final_chunk,
+        })
+    }
+
+    /// Decrypts the chunks fetched for a `ChunkRange` and returns only the requested plaintext.
+    pub fn decrypt_range(&self, chunk_range: &ChunkRange, encrypted: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, StreamError> {
+        let mut plaintext = Vec::with_capacity(encrypted.len());
+        let mut chunks = encrypted.chunks(self.encrypted_chunk_size());
+        for index in chunk_range.first_chunk..=chunk_range.last_chunk {
+            let chunk = chunks.next().ok_or(StreamError::Truncated)?;
+            let is_last = chunk_range.includes_final_chunk && index == chunk_range.last_chunk;
+            let mut buffer = chunk.to_vec();
+            self.decrypt_chunk(index, is_last, associated_data, &mut buffer)?;
+            plaintext.extend_from_slice(&buffer);
+        }
+        if chunks.next().is_some() {
+            return Err(StreamError::TrailingData);
+        }
+        plaintext
+            .get(chunk_range.plaintext.clone())
+            .map(<[u8]>::to_vec)
+            .ok_or(StreamError::Truncated)
+    }
+}
+
+/// `Write` adapter that encrypts everything written to it into the chunked stream format.
+/// `finish` must be called to write the final chunk, without it the stream is rejected as truncated.
+pub struct EncryptWriter<W: Write> {
+    stream: StreamCipher,
+    inner: W,
+    associated_data: Vec<u8>,
+    buffer: Vec<u8>,
+    index: u32,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(stream: StreamCipher, inner: W, associated_data: &[u8]) -> Self {
+        let capacity = stream.encrypted_chunk_size();
+        Self {
+            stream,
+            inner,
+            associated_data: associated_data.to_vec(),
+            buffer: Vec::with_capacity(capacity),
+            index: 0,
+        }
+    }
+
+    /// Writes `header` to `inner` and encrypts everything written after it, bound to the header.
+    pub fn with_header(header: &EncryptedObjectHeader, key: &DerivedKey, mut inner: W) -> io::Result<Self> {
+        let stream = StreamCipher::from_header(header, key)?;
+        let encoded = header.to_bytes().map_err(StreamError::from)?;
+        inner.write_all(&encoded)?;
+        Ok(Self::new(stream, inner, &encoded))
+    }
+
+    fn write_chunk(&mut self, is_last: bool) -> io::Result<()> {
+        self.stream.encrypt_chunk(self.index, is_last, &self.associated_data, &mut self.buffer)?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.index = self.index.checked_add(1).ok_or(StreamError::TooManyChunks)?;
+        Ok(())
+    }
+
+    /// Writes the final chunk and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len().min(self.stream.chunk_size() - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        // A full chunk can't be the last one, the final chunk is always shorter than the chunk size.
+        if self.buffer.len() == self.stream.chunk_size() {
+            self.write_chunk(false)?;
+        }
+        Ok(written)
+    }
+
+    /// Flushes the inner writer, buffered plaintext is only written once a full chunk is available.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `Read` adapter that decrypts and authenticates a chunked stream.
+/// Returns an `InvalidData` error if the stream has been tampered with, truncated or extended.
+pub struct DecryptReader<R: Read> {
+    stream: StreamCipher,
+    inner: R,
+    associated_data: Vec<u8>,
+    buffer: Vec<u8>,
+    position: usize,
+    index: u32,
+    finished: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(stream: StreamCipher, inner: R, associated_data: &[u8]) -> Self {
+        let capacity = stream.encrypted_chunk_size();
+        Self {
+            stream,
+            inner,
+            associated_data: associated_data.to_vec(),
+            buffer: Vec::with_capacity(capacity),
+            position: 0,
+            index: 0,
+            finished: false,
+        }
+    }
+
+    /// Decrypts the payload of an object written by `EncryptWriter::with_header`, `inner` starts right after the header.
+    /// Fails on the first chunk if any header field was changed.
+    pub fn with_header(header: &EncryptedObjectHeader, key: &DerivedKey, inner: R) -> Result<Self, StreamError> {
+        let stream = StreamCipher::from_header(header, key)?;
+        Ok(Self::new(stream, inner, &header.to_bytes()?))
+    }
+
+    /// Reads until the buffer is full or the inner reader is exhausted.
+    fn fill(inner: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match inner.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let encrypted_chunk_size = self.stream.encrypted_chunk_size();
+        self.buffer.resize(encrypted_chunk_size, 0);
+        let read = Self::fill(&mut self.inner, &mut self.buffer)?;
+        self.buffer.truncate(read);
+        self.position = 0;
+
+        let is_last = read < encrypted_chunk_size;
+        if is_last && read < TAG_SIZE {
+            return Err(StreamError::Truncated.into());
+        }
+        self.stream.decrypt_chunk(self.index, is_last, &self.associated_data, &mut self.buffer)?;
+        self.index = self.index.checked_add(1).ok_or(StreamError::TooManyChunks)?;
+        if is_last {
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let read = buf.len().min(self.buffer.len() - self.position);
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use generic_array::GenericArray;
+
+    use crate::bucket::encryption_algorithm::EncryptionAlgorithm;
+    use crate::key::memory::secure_generic_array::SecreteGenericArray;
+
+    use super::*;
+
+    const CHUNK_SIZE: usize = 16;
+
+    fn stream(algorithm: &EncryptionAlgorithm) -> StreamCipher {
+        let key = DerivedKey {
+            key: SecreteGenericArray::new(GenericArray::from_array([3u8; 32])),
+        };
+        let cipher = EncryptionAlgorithmCipher::new(algorithm, &key).unwrap();
+        let nonce_prefix = vec![9u8; StreamCipher::nonce_prefix_size(&cipher)];
+        StreamCipher::new(cipher, &nonce_prefix, CHUNK_SIZE).unwrap()
+    }
+
+    fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(stream(&EncryptionAlgorithm::Aes256), Vec::new(), b"aad");
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptReader::new(stream(&EncryptionAlgorithm::Aes256), encrypted, b"aad");
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_round_trip_chunk_boundaries() {
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, CHUNK_SIZE * 3, CHUNK_SIZE * 3 + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&plaintext);
+            assert_eq!(encrypted.len() as u64, stream(&EncryptionAlgorithm::Aes256).encrypted_len(len as u64));
+            assert_eq!(decrypt(&encrypted).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_every_algorithm() {
+        for algorithm in [
+            EncryptionAlgorithm::Aes256,
+            EncryptionAlgorithm::ChaCha20Poly1305,
+            EncryptionAlgorithm::XChaCha20Poly1305,
+            EncryptionAlgorithm::Aes256GcmSiv,
+        ] {
+            let mut writer = EncryptWriter::new(stream(&algorithm), Vec::new(), b"");
+            writer.write_all(&[1u8; 40]).unwrap();
+            let encrypted = writer.finish().unwrap();
+            let mut plaintext = Vec::new();
+            DecryptReader::new(stream(&algorithm), encrypted.as_slice(), b"").read_to_end(&mut plaintext).unwrap();
+            assert_eq!(plaintext, vec![1u8; 40]);
+        }
+    }
+
+    #[test]
+    fn test_truncation_is_detected() {
+        let encrypted = encrypt(&[7u8; CHUNK_SIZE * 2]);
+        // Drop the empty final chunk, the remaining chunks are all full and none is marked as last.
+        let truncated = &encrypted[..encrypted.len() - TAG_SIZE];
+        assert!(decrypt(truncated).is_err());
+        // Drop a full chunk and the final chunk.
+        let truncated = &encrypted[..CHUNK_SIZE + TAG_SIZE];
+        assert!(decrypt(truncated).is_err());
+    }
+
+    #[test]
+    fn test_reordering_is_detected() {
+        let mut encrypted = encrypt(&[1u8; CHUNK_SIZE * 2 + 3]);
+        let chunk = CHUNK_SIZE + TAG_SIZE;
+        let (first, rest) = encrypted.split_at_mut(chunk);
+        first.swap_with_slice(&mut rest[..chunk]);
+        assert!(decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_ranged_read_decrypts_only_covering_chunks() {
+        let plaintext: Vec<u8> = (0..(CHUNK_SIZE * 4 + 3)).map(|i| i as u8).collect();
+        let encrypted = encrypt(&plaintext);
+        let stream = stream(&EncryptionAlgorithm::Aes256);
+
+        let range = stream.chunk_range(20..40, encrypted.len() as u64).unwrap();
+        assert_eq!((range.first_chunk, range.last_chunk), (1, 2));
+        assert!(!range.includes_final_chunk);
+        let fetched = &encrypted[range.encrypted.start as usize..range.encrypted.end as usize];
+        assert_eq!(stream.decrypt_range(&range, fetched, b"aad").unwrap(), &plaintext[20..40]);
+
+        // A range ending in the final chunk must authenticate it as the last chunk.
+        let range = stream.chunk_range(60..plaintext.len() as u64, encrypted.len() as u64).unwrap();
+        assert!(range.includes_final_chunk);
+        let fetched = &encrypted[range.encrypted.start as usize..range.encrypted.end as usize];
+        assert_eq!(stream.decrypt_range(&range, fetched, b"aad").unwrap(), &plaintext[60..]);
+
+        assert!(matches!(
+            stream.chunk_range(0..1000, encrypted.len() as u64),
+            Err(StreamError::RangeOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_header_is_bound_to_the_stream() {
+        use uuid::Uuid;
+
+        use crate::bucket::encryption_scheme::Role;
+        use crate::key::kdf::KeyDeriveFunction;
+        use crate::key::KeyId;
+
+        let key = DerivedKey {
+            key: SecreteGenericArray::new(GenericArray::from_array([3u8; 32])),
+        };
+        let header = EncryptedObjectHeader {
+            scheme: BucketEncryptionScheme {
+                version: 1,
+                responsible: Role::Client,
+                encryption: EncryptionAlgorithm::Aes256,
+            },
+            key_derive_function: KeyDeriveFunction::Argon2id,
+            nonce: vec![7u8; 7],
+            chunk_size: CHUNK_SIZE as u32,
+            key_id: KeyId(Uuid::nil()),
+        };
+        let plaintext: Vec<u8> = (0..40).collect();
+        let mut writer = EncryptWriter::with_header(&header, &key, Vec::new()).unwrap();
+        writer.write_all(&plaintext).unwrap();
+        let object = writer.finish().unwrap();
+
+        let decrypt = |object: &[u8]| -> io::Result<Vec<u8>> {
+            let (header, payload) = EncryptedObjectHeader::parse(object).map_err(StreamError::from)?;
+            let mut decrypted = Vec::new();
+            DecryptReader::with_header(&header, &key, payload)?.read_to_end(&mut decrypted)?;
+            Ok(decrypted)
+        };
+        assert_eq!(decrypt(&object).unwrap(), plaintext);
+
+        // Scheme version, role, key derive function, a nonce prefix byte and the key id, all still parse.
+        let key_id_start = header.to_bytes().unwrap().len() - 16;
+        for (position, value) in [(8, 2), (9, 0), (11, 1), (13, 8), (key_id_start, 1)] {
+            let mut tampered = object.clone();
+            tampered[position] = value;
+            assert!(decrypt(&tampered).is_err(), "byte {} wasn't authenticated", position);
+        }
+
+        // A forged chunk size is rejected before the reader allocates its chunk buffer.
+        let forged = EncryptedObjectHeader {
+            chunk_size: u32::MAX,
+            ..header
+        };
+        assert!(matches!(
+            DecryptReader::with_header(&forged, &key, &object[..]),
+            Err(StreamError::InvalidChunkSize)
+        ));
+    }
+}
@@ -17,6 +17,7 @@ pub struct DeriveKeyParams {
 
 impl DerivedKey {
     /// Creates a derive key from the master key using KDF function.
+    /// Slow by design, use `key_hierarchy` to derive bucket, object and chunk keys.
     pub fn new(master_key: &MasterKey256, bucket_guid: &BucketGuid, params: &DeriveKeyParams) -> Self{
         let mut secrete = SecreteGenericArray::new(GenericArray::<u8, generic_array::typenum::U32>::default());
         pbkdf2::pbkdf2_hmac::<Sha3_256>(master_key.key.expose_secret(), &bucket_guid.to_bytes(), params
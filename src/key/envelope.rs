@@ -0,0 +1,371 @@
+/*
+* Envelope encryption for bucket keys.
+* Objects are encrypted with a random data key, the data key is stored wrapped (encrypted) by a key-encryption key (KEK)
+* derived from the master key. Rotating the master key only re-wraps the data keys, the objects are left untouched.
+* Rotating a data key retires it, objects encrypted under a retired data key must be re-encrypted before it's removed.
+*/
+use generic_array::GenericArray;
+use rand::{CryptoRng, RngCore};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::bucket::bucket_guid::BucketGuid;
+use crate::encryption::cipher::{AeadCipher, CipherError, XChaCha20Poly1305Cipher};
+
+use super::derived_key::DerivedKey;
+use super::master_key::MasterKey256;
+use super::memory::secure_generic_array::SecreteGenericArray;
+use super::{KeyId, Nonce};
+
+const KEY_ENCRYPTION_KEY_CONTEXT: &str = "bucketdrive.co 2024-11-01 key encryption key v1";
+const WRAP_NONCE_SIZE: usize = 24;
+
+/// Version of the key-encryption key, incremented every time the master key is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct KeyVersion(pub u32);
+
+impl KeyVersion {
+    pub fn next(&self) -> Result<Self, EnvelopeError> {
+        self.0.checked_add(1).map(Self).ok_or(EnvelopeError::KeyVersionExhausted)
+    }
+}
+
+/// Key-encryption key derived from a master key, only ever used to wrap data keys.
+pub struct KeyEncryptionKey {
+    version: KeyVersion,
+    key: DerivedKey,
+}
+
+impl KeyEncryptionKey {
+    pub fn derive(master_key: &MasterKey256, version: KeyVersion) -> Self {
+        let mut key_material = Zeroizing::new(Vec::with_capacity(32 + 4));
+        key_material.extend_from_slice(master_key.key.expose_secret());
+        key_material.extend_from_slice(&version.0.to_be_bytes());
+        let mut key = blake3::derive_key(KEY_ENCRYPTION_KEY_CONTEXT, &key_material);
+        let kek = Self {
+            version,
+            key: DerivedKey {
+                key: SecreteGenericArray::new(GenericArray::from_array(key)),
+            },
+        };
+        key.zeroize();
+        kek
+    }
+
+    pub fn version(&self) -> KeyVersion {
+        self.version
+    }
+}
+
+/// Unwrapped data key, this is the key objects are encrypted with.
+pub struct DataKey {
+    pub key_id: KeyId,
+    pub key: DerivedKey,
+}
+
+impl DataKey {
+    pub fn generate<TCryptoRng>(csprng: &mut TCryptoRng) -> Self
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        let mut key = [0u8; 32];
+        csprng.fill_bytes(&mut key);
+        let data_key = Self {
+            key_id: KeyId::generate(),
+            key: DerivedKey {
+                key: SecreteGenericArray::new(GenericArray::from_array(key)),
+            },
+        };
+        key.zeroize();
+        data_key
+    }
+}
+
+/// Data key encrypted by a key-encryption key, safe to store next to the bucket metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedDataKey {
+    pub key_id: KeyId,
+    pub bucket_guid: BucketGuid,
+    /// Version of the key-encryption key the data key is wrapped with.
+    pub kek_version: KeyVersion,
+    pub nonce: [u8; WRAP_NONCE_SIZE],
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The wrapped key has been tampered with or the key-encryption key is wrong.
+    #[error(transparent)]
+    Cipher(#[from] CipherError),
+    #[error("Data key is wrapped with key version {expected:?}, got key version {actual:?}")]
+    KeyVersionMismatch { expected: KeyVersion, actual: KeyVersion },
+    #[error("Key version can't be incremented past {max}", max = u32::MAX)]
+    KeyVersionExhausted,
+    #[error("Unknown data key {0:?}")]
+    UnknownKeyId(KeyId),
+    #[error("Bucket key ring has no active data key")]
+    NoActiveKey,
+    #[error("Active data key can't be removed")]
+    KeyIsActive,
+}
+
+impl WrappedDataKey {
+    /// Binds the wrapped key to its id, bucket and key version, so it can't be swapped with another wrapped key.
+    fn associated_data(key_id: &KeyId, bucket_guid: &BucketGuid, kek_version: KeyVersion) -> Vec<u8> {
+        let mut associated_data = Vec::with_capacity(16 + BucketGuid::size() + 4);
+        associated_data.extend_from_slice(key_id.0.as_bytes());
+        associated_data.extend_from_slice(&bucket_guid.to_bytes());
+        associated_data.extend_from_slice(&kek_version.0.to_be_bytes());
+        associated_data
+    }
+
+    pub fn wrap<TCryptoRng>(
+        data_key: &DataKey,
+        bucket_guid: &BucketGuid,
+        kek: &KeyEncryptionKey,
+        csprng: &mut TCryptoRng,
+    ) -> Result<Self, EnvelopeError>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        // Random 192-bit nonces never collide in practice, so a KEK doesn't have to track the nonces it used.
+        let mut nonce = [0u8; WRAP_NONCE_SIZE];
+        csprng.fill_bytes(&mut nonce);
+        let associated_data = Self::associated_data(&data_key.key_id, bucket_guid, kek.version);
+        let ciphertext = XChaCha20Poly1305Cipher::new(&kek.key).encrypt(
+            &Nonce::new(GenericArray::from_array(nonce)),
+            data_key.key.key.expose_secret(),
+            &associated_data,
+        )?;
+        Ok(Self {
+            key_id: data_key.key_id,
+            bucket_guid: bucket_guid.clone(),
+            kek_version: kek.version,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn unwrap(&self, kek: &KeyEncryptionKey) -> Result<DataKey, EnvelopeError> {
+        if self.kek_version != kek.version {
+            return Err(EnvelopeError::KeyVersionMismatch { expected: self.kek_version, actual: kek.version });
+        }
+        let associated_data = Self::associated_data(&self.key_id, &self.bucket_guid, self.kek_version);
+        let key = Zeroizing::new(XChaCha20Poly1305Cipher::new(&kek.key).decrypt(
+            &Nonce::new(GenericArray::from_array(self.nonce)),
+            &self.ciphertext,
+            &associated_data,
+        )?);
+        let key = GenericArray::try_from_slice(&key).map_err(|_| CipherError::DecryptionFailed)?;
+        Ok(DataKey {
+            key_id: self.key_id,
+            key: DerivedKey {
+                key: SecreteGenericArray::new(*key),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataKeyState {
+    /// Used to encrypt new objects, there is only one active data key per bucket.
+    Active,
+    /// Only used to decrypt existing objects until they're re-encrypted.
+    Retired,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketKeyRingEntry {
+    pub wrapped_key: WrappedDataKey,
+    pub state: DataKeyState,
+}
+
+/// All data keys of a bucket, every key is wrapped by the same key-encryption key version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketKeyRing {
+    pub bucket_guid: BucketGuid,
+    pub kek_version: KeyVersion,
+    pub keys: Vec<BucketKeyRingEntry>,
+}
+
+impl BucketKeyRing {
+    /// Creates a key ring with a new active data key.
+    pub fn new<TCryptoRng>(bucket_guid: BucketGuid, kek: &KeyEncryptionKey, csprng: &mut TCryptoRng) -> Result<Self, EnvelopeError>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        let wrapped_key = WrappedDataKey::wrap(&DataKey::generate(csprng), &bucket_guid, kek, csprng)?;
+        Ok(Self {
+            bucket_guid,
+            kek_version: kek.version,
+            keys: vec![BucketKeyRingEntry { wrapped_key, state: DataKeyState::Active }],
+        })
+    }
+
+    pub fn active(&self) -> Result<&WrappedDataKey, EnvelopeError> {
+        self.keys
+            .iter()
+            .find(|entry| entry.state == DataKeyState::Active)
+            .map(|entry| &entry.wrapped_key)
+            .ok_or(EnvelopeError::NoActiveKey)
+    }
+
+    /// Unwraps the data key new objects must be encrypted with.
+    pub fn unwrap_active(&self, kek: &KeyEncryptionKey) -> Result<DataKey, EnvelopeError> {
+        self.active()?.unwrap(kek)
+    }
+
+    /// Unwraps the data key with the key id found in an object's header.
+    pub fn unwrap(&self, key_id: &KeyId, kek: &KeyEncryptionKey) -> Result<DataKey, EnvelopeError> {
+        self.keys
+            .iter()
+            .find(|entry| &entry.wrapped_key.key_id == key_id)
+            .ok_or(EnvelopeError::UnknownKeyId(*key_id))?
+            .wrapped_key
+            .unwrap(kek)
+    }
+
+    /// Retires the active data key and replaces it with a new one, returns the id of the new data key.
+    pub fn rotate_data_key<TCryptoRng>(&mut self, kek: &KeyEncryptionKey, csprng: &mut TCryptoRng) -> Result<KeyId, EnvelopeError>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        if kek.version != self.kek_version {
+            return Err(EnvelopeError::KeyVersionMismatch { expected: self.kek_version, actual: kek.version });
+        }
+        let wrapped_key = WrappedDataKey::wrap(&DataKey::generate(csprng), &self.bucket_guid, kek, csprng)?;
+        let key_id = wrapped_key.key_id;
+        for entry in self.keys.iter_mut() {
+            entry.state = DataKeyState::Retired;
+        }
+        self.keys.push(BucketKeyRingEntry { wrapped_key, state: DataKeyState::Active });
+        Ok(key_id)
+    }
+
+    /// Re-wraps every data key under a new key-encryption key, used when the master key is rotated.
+    /// Either every key is re-wrapped or the key ring is left unchanged.
+    pub fn rewrap<TCryptoRng>(
+        &mut self,
+        old_kek: &KeyEncryptionKey,
+        new_kek: &KeyEncryptionKey,
+        csprng: &mut TCryptoRng,
+    ) -> Result<(), EnvelopeError>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        let keys = self
+            .keys
+            .iter()
+            .map(|entry| {
+                let data_key = entry.wrapped_key.unwrap(old_kek)?;
+                Ok(BucketKeyRingEntry {
+                    wrapped_key: WrappedDataKey::wrap(&data_key, &self.bucket_guid, new_kek, csprng)?,
+                    state: entry.state,
+                })
+            })
+            .collect::<Result<Vec<_>, EnvelopeError>>()?;
+        self.keys = keys;
+        self.kek_version = new_kek.version;
+        Ok(())
+    }
+
+    pub fn retired_key_ids(&self) -> impl Iterator<Item = KeyId> + '_ {
+        self.keys
+            .iter()
+            .filter(|entry| entry.state == DataKeyState::Retired)
+            .map(|entry| entry.wrapped_key.key_id)
+    }
+
+    /// Filters objects, given with the key id from their header, down to the ones still encrypted under a retired data key.
+    pub fn objects_under_retired_keys<'a, TObject, TObjects>(&'a self, objects: TObjects) -> impl Iterator<Item = TObject> + 'a
+    where
+        TObjects: IntoIterator<Item = (TObject, KeyId)>,
+        TObjects::IntoIter: 'a,
+    {
+        objects
+            .into_iter()
+            .filter(|(_, key_id)| self.retired_key_ids().any(|retired| &retired == key_id))
+            .map(|(object, _)| object)
+    }
+
+    /// Removes a retired data key, once no object is encrypted under it anymore.
+    pub fn remove_retired(&mut self, key_id: &KeyId) -> Result<(), EnvelopeError> {
+        let index = self
+            .keys
+            .iter()
+            .position(|entry| &entry.wrapped_key.key_id == key_id)
+            .ok_or(EnvelopeError::UnknownKeyId(*key_id))?;
+        if self.keys[index].state == DataKeyState::Active {
+            return Err(EnvelopeError::KeyIsActive);
+        }
+        self.keys.remove(index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn master_key(byte: u8) -> MasterKey256 {
+        MasterKey256 {
+            key: SecreteGenericArray::new(GenericArray::from_array([byte; 32])),
+        }
+    }
+
+    fn key_bytes(data_key: &DataKey) -> Vec<u8> {
+        data_key.key.key.expose_secret().to_vec()
+    }
+
+    #[test]
+    fn test_key_version_next() {
+        assert_eq!(KeyVersion(1).next(), Ok(KeyVersion(2)));
+        assert_eq!(KeyVersion(u32::MAX).next(), Err(EnvelopeError::KeyVersionExhausted));
+    }
+
+    #[test]
+    fn test_master_key_rotation_keeps_data_keys() {
+        let bucket_guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(2));
+        let old_kek = KeyEncryptionKey::derive(&master_key(1), KeyVersion(1));
+        let mut key_ring = BucketKeyRing::new(bucket_guid, &old_kek, &mut OsRng).unwrap();
+        let data_key = key_ring.unwrap_active(&old_kek).unwrap();
+
+        let new_kek = KeyEncryptionKey::derive(&master_key(2), KeyVersion(2));
+        key_ring.rewrap(&old_kek, &new_kek, &mut OsRng).unwrap();
+        assert_eq!(key_ring.kek_version, KeyVersion(2));
+        assert_eq!(key_bytes(&key_ring.unwrap_active(&new_kek).unwrap()), key_bytes(&data_key));
+        assert!(matches!(key_ring.unwrap_active(&old_kek), Err(EnvelopeError::KeyVersionMismatch { .. })));
+
+        // A key with the right version but derived from the wrong master key must fail authentication.
+        let wrong_kek = KeyEncryptionKey::derive(&master_key(3), KeyVersion(2));
+        assert!(matches!(key_ring.unwrap_active(&wrong_kek), Err(EnvelopeError::Cipher(_))));
+        // A failed rewrap leaves the key ring untouched.
+        assert!(key_ring.rewrap(&wrong_kek, &old_kek, &mut OsRng).is_err());
+        assert_eq!(key_ring.kek_version, KeyVersion(2));
+    }
+
+    #[test]
+    fn test_data_key_rotation_lists_objects_under_retired_keys() {
+        let bucket_guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(2));
+        let kek = KeyEncryptionKey::derive(&master_key(1), KeyVersion(1));
+        let mut key_ring = BucketKeyRing::new(bucket_guid, &kek, &mut OsRng).unwrap();
+        let old_key_id = key_ring.active().unwrap().key_id;
+        let new_key_id = key_ring.rotate_data_key(&kek, &mut OsRng).unwrap();
+        assert_eq!(key_ring.active().unwrap().key_id, new_key_id);
+        assert_eq!(key_ring.retired_key_ids().collect::<Vec<_>>(), vec![old_key_id]);
+
+        // Retired keys can still decrypt existing objects.
+        assert!(key_ring.unwrap(&old_key_id, &kek).is_ok());
+
+        let objects = vec![("/old.txt", old_key_id), ("/new.txt", new_key_id)];
+        assert_eq!(key_ring.objects_under_retired_keys(objects).collect::<Vec<_>>(), vec!["/old.txt"]);
+
+        assert_eq!(key_ring.remove_retired(&new_key_id), Err(EnvelopeError::KeyIsActive));
+        key_ring.remove_retired(&old_key_id).unwrap();
+        assert_eq!(key_ring.unwrap(&old_key_id, &kek).err(), Some(EnvelopeError::UnknownKeyId(old_key_id)));
+    }
+}
@@ -1,25 +1,56 @@
-use std::fmt::Display;
+use std::fmt;
+use std::str::FromStr;
 
+use argon2::{Algorithm, Argon2, Params, Version};
+use generic_array::GenericArray;
+use secrecy::ExposeSecretMut;
 use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use sha3::Sha3_256;
 use strum::Display;
 
+use super::master_key::MasterKey256;
+use super::memory::secure_generic_array::SecreteGenericArray;
+
+/// Output length of every KDF, the master key is 256 bits.
+pub const KDF_OUTPUT_LENGTH: usize = 32;
+/// Shortest salt accepted by `derive`, 128 bits.
+pub const KDF_MIN_SALT_LENGTH: usize = 16;
+
+/// Argon2id parameters, defaults follow the OWASP password storage cheat sheet (m=19 MiB, t=2, p=1).
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Argon2IdParams {
+    /// Memory cost in KiB.
+    pub memory_cost: u32,
     pub iterations: u32,
+    pub parallelism: u32,
+    pub output_length: usize,
 }
 
 impl Default for Argon2IdParams {
     fn default() -> Self {
-        todo!()
+        Self {
+            memory_cost: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+            output_length: KDF_OUTPUT_LENGTH,
+        }
     }
 }
 
+/// PBKDF2-HMAC parameters, defaults follow the OWASP password storage cheat sheet for a 256-bit hash (600 000 iterations).
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct PBKDF2Params {
     pub iterations: u32,
+    pub output_length: usize,
 }
 
 impl Default for PBKDF2Params {
     fn default() -> Self {
-        todo!()
+        Self {
+            iterations: 600_000,
+            output_length: KDF_OUTPUT_LENGTH,
+        }
     }
 }
 
@@ -31,4 +62,259 @@ pub enum KeyDeriveFunction {
     Argon2id,
     /// Uses less memory than Argon2Id, consider if you want to use Argon2Id
     PBKDF2
-}
\ No newline at end of file
+}
+
+impl KeyDeriveFunction {
+    pub fn default_params(&self) -> KdfParams {
+        match self {
+            KeyDeriveFunction::Argon2id => KdfParams::Argon2id(Argon2IdParams::default()),
+            KeyDeriveFunction::PBKDF2 => KdfParams::PBKDF2(PBKDF2Params::default()),
+        }
+    }
+}
+
+/// Full parameter set of a KDF, serialized as a PHC string without salt and hash,
+/// e.g. `$argon2id$v=19$m=19456,t=2,p=1,l=32` or `$pbkdf2-sha3-256$i=600000,l=32`.
+#[derive(Clone, Debug, Eq, PartialEq, SerializeDisplay, DeserializeFromStr)]
+pub enum KdfParams {
+    Argon2id(Argon2IdParams),
+    PBKDF2(PBKDF2Params),
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KeyDeriveFunction::default().default_params()
+    }
+}
+
+impl KdfParams {
+    pub fn key_derive_function(&self) -> KeyDeriveFunction {
+        match self {
+            KdfParams::Argon2id(_) => KeyDeriveFunction::Argon2id,
+            KdfParams::PBKDF2(_) => KeyDeriveFunction::PBKDF2,
+        }
+    }
+
+    pub fn output_length(&self) -> usize {
+        match self {
+            KdfParams::Argon2id(params) => params.output_length,
+            KdfParams::PBKDF2(params) => params.output_length,
+        }
+    }
+}
+
+const ARGON2ID_PHC_ID: &str = "argon2id";
+const PBKDF2_PHC_ID: &str = "pbkdf2-sha3-256";
+const ARGON2_VERSION: u32 = 0x13;
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KdfParams::Argon2id(params) => write!(
+                f,
+                "${}$v={}$m={},t={},p={},l={}",
+                ARGON2ID_PHC_ID, ARGON2_VERSION, params.memory_cost, params.iterations, params.parallelism, params.output_length
+            ),
+            KdfParams::PBKDF2(params) => write!(f, "${}$i={},l={}", PBKDF2_PHC_ID, params.iterations, params.output_length),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum KdfParamsParseError {
+    #[error("Expected a PHC string like `$argon2id$v=19$m=19456,t=2,p=1,l=32`")]
+    InvalidFormat,
+    #[error("Unknown key derive function {0}")]
+    UnknownAlgorithm(String),
+    #[error("Unsupported Argon2 version {0}")]
+    UnsupportedVersion(String),
+    #[error("Missing parameter {0}")]
+    MissingParam(&'static str),
+    #[error("Invalid parameter {0}")]
+    InvalidParam(String),
+}
+
+/// Parses the comma separated `key=value` list of a PHC string, every key in `keys` must be present exactly once.
+fn parse_phc_params<const N: usize>(params: &str, keys: [&'static str; N]) -> Result<[u64; N], KdfParamsParseError> {
+    let mut values: [Option<u64>; N] = [None; N];
+    for param in params.split(',') {
+        let (key, value) = param
+            .split_once('=')
+            .ok_or_else(|| KdfParamsParseError::InvalidParam(param.to_string()))?;
+        let index = keys
+            .iter()
+            .position(|expected| *expected == key)
+            .ok_or_else(|| KdfParamsParseError::InvalidParam(param.to_string()))?;
+        if values[index].is_some() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(KdfParamsParseError::InvalidParam(param.to_string()));
+        }
+        values[index] = Some(value.parse().map_err(|_| KdfParamsParseError::InvalidParam(param.to_string()))?);
+    }
+    let mut result = [0; N];
+    for (index, value) in values.into_iter().enumerate() {
+        result[index] = value.ok_or(KdfParamsParseError::MissingParam(keys[index]))?;
+    }
+    Ok(result)
+}
+
+fn to_u32(value: u64, key: &str) -> Result<u32, KdfParamsParseError> {
+    u32::try_from(value).map_err(|_| KdfParamsParseError::InvalidParam(format!("{}={}", key, value)))
+}
+
+impl FromStr for KdfParams {
+    type Err = KdfParamsParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.strip_prefix('$').ok_or(KdfParamsParseError::InvalidFormat)?.split('$');
+        let id = parts.next().ok_or(KdfParamsParseError::InvalidFormat)?;
+        let params = match id {
+            ARGON2ID_PHC_ID => {
+                let version = parts.next().ok_or(KdfParamsParseError::InvalidFormat)?;
+                if version != format!("v={}", ARGON2_VERSION) {
+                    return Err(KdfParamsParseError::UnsupportedVersion(version.to_string()));
+                }
+                let [memory_cost, iterations, parallelism, output_length] =
+                    parse_phc_params(parts.next().ok_or(KdfParamsParseError::InvalidFormat)?, ["m", "t", "p", "l"])?;
+                KdfParams::Argon2id(Argon2IdParams {
+                    memory_cost: to_u32(memory_cost, "m")?,
+                    iterations: to_u32(iterations, "t")?,
+                    parallelism: to_u32(parallelism, "p")?,
+                    output_length: output_length as usize,
+                })
+            }
+            PBKDF2_PHC_ID => {
+                let [iterations, output_length] =
+                    parse_phc_params(parts.next().ok_or(KdfParamsParseError::InvalidFormat)?, ["i", "l"])?;
+                KdfParams::PBKDF2(PBKDF2Params {
+                    iterations: to_u32(iterations, "i")?,
+                    output_length: output_length as usize,
+                })
+            }
+            _ => return Err(KdfParamsParseError::UnknownAlgorithm(id.to_string())),
+        };
+        if parts.next().is_some() {
+            return Err(KdfParamsParseError::InvalidFormat);
+        }
+        Ok(params)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KdfError {
+    #[error("Salt must be at least {KDF_MIN_SALT_LENGTH} bytes, got {0}")]
+    SaltTooShort(usize),
+    #[error("Master key is {KDF_OUTPUT_LENGTH} bytes, got output length {0}")]
+    InvalidOutputLength(usize),
+    #[error("PBKDF2 requires at least one iteration")]
+    InvalidIterations,
+    #[error("Argon2 error: {0}")]
+    Argon2(argon2::Error),
+}
+
+/// Derives the master key from a password, dispatching on the key derive function of `params`.
+pub fn derive(password: impl AsRef<[u8]>, salt: &[u8], params: &KdfParams) -> Result<MasterKey256, KdfError> {
+    if salt.len() < KDF_MIN_SALT_LENGTH {
+        return Err(KdfError::SaltTooShort(salt.len()));
+    }
+    if params.output_length() != KDF_OUTPUT_LENGTH {
+        return Err(KdfError::InvalidOutputLength(params.output_length()));
+    }
+    let mut key = SecreteGenericArray::new(GenericArray::default());
+    match params {
+        KdfParams::Argon2id(params) => {
+            let argon2_params = Params::new(params.memory_cost, params.iterations, params.parallelism, Some(params.output_length))
+                .map_err(KdfError::Argon2)?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+                .hash_password_into(password.as_ref(), salt, key.expose_secret_mut().as_mut_slice())
+                .map_err(KdfError::Argon2)?;
+        }
+        KdfParams::PBKDF2(params) => {
+            if params.iterations == 0 {
+                return Err(KdfError::InvalidIterations);
+            }
+            pbkdf2::pbkdf2_hmac::<Sha3_256>(password.as_ref(), salt, params.iterations, key.expose_secret_mut().as_mut_slice());
+        }
+    }
+    Ok(MasterKey256 { key })
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::*;
+
+    const SALT: &[u8] = b"bucketdrive salt";
+
+    fn fast_argon2id() -> KdfParams {
+        KdfParams::Argon2id(Argon2IdParams {
+            memory_cost: 64,
+            iterations: 1,
+            parallelism: 1,
+            output_length: KDF_OUTPUT_LENGTH,
+        })
+    }
+
+    fn fast_pbkdf2() -> KdfParams {
+        KdfParams::PBKDF2(PBKDF2Params {
+            iterations: 10,
+            output_length: KDF_OUTPUT_LENGTH,
+        })
+    }
+
+    #[test]
+    fn test_default_params_phc_string() {
+        assert_eq!(KdfParams::default().to_string(), "$argon2id$v=19$m=19456,t=2,p=1,l=32");
+        assert_eq!(KeyDeriveFunction::PBKDF2.default_params().to_string(), "$pbkdf2-sha3-256$i=600000,l=32");
+    }
+
+    #[test]
+    fn test_phc_string_round_trip() {
+        for params in [KdfParams::default(), KeyDeriveFunction::PBKDF2.default_params(), fast_argon2id(), fast_pbkdf2()] {
+            assert_eq!(KdfParams::from_str(&params.to_string()), Ok(params.clone()));
+            let encoded = bincode::serialize(&params).unwrap();
+            assert_eq!(encoded, bincode::serialize(&params.to_string()).unwrap());
+            assert_eq!(bincode::deserialize::<KdfParams>(&encoded).unwrap(), params);
+        }
+    }
+
+    #[test]
+    fn test_phc_string_invalid() {
+        assert_eq!(KdfParams::from_str("argon2id$v=19$m=1,t=1,p=1,l=32"), Err(KdfParamsParseError::InvalidFormat));
+        assert_eq!(KdfParams::from_str("$scrypt$ln=15"), Err(KdfParamsParseError::UnknownAlgorithm("scrypt".to_string())));
+        assert_eq!(
+            KdfParams::from_str("$argon2id$v=16$m=1,t=1,p=1,l=32"),
+            Err(KdfParamsParseError::UnsupportedVersion("v=16".to_string()))
+        );
+        assert_eq!(KdfParams::from_str("$argon2id$v=19$m=1,t=1,l=32"), Err(KdfParamsParseError::MissingParam("p")));
+        assert_eq!(
+            KdfParams::from_str("$pbkdf2-sha3-256$i=1,i=2,l=32"),
+            Err(KdfParamsParseError::InvalidParam("i=2".to_string()))
+        );
+        assert_eq!(
+            KdfParams::from_str("$pbkdf2-sha3-256$i=-1,l=32"),
+            Err(KdfParamsParseError::InvalidParam("i=-1".to_string()))
+        );
+        assert_eq!(KdfParams::from_str("$pbkdf2-sha3-256$i=1,l=32$salt"), Err(KdfParamsParseError::InvalidFormat));
+    }
+
+    #[test]
+    fn test_derive_dispatches_on_key_derive_function() {
+        let argon2id = derive("password", SALT, &fast_argon2id()).unwrap();
+        let pbkdf2 = derive("password", SALT, &fast_pbkdf2()).unwrap();
+        assert_eq!(argon2id.key.expose_secret(), derive("password", SALT, &fast_argon2id()).unwrap().key.expose_secret());
+        assert_ne!(argon2id.key.expose_secret(), pbkdf2.key.expose_secret());
+        assert_ne!(pbkdf2.key.expose_secret(), derive("other password", SALT, &fast_pbkdf2()).unwrap().key.expose_secret());
+    }
+
+    #[test]
+    fn test_derive_invalid_params() {
+        assert!(matches!(derive("password", b"short", &fast_pbkdf2()), Err(KdfError::SaltTooShort(5))));
+        let params = KdfParams::PBKDF2(PBKDF2Params { iterations: 10, output_length: 64 });
+        assert!(matches!(derive("password", SALT, &params), Err(KdfError::InvalidOutputLength(64))));
+        let params = KdfParams::PBKDF2(PBKDF2Params { iterations: 0, output_length: KDF_OUTPUT_LENGTH });
+        assert!(matches!(derive("password", SALT, &params), Err(KdfError::InvalidIterations)));
+        let params = KdfParams::Argon2id(Argon2IdParams { parallelism: 0, ..Argon2IdParams::default() });
+        assert!(matches!(derive("password", SALT, &params), Err(KdfError::Argon2(_))));
+    }
+}
@@ -0,0 +1,178 @@
+/*
+* Key hierarchy: master key -> bucket key -> object key -> chunk key.
+* Every level is derived from its parent with BLAKE3 in key derivation mode, with a context string per level for domain separation.
+* Knowing a key only gives access to the keys below it, an object key says nothing about its siblings or its bucket key.
+* The expensive password KDF is only paid once for the master key, everything below is a single BLAKE3 call.
+*/
+use std::ops::Deref;
+
+use generic_array::GenericArray;
+use secrecy::ExposeSecret;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::bucket::bucket_guid::BucketGuid;
+use crate::bucket::bucket_path::BucketAbsolutePath;
+
+use super::derived_key::DerivedKey;
+use super::master_key::MasterKey256;
+use super::memory::secure_generic_array::SecreteGenericArray;
+
+// Context strings must be globally unique and never change, changing them changes every derived key.
+const BUCKET_KEY_CONTEXT: &str = "bucketdrive.co 2024-11-01 bucket key v1";
+const OBJECT_KEY_CONTEXT: &str = "bucketdrive.co 2024-11-01 object key v1";
+const CHUNK_KEY_CONTEXT: &str = "bucketdrive.co 2024-11-01 chunk key v1";
+
+fn derive(context: &str, parent: &[u8], info: &[u8]) -> DerivedKey {
+    let mut key_material = Zeroizing::new(Vec::with_capacity(parent.len() + info.len()));
+    key_material.extend_from_slice(parent);
+    key_material.extend_from_slice(info);
+    let mut key = blake3::derive_key(context, &key_material);
+    let derived_key = DerivedKey {
+        key: SecreteGenericArray::new(GenericArray::from_array(key)),
+    };
+    key.zeroize();
+    derived_key
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyHierarchyError {
+    #[error("The path is in bucket {path_bucket_guid}, but the key is for bucket {key_bucket_guid}")]
+    BucketMismatch {
+        key_bucket_guid: BucketGuid,
+        path_bucket_guid: BucketGuid,
+    },
+}
+
+/// Key for everything stored in a single bucket, derived from the master key and the bucket guid.
+pub struct BucketKey {
+    key: DerivedKey,
+    bucket_guid: BucketGuid,
+}
+
+/// Key for a single object, derived from the bucket key and the absolute path of the object.
+pub struct ObjectKey(DerivedKey);
+
+/// Key for a single chunk of an object, derived from the object key and the chunk index.
+pub struct ChunkKey(DerivedKey);
+
+impl BucketKey {
+    pub fn derive(master_key: &MasterKey256, bucket_guid: &BucketGuid) -> Self {
+        Self {
+            key: derive(BUCKET_KEY_CONTEXT, master_key.key.expose_secret(), &bucket_guid.to_bytes()),
+            bucket_guid: bucket_guid.clone(),
+        }
+    }
+
+    pub fn bucket_guid(&self) -> &BucketGuid {
+        &self.bucket_guid
+    }
+
+    /// Fails when the path is in another bucket, that object is encrypted with the other bucket's key.
+    pub fn derive_object_key(&self, path: &BucketAbsolutePath) -> Result<ObjectKey, KeyHierarchyError> {
+        if path.bucket_guid != self.bucket_guid {
+            return Err(KeyHierarchyError::BucketMismatch {
+                key_bucket_guid: self.bucket_guid.clone(),
+                path_bucket_guid: path.bucket_guid.clone(),
+            });
+        }
+        // The guid is fixed size, so the path is unambiguous without a length prefix.
+        let mut info = Vec::with_capacity(BucketGuid::size() + path.relative_path.as_str().len());
+        info.extend_from_slice(&path.bucket_guid.to_bytes());
+        info.extend_from_slice(path.relative_path.as_str().as_bytes());
+        Ok(ObjectKey(derive(OBJECT_KEY_CONTEXT, self.key.key.expose_secret(), &info)))
+    }
+}
+
+impl ObjectKey {
+    pub fn derive_chunk_key(&self, chunk_index: u64) -> ChunkKey {
+        ChunkKey(derive(CHUNK_KEY_CONTEXT, self.0.key.expose_secret(), &chunk_index.to_be_bytes()))
+    }
+}
+
+macro_rules! impl_deref_derived_key {
+    ($($name:ident),*) => {
+        $(
+            impl Deref for $name {
+                type Target = DerivedKey;
+
+                fn deref(&self) -> &Self::Target {
+                    &self.0
+                }
+            }
+        )*
+    };
+}
+
+impl_deref_derived_key!(ObjectKey, ChunkKey);
+
+impl Deref for BucketKey {
+    type Target = DerivedKey;
+
+    fn deref(&self) -> &Self::Target {
+        &self.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use uuid::Uuid;
+
+    use crate::bucket::bucket_path::BucketRelativePath;
+
+    use super::*;
+
+    fn master_key(byte: u8) -> MasterKey256 {
+        MasterKey256 {
+            key: SecreteGenericArray::new(GenericArray::from_array([byte; 32])),
+        }
+    }
+
+    fn bytes(key: &DerivedKey) -> [u8; 32] {
+        key.key.expose_secret().as_slice().try_into().unwrap()
+    }
+
+    fn path(guid: &BucketGuid, path: &str) -> BucketAbsolutePath {
+        BucketAbsolutePath::new(guid.clone(), BucketRelativePath::from_str(path).unwrap())
+    }
+
+    #[test]
+    fn test_derivation_is_deterministic() {
+        let guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(2));
+        let first = BucketKey::derive(&master_key(1), &guid).derive_object_key(&path(&guid, "/a/b")).unwrap();
+        let second = BucketKey::derive(&master_key(1), &guid).derive_object_key(&path(&guid, "/a/b")).unwrap();
+        assert_eq!(bytes(&first), bytes(&second));
+        assert_eq!(bytes(&first.derive_chunk_key(3)), bytes(&second.derive_chunk_key(3)));
+    }
+
+    #[test]
+    fn test_siblings_are_independent() {
+        let guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(2));
+        let other_guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(3));
+        let bucket_key = BucketKey::derive(&master_key(1), &guid);
+
+        assert_ne!(bytes(&bucket_key), bytes(&BucketKey::derive(&master_key(2), &guid)));
+        assert_ne!(bytes(&bucket_key), bytes(&BucketKey::derive(&master_key(1), &other_guid)));
+
+        let object_key = bucket_key.derive_object_key(&path(&guid, "/a/b")).unwrap();
+        assert_ne!(bytes(&object_key), bytes(&bucket_key.derive_object_key(&path(&guid, "/a/c")).unwrap()));
+        assert_ne!(bytes(&object_key), bytes(&bucket_key));
+        assert_ne!(bytes(&object_key.derive_chunk_key(0)), bytes(&object_key.derive_chunk_key(1)));
+    }
+
+    #[test]
+    fn test_path_in_other_bucket_is_rejected() {
+        let guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(2));
+        let other_guid = BucketGuid::new(Uuid::from_u128(1), Uuid::from_u128(3));
+        let bucket_key = BucketKey::derive(&master_key(1), &guid);
+        assert_eq!(bucket_key.bucket_guid(), &guid);
+        assert_eq!(
+            bucket_key.derive_object_key(&path(&other_guid, "/a/b")).err(),
+            Some(KeyHierarchyError::BucketMismatch {
+                key_bucket_guid: guid,
+                path_bucket_guid: other_guid,
+            })
+        );
+    }
+}
@@ -1,16 +1,25 @@
 use argon2::PasswordHash;
 use generic_array::GenericArray;
 use p256::elliptic_curve::bigint::{Encoding, NonZero};
-use p256::{NistP256, U256};
-use vsss_rs::{combine_shares, shamir::split_secret};
+use p256::elliptic_curve::group::GroupEncoding;
+use p256::{NistP256, ProjectivePoint, U256};
+use serde::{Deserialize, Serialize};
+use vsss_rs::{combine_shares, feldman, shamir::split_secret, FeldmanVerifierSet};
 use rand::{CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use zeroize::Zeroize;
 use crate::key::memory::secure_generic_array::SecreteGenericArray;
 use p256::Scalar;
 use super::master_key::MasterKey256;
+use super::recovery_share::{RecoveryShare, RecoveryShareError};
+use super::MasterKeyErrors;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum VerifiableSecretSharingSchemeAlgorithm {
+    /// Plain Shamir, a corrupted share is only noticed when the reconstructed key is wrong.
     ShamirSecret,
+    /// Feldman VSS, publishes commitments so every share can be verified on its own before combining.
+    Feldman,
 }
 
 /// Parameters for Verifiable Secret Sharing Scheme
@@ -26,7 +35,7 @@ pub struct VerifiableSecretSharingSchemeParams {
 impl Default for VerifiableSecretSharingSchemeParams {
     fn default() -> Self {
         Self {
-            algorithm: VerifiableSecretSharingSchemeAlgorithm::ShamirSecret,
+            algorithm: VerifiableSecretSharingSchemeAlgorithm::Feldman,
             threshold: 2,
             limit: 3,
         }
@@ -46,32 +55,86 @@ pub struct MasterKeyBuilder {
 }
 
 
+// Must be globally unique and never change, changing it changes every deterministic split.
+const DETERMINISTIC_SPLIT_CONTEXT: &str = "bucketdrive.co 2024-11-01 deterministic share split v1";
+
+/// One identifier byte followed by the 32 byte P-256 scalar.
+const SECRETE_SHARE_LENGTH: usize = 1 + 32;
+
 pub struct SecreteShare {
     pub share: Vec<u8>,
 }
 
+/// Feldman commitments to the sharing polynomial, compressed SEC1 P-256 points.
+/// Reveal nothing about the secret, publish them next to the shares.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareCommitments {
+    pub commitments: Vec<Vec<u8>>,
+}
+
+impl ShareCommitments {
+    fn from_verifier(verifier: &[ProjectivePoint]) -> Self {
+        Self {
+            commitments: verifier.iter().map(|point| point.to_bytes().to_vec()).collect(),
+        }
+    }
+
+    fn to_verifier(&self) -> Result<Vec<ProjectivePoint>, MasterKeyErrors> {
+        self.commitments
+            .iter()
+            .map(|commitment| {
+                let mut encoded = <ProjectivePoint as GroupEncoding>::Repr::default();
+                if commitment.len() != encoded.len() {
+                    return Err(MasterKeyErrors::InvalidCommitment);
+                }
+                encoded.copy_from_slice(commitment);
+                Option::<ProjectivePoint>::from(ProjectivePoint::from_bytes(&encoded)).ok_or(MasterKeyErrors::InvalidCommitment)
+            })
+            .collect()
+    }
+}
+
 pub struct Secretes {
     pub master_key: MasterKey256,
     pub secrete_shares: Vec<SecreteShare>,
     pub threshold: usize, // When we reconstruct the secrete we must meet the threshold.
+    /// Only set for `VerifiableSecretSharingSchemeAlgorithm::Feldman`.
+    pub commitments: Option<ShareCommitments>,
+}
+
+impl Secretes {
+    /// Wraps every share with its metadata, so it can be printed and typed back in, see `RecoveryShare`.
+    pub fn recovery_shares(&self, set_id: u16) -> Result<Vec<RecoveryShare>, RecoveryShareError> {
+        self.secrete_shares
+            .iter()
+            .map(|secrete_share| RecoveryShare::new(set_id, self.threshold, secrete_share))
+            .collect()
+    }
 }
 
 impl MasterKeyBuilder {
-    pub fn new(params: VerifiableSecretSharingSchemeParams) -> Self  {
-        if params.validate() {
-            Self {
-                params,
-            }
-        } else {
-            panic!("Invalid secret sharing parameters");
+    pub fn new(params: VerifiableSecretSharingSchemeParams) -> Result<Self, MasterKeyErrors> {
+        if !params.validate() {
+            return Err(MasterKeyErrors::InvalidParams {
+                threshold: params.threshold,
+                limit: params.limit,
+            });
         }
+        Ok(Self { params })
     }
 
-    pub fn combine(&self, secrete_shares: Vec<SecreteShare>) -> MasterKey256 {
-        assert!(
-            secrete_shares.len() >= self.params.threshold,
-            "Not enough shares to meet the threshold"
-        );
+    pub fn combine(&self, secrete_shares: Vec<SecreteShare>) -> Result<MasterKey256, MasterKeyErrors> {
+        if secrete_shares.len() < self.params.threshold {
+            return Err(MasterKeyErrors::NotEnoughShares {
+                required: self.params.threshold,
+                actual: secrete_shares.len(),
+            });
+        }
+
+        // vsss indexes into the share bytes, malformed shares must not reach it.
+        if let Some(index) = secrete_shares.iter().position(|secrete_share| secrete_share.share.len() != SECRETE_SHARE_LENGTH) {
+            return Err(MasterKeyErrors::InvalidShare(index));
+        }
 
         // Convert SecreteShare to Vec<Gf256> for reconstruction
         let shares: Vec<Vec<u8>> = secrete_shares.iter()
@@ -80,52 +143,110 @@ impl MasterKeyBuilder {
             
         // Reconstruct the secret
         let reconstructed_secret: p256::Scalar = combine_shares::<Scalar, u8, Vec<u8>>(&shares)
-        .expect("Failed to combine shares");
+            .map_err(|_| MasterKeyErrors::CombineFailed)?;
         
         // Wrap the reconstructed secret into a `MasterKey256`
-        MasterKey256 {
+        Ok(MasterKey256 {
             key:SecreteGenericArray::new(*GenericArray::from_slice(&reconstructed_secret.to_bytes())),
+        })
+    }
+
+    /// Combines shares typed back in by the user, rejects shares of different splits and too few shares.
+    pub fn combine_recovery_shares(&self, recovery_shares: &[RecoveryShare]) -> Result<MasterKey256, MasterKeyErrors> {
+        self.combine(RecoveryShare::to_secrete_shares(recovery_shares)?)
+    }
+
+    /// Verifies a single share against the published commitments, without needing any other share.
+    pub fn verify_share(&self, secrete_share: &SecreteShare, commitments: &ShareCommitments) -> Result<(), MasterKeyErrors> {
+        let verifier = commitments.to_verifier()?;
+        // The first commitment is the generator, followed by one commitment per coefficient.
+        if verifier.len() != self.params.threshold + 1 {
+            return Err(MasterKeyErrors::InvalidCommitment);
         }
+        if secrete_share.share.len() != SECRETE_SHARE_LENGTH {
+            return Err(MasterKeyErrors::InvalidShare(0));
+        }
+        verifier
+            .verify_share(&secrete_share.share)
+            .map_err(|_| MasterKeyErrors::InvalidShare(0))
     }
 
-    /// Build and return generated master keys
-    pub fn build(&self, kdf: PasswordHash<'_>) -> Secretes {
-        let kdf_output = kdf.hash.expect("Password hash is missing");
-        assert_eq!(
-            kdf_output.len(),
-            32,
-            "Password hash must be 256 bits (32 bytes)"
-        );
+    /// Verifies every share before combining them, fails with the index of the first invalid share.
+    pub fn combine_verified(&self, secrete_shares: Vec<SecreteShare>, commitments: &ShareCommitments) -> Result<MasterKey256, MasterKeyErrors> {
+        for (index, secrete_share) in secrete_shares.iter().enumerate() {
+            self.verify_share(secrete_share, commitments).map_err(|error| match error {
+                MasterKeyErrors::InvalidShare(_) => MasterKeyErrors::InvalidShare(index),
+                error => error,
+            })?;
+        }
+        self.combine(secrete_shares)
+    }
+
+    /// Build and return generated master keys, the polynomial coefficients are drawn from `csprng`.
+    /// Every call produces different shares for the same password hash, use this unless the shares must be reproducible.
+    pub fn build<TCryptoRng>(&self, kdf: PasswordHash<'_>, csprng: &mut TCryptoRng) -> Result<Secretes, MasterKeyErrors>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        self.split(kdf, csprng)
+    }
+
+    /// Build and return generated master keys, the polynomial coefficients are drawn from ChaCha20 seeded by the password hash.
+    ///
+    /// Opt-in only: anyone holding the password hash and `label` can recompute every share, so the shares are only as secret
+    /// as the hash itself. `label` separates splits of the same hash, e.g. the account id and a split counter.
+    pub fn build_deterministic(&self, kdf: PasswordHash<'_>, label: &[u8]) -> Result<Secretes, MasterKeyErrors> {
+        let kdf_output = kdf.hash.ok_or(MasterKeyErrors::MissingHash)?;
+        let mut seed_material = Vec::with_capacity(kdf_output.len() + label.len());
+        seed_material.extend_from_slice(kdf_output.as_bytes());
+        seed_material.extend_from_slice(label);
+        let mut seed = blake3::derive_key(DETERMINISTIC_SPLIT_CONTEXT, &seed_material);
+        seed_material.zeroize();
+        let mut csprng = ChaCha20Rng::from_seed(seed);
+        seed.zeroize();
+        self.split(kdf, &mut csprng)
+    }
+
+    fn split<TCryptoRng>(&self, kdf: PasswordHash<'_>, csprng: &mut TCryptoRng) -> Result<Secretes, MasterKeyErrors>
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        let kdf_output = kdf.hash.ok_or(MasterKeyErrors::MissingHash)?;
+        if kdf_output.len() != 32 {
+            return Err(MasterKeyErrors::InvalidHashLength(kdf_output.len()));
+        }
 
         // Convert password hash to scalar securely
         let modulus = <NistP256 as p256::elliptic_curve::Curve>::ORDER;
         let mut value = U256::from_be_slice(kdf_output.as_bytes());
-        let non_zero_modulus = NonZero::new(modulus).expect("Modulus cannot be zero");
+        let non_zero_modulus = Option::<NonZero<U256>>::from(NonZero::new(modulus)).ok_or(MasterKeyErrors::InvalidScalar)?;
         value = value.rem(&non_zero_modulus); // Reduce modulo p
-        let secret_scalar = p256::NonZeroScalar::from_repr(value.to_be_bytes().into())
-            .expect("Failed to create Scalar from password hash");
-        
-        // Create a deterministic seed from the KDF output
-        let seed = {
-            let mut hasher = blake3::Hasher::new();
-            hasher.update(kdf_output.as_bytes());
-            hasher.update(b"rng_seed"); // Domain separation
-            let hash = hasher.finalize();
-            let mut seed = [0u8; 16];
-            seed.copy_from_slice(&hash.as_bytes()[0..16]);
-            seed
-        };
-        
-        let mut deterministic_rng = DeterministicRng::from_seed(seed);
+        let secret_scalar = Option::<p256::NonZeroScalar>::from(p256::NonZeroScalar::from_repr(value.to_be_bytes().into()))
+            .ok_or(MasterKeyErrors::InvalidScalar)?;
         
         // Split the secret into shares
         //let scalar_bytes = secret_scalar.to_repr().as_ref().to_vec();
-        let shares = split_secret::<Scalar, u8, Vec<u8>>(
-            self.params.threshold,
-            self.params.limit,
-            *secret_scalar.as_ref(),
-            &mut deterministic_rng
-        ).expect("Failed to split secret");
+        let (shares, commitments) = match self.params.algorithm {
+            VerifiableSecretSharingSchemeAlgorithm::ShamirSecret => {
+                let shares = split_secret::<Scalar, u8, Vec<u8>>(
+                    self.params.threshold,
+                    self.params.limit,
+                    *secret_scalar.as_ref(),
+                    csprng
+                ).map_err(|_| MasterKeyErrors::SplitFailed)?;
+                (shares, None)
+            }
+            VerifiableSecretSharingSchemeAlgorithm::Feldman => {
+                let (shares, verifier) = feldman::split_secret::<ProjectivePoint, u8, Vec<u8>>(
+                    self.params.threshold,
+                    self.params.limit,
+                    *secret_scalar.as_ref(),
+                    None,
+                    csprng
+                ).map_err(|_| MasterKeyErrors::SplitFailed)?;
+                (shares, Some(ShareCommitments::from_verifier(&verifier)))
+            }
+        };
 
         // Wrap shares into SecretShare
         let secret_shares: Vec<SecreteShare> = shares
@@ -133,49 +254,156 @@ impl MasterKeyBuilder {
             .map(|share| SecreteShare { share })
             .collect();
 
-        Secretes {
+        // The shares encode the reduced scalar, so hand out exactly what `combine` reconstructs.
+        Ok(Secretes {
             master_key: MasterKey256 { 
-                key: SecreteGenericArray::new(GenericArray::from_slice(kdf_output.as_bytes() ).to_owned() )
+                key: SecreteGenericArray::new(*GenericArray::from_slice(&secret_scalar.to_bytes()))
             },
             secrete_shares: secret_shares,
             threshold: self.params.threshold,
-        }
+            commitments,
+        })
     }
 }
 
 
-pub struct DeterministicRng(rand_xorshift::XorShiftRng);
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use secrecy::ExposeSecret;
 
-impl Default for DeterministicRng {
-    fn default() -> Self {
-        Self::from_seed([7u8; 16])
+    use super::*;
+
+    // 32 byte hash 0x01..=0x20.
+    const PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$YnVja2V0ZHJpdmUgc2FsdA$AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA";
+
+    fn build(algorithm: VerifiableSecretSharingSchemeAlgorithm) -> (MasterKeyBuilder, Secretes) {
+        let builder = MasterKeyBuilder::new(VerifiableSecretSharingSchemeParams {
+            algorithm,
+            threshold: 2,
+            limit: 3,
+        })
+        .unwrap();
+        let secretes = builder.build(PasswordHash::new(PASSWORD_HASH).unwrap(), &mut OsRng).unwrap();
+        (builder, secretes)
     }
-}
 
-impl SeedableRng for DeterministicRng {
-    type Seed = [u8; 16];
+    #[test]
+    fn test_feldman_shares_verify_and_combine() {
+        let (builder, secretes) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let commitments = secretes.commitments.as_ref().unwrap();
+        for share in &secretes.secrete_shares {
+            assert_eq!(builder.verify_share(share, commitments), Ok(()));
+        }
+        let shares = secretes.secrete_shares[1..].iter().map(|share| SecreteShare { share: share.share.clone() }).collect();
+        let master_key = builder.combine_verified(shares, commitments).unwrap();
+        assert_eq!(master_key.key.expose_secret(), secretes.master_key.key.expose_secret());
+    }
 
-    fn from_seed(seed: Self::Seed) -> Self {
-        Self(rand_xorshift::XorShiftRng::from_seed(seed))
+    #[test]
+    fn test_feldman_detects_corrupted_share() {
+        let (builder, secretes) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let commitments = secretes.commitments.as_ref().unwrap();
+        let mut corrupted = secretes.secrete_shares[1].share.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let shares = vec![
+            SecreteShare { share: secretes.secrete_shares[0].share.clone() },
+            SecreteShare { share: corrupted },
+        ];
+        assert_eq!(builder.combine_verified(shares, commitments).err(), Some(MasterKeyErrors::InvalidShare(1)));
+
+        let mut invalid_commitments = commitments.clone();
+        invalid_commitments.commitments[1].truncate(10);
+        assert_eq!(
+            builder.verify_share(&secretes.secrete_shares[0], &invalid_commitments),
+            Err(MasterKeyErrors::InvalidCommitment)
+        );
     }
-}
 
-impl CryptoRng for DeterministicRng {}
+    #[test]
+    fn test_shamir_has_no_commitments() {
+        let (builder, secretes) = build(VerifiableSecretSharingSchemeAlgorithm::ShamirSecret);
+        assert!(secretes.commitments.is_none());
+        let shares = secretes.secrete_shares.into_iter().take(1).collect();
+        assert_eq!(
+            builder.combine(shares).err(),
+            Some(MasterKeyErrors::NotEnoughShares { required: 2, actual: 1 })
+        );
+    }
 
-impl RngCore for DeterministicRng {
-    fn next_u32(&mut self) -> u32 {
-        self.0.next_u32()
+    fn shares(secretes: &Secretes) -> Vec<Vec<u8>> {
+        secretes.secrete_shares.iter().map(|share| share.share.clone()).collect()
     }
 
-    fn next_u64(&mut self) -> u64 {
-        self.0.next_u64()
+    #[test]
+    fn test_random_split_differs_every_time() {
+        let (builder, first) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let second = builder.build(PasswordHash::new(PASSWORD_HASH).unwrap(), &mut OsRng).unwrap();
+        assert_ne!(shares(&first), shares(&second));
+        assert_eq!(first.master_key.key.expose_secret(), second.master_key.key.expose_secret());
     }
 
-    fn fill_bytes(&mut self, dest: &mut [u8]) {
-        self.0.fill_bytes(dest)
+    #[test]
+    fn test_deterministic_split_is_reproducible_per_label() {
+        let (builder, _) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let split = |label: &[u8]| builder.build_deterministic(PasswordHash::new(PASSWORD_HASH).unwrap(), label).unwrap();
+        let first = split(b"account 1");
+        assert_eq!(shares(&first), shares(&split(b"account 1")));
+        assert_eq!(first.commitments, split(b"account 1").commitments);
+        assert_ne!(shares(&first), shares(&split(b"account 2")));
+
+        let shares = first.secrete_shares.into_iter().take(2).collect();
+        let master_key = builder.combine_verified(shares, first.commitments.as_ref().unwrap()).unwrap();
+        assert_eq!(master_key.key.expose_secret(), first.master_key.key.expose_secret());
     }
 
-    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
-        self.0.try_fill_bytes(dest)
+    #[test]
+    fn test_hash_above_order_reconstructs_master_key() {
+        // 32 bytes of 0xff, above the P-256 order, the shared scalar is the hash reduced mod n.
+        const ABOVE_ORDER: &str = "$argon2id$v=19$m=19456,t=2,p=1$YnVja2V0ZHJpdmUgc2FsdA$//////////////////////////////////////////8";
+        let (builder, _) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let secretes = builder.build(PasswordHash::new(ABOVE_ORDER).unwrap(), &mut OsRng).unwrap();
+        assert_ne!(secretes.master_key.key.expose_secret().as_slice(), &[0xff; 32]);
+        let shares = secretes.secrete_shares[..2].iter().map(|share| SecreteShare { share: share.share.clone() }).collect();
+        let master_key = builder.combine_verified(shares, secretes.commitments.as_ref().unwrap()).unwrap();
+        assert_eq!(master_key.key.expose_secret(), secretes.master_key.key.expose_secret());
+    }
+
+    #[test]
+    fn test_malformed_shares_are_rejected() {
+        let (builder, secretes) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let empty = || SecreteShare { share: vec![] };
+        assert_eq!(builder.combine(vec![empty(), empty()]).err(), Some(MasterKeyErrors::InvalidShare(0)));
+        let truncated = SecreteShare { share: secretes.secrete_shares[1].share[..32].to_vec() };
+        let shares = vec![SecreteShare { share: secretes.secrete_shares[0].share.clone() }, truncated];
+        assert_eq!(builder.combine(shares).err(), Some(MasterKeyErrors::InvalidShare(1)));
+        assert_eq!(
+            builder.verify_share(&empty(), secretes.commitments.as_ref().unwrap()),
+            Err(MasterKeyErrors::InvalidShare(0))
+        );
+    }
+
+    #[test]
+    fn test_combine_recovery_shares() {
+        let (builder, secretes) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let (_, other) = build(VerifiableSecretSharingSchemeAlgorithm::Feldman);
+        let recovery_shares = secretes.recovery_shares(1).unwrap();
+        let master_key = builder.combine_recovery_shares(&recovery_shares[..2]).unwrap();
+        assert_eq!(master_key.key.expose_secret(), secretes.master_key.key.expose_secret());
+
+        let mixed = [recovery_shares[0].clone(), other.recovery_shares(2).unwrap()[1].clone()];
+        assert_eq!(
+            builder.combine_recovery_shares(&mixed).err(),
+            Some(MasterKeyErrors::RecoveryShare(RecoveryShareError::MixedSets { expected: 1, actual: 2 }))
+        );
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let params = VerifiableSecretSharingSchemeParams { threshold: 4, ..Default::default() };
+        assert_eq!(
+            MasterKeyBuilder::new(params).err(),
+            Some(MasterKeyErrors::InvalidParams { threshold: 4, limit: 3 })
+        );
     }
 }
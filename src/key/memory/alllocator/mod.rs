@@ -1,105 +1,262 @@
-
 use std::alloc::{Allocator, Layout, AllocError};
 use std::os::raw::c_void;
-use std::ptr;
+use std::ptr::NonNull;
+use std::sync::Mutex;
 use region::{self, Allocation, Protection};
+use zeroize::Zeroize;
 
-use super::CryptoSecureAllocator;
+use super::{CryptoSecureAllocator, MemoryAccess};
 
 /// This is a secure memory allocator that will call mprotect and mlock
-/// on the memory page to prevent swapping and secure access to sensitive data.
-/// Note that if we do access protection then we need to support 
+/// on the memory pages to prevent swapping and secure access to sensitive data.
+///
+/// The pool is split into fixed size slots of whole pages, every slot is surrounded by no-access guard pages:
+/// `guard | slot 0 | guard | slot 1 | guard | ...`
+/// Every allocation gets its own slot, placed at the end of the slot so an overflow runs straight into the guard page.
+/// Freed slots are zeroed before they're reused, and a slot can be made no-access while its secrete isn't used.
 pub struct SecurePoolAllocator {
-    pool: Vec<Allocation>, 
-    used: u64, 
-    total: u64,
+    state: Mutex<PoolState>,
+    page_size: usize,
+    slot_size: usize,
+    slots: usize,
 }
 
-pub struct SecureMemory {
-    pub allocation: Allocation, 
-    pub protection: Protection, 
+struct PoolState {
+    allocation: Allocation,
+    free_slots: Vec<usize>,
 }
 
-impl SecurePoolAllocator {
-    pub fn new() -> Result<Self, AllocError> {
-        // Step 1: Allocate a large block of memory (memory-mapped region)
-            // Memory-mapped region with read-write protection
-        let allocation_size = region::page::size(); 
-        let mmap = region::alloc(allocation_size, Protection::READ_WRITE)
-                .map_err(|_| AllocError)?;
-
+// Safety: the allocation is only ever accessed through the mutex, or through pointers handed out by `allocate`.
+unsafe impl Send for PoolState {}
 
-        // Lock the memory into RAM (prevent swapping)
-        region::lock(mmap.as_ptr::<c_void>(), mmap.len()).map_err(|_| AllocError)?;
+#[derive(thiserror::Error, Debug)]
+pub enum SecurePoolAllocatorError {
+    #[error("Pool must have at least one slot of at least one page")]
+    InvalidSize,
+    #[error(transparent)]
+    Region(#[from] region::Error),
+}
 
-        // Optionally set the protection to be read-only for secure memory handling
-        unsafe {
-            region::protect(mmap.as_ptr::<c_void>(), mmap.len(), Protection::READ);
+impl SecurePoolAllocator {
+    /// Allocates and locks a pool of `slots` slots, each `slot_pages` pages large.
+    /// Locked memory is limited by `RLIMIT_MEMLOCK`, keep the pool small.
+    pub fn new(slots: usize, slot_pages: usize) -> Result<Self, SecurePoolAllocatorError> {
+        if slots == 0 || slot_pages == 0 {
+            return Err(SecurePoolAllocatorError::InvalidSize);
         }
+        let page_size = region::page::size();
+        // `slot_start` indexes up to `slots`, so the whole pool size must be computed without wrapping.
+        let slot_size = slot_pages.checked_mul(page_size).ok_or(SecurePoolAllocatorError::InvalidSize)?;
+        let total = slot_size
+            .checked_add(page_size)
+            .and_then(|stride| stride.checked_mul(slots))
+            .and_then(|slots_size| slots_size.checked_add(page_size))
+            .ok_or(SecurePoolAllocatorError::InvalidSize)?;
 
-        dbg!("SecurePoolAllocator initialized with {} bytes", allocation_size);
-
-        // Step 2: Return the pool allocator
-        Ok(SecurePoolAllocator {
-            pool: mmap,
-            used: 0,
-            total: todo!(),  // Initially, nothing is used
-        })
-    }
-
-    fn allocate_from_pool(&mut self, layout: Layout) -> *mut u8 {
-        // Step 3: Check if there is enough space left in the pool
-        if self.used + layout.size() > region::page::size() {
-            dbg!(
-                "Allocation failed: not enough space. Requested: {} bytes, Available: {} bytes",
-                layout.size(),
-                region::page::size() - self.used
-            );
-            return ptr::null_mut();  // Not enough space in the pool
+        // Everything starts out as guard pages, only the slots are made accessible.
+        let allocation = region::alloc(total, Protection::NONE)?;
+        let pool = Self {
+            state: Mutex::new(PoolState {
+                allocation,
+                // Reversed so slots are handed out from the start of the pool.
+                free_slots: (0..slots).rev().collect(),
+            }),
+            page_size,
+            slot_size,
+            slots,
+        };
+        for slot in 0..slots {
+            let slot_start = pool.slot_start(&pool.state.lock().unwrap(), slot);
+            unsafe {
+                region::protect(slot_start, slot_size, Protection::READ_WRITE)?;
+            }
+            // Lock the memory into RAM (prevent swapping), unlocked again on drop.
+            std::mem::forget(region::lock(slot_start, slot_size)?);
         }
+        Ok(pool)
+    }
 
-        let ptr = unsafe {
-            self.pool.add(self.used)  // Get the next available chunk in the pool
-        };
-        self.used += layout.size();  // Update used memory tracker
+    /// Number of free slots.
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().free_slots.len()
+    }
 
-        dbg!(
-            "Allocated {} bytes from pool. Total used: {} bytes",
-            layout.size(),
-            self.used
-        );
-        ptr
+    /// Largest allocation the pool can serve.
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
     }
 
-    fn protect_memory(protection: region::Protection) {
+    fn slot_start(&self, state: &PoolState, slot: usize) -> *mut u8 {
+        let base = state.allocation.as_ptr::<u8>() as *mut u8;
+        unsafe { base.add(self.page_size + slot * (self.slot_size + self.page_size)) }
+    }
 
+    fn slot_of(&self, state: &PoolState, ptr: NonNull<u8>) -> usize {
+        let base = state.allocation.as_ptr::<u8>() as usize;
+        let slot = (ptr.as_ptr() as usize - base - self.page_size) / (self.slot_size + self.page_size);
+        debug_assert!(slot < self.slots, "Pointer was not allocated by this pool");
+        slot
     }
 }
 
 unsafe impl Allocator for SecurePoolAllocator {
-    fn allocate(&self, layout: Layout) -> Result<std::ptr::NonNull<[u8]>, AllocError> {
-        // Step 4: Try to allocate memory from the pool
-        let ptr = self.allocate_from_pool(layout);
-        if ptr.is_null() {
-            return Err(AllocError);  // Allocation failed, pool is exhausted
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.slot_size || layout.align() > self.page_size {
+            return Err(AllocError);
         }
+        let mut state = self.state.lock().map_err(|_| AllocError)?;
+        let slot = state.free_slots.pop().ok_or(AllocError)?;
+        // Place the allocation as close to the next guard page as the alignment allows.
+        let offset = (self.slot_size - layout.size()) & !(layout.align() - 1);
+        let ptr = unsafe { self.slot_start(&state, slot).add(offset) };
+        Ok(NonNull::slice_from_raw_parts(NonNull::new(ptr).ok_or(AllocError)?, layout.size()))
+    }
 
-        // Return the memory as NonNull
-        Ok(unsafe { std::ptr::NonNull::new_unchecked(ptr) })
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let slot = self.slot_of(&state, ptr);
+        debug_assert!(!state.free_slots.contains(&slot), "Slot {} freed twice", slot);
+        let slot_start = self.slot_start(&state, slot);
+        // The slot might still be protected, zeroing it would fault.
+        // Deallocating can't fail, a slot that can't be zeroed is leaked instead of being handed out again.
+        if let Err(error) = region::protect(slot_start, self.slot_size, Protection::READ_WRITE) {
+            log::error!("Leaking slot {slot} of the secure pool, it can't be unprotected to zero it: {error}");
+            return;
+        }
+        std::slice::from_raw_parts_mut(slot_start, self.slot_size).zeroize();
+        state.free_slots.push(slot);
     }
+}
 
-    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
-        // Deallocation is handled in a simple way here, but you can implement more sophisticated free lists
-        // For now, we do nothing since we're using a pool.
-        dbg!(
-            "Deallocated {} bytes from pool (Note: deallocation not implemented).",
-            layout.size()
-        );
+impl CryptoSecureAllocator for SecurePoolAllocator {
+    unsafe fn set_access(&self, ptr: NonNull<u8>, _layout: Layout, access: MemoryAccess) -> Result<(), AllocError> {
+        let state = self.state.lock().map_err(|_| AllocError)?;
+        let slot_start = self.slot_start(&state, self.slot_of(&state, ptr));
+        let protection = match access {
+            MemoryAccess::NoAccess => Protection::NONE,
+            MemoryAccess::ReadOnly => Protection::READ,
+            MemoryAccess::ReadWrite => Protection::READ_WRITE,
+        };
+        region::protect(slot_start, self.slot_size, protection).map_err(|_| AllocError)
     }
-    
+}
 
+impl Drop for SecurePoolAllocator {
+    fn drop(&mut self) {
+        let state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for slot in 0..self.slots {
+            let slot_start = self.slot_start(&state, slot);
+            unsafe {
+                let _ = region::protect(slot_start, self.slot_size, Protection::READ_WRITE);
+                std::slice::from_raw_parts_mut(slot_start, self.slot_size).zeroize();
+            }
+            let _ = region::unlock(slot_start as *const c_void, self.slot_size);
+        }
+        // Dropping the allocation unmaps the pool.
+    }
 }
 
-impl CryptoSecureAllocator for SecurePoolAllocator {
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use generic_array::GenericArray;
+
+    use super::super::secure_generic_array::SecreteGenericArray;
+    use super::*;
 
-}
\ No newline at end of file
+    fn protection(ptr: *const u8) -> Protection {
+        region::query(ptr).unwrap().protection()
+    }
+
+    #[test]
+    fn test_allocate_and_reuse_zeroed_slot() {
+        let pool = SecurePoolAllocator::new(2, 1).unwrap();
+        let layout = Layout::new::<[u8; 32]>();
+        let ptr = pool.allocate(layout).unwrap().cast::<u8>();
+        assert_eq!(pool.available(), 1);
+        unsafe {
+            ptr.as_ptr().write_bytes(0xAB, 32);
+            pool.deallocate(ptr, layout);
+        }
+        assert_eq!(pool.available(), 2);
+
+        let reused = pool.allocate(layout).unwrap().cast::<u8>();
+        assert_eq!(reused, ptr);
+        assert!(unsafe { std::slice::from_raw_parts(reused.as_ptr(), 32) }.iter().all(|byte| *byte == 0));
+        unsafe { pool.deallocate(reused, layout) };
+    }
+
+    #[test]
+    fn test_allocation_is_next_to_guard_page() {
+        let pool = SecurePoolAllocator::new(1, 1).unwrap();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = pool.allocate(layout).unwrap().cast::<u8>().as_ptr();
+        let end = unsafe { ptr.add(layout.size()) };
+        assert_eq!(end as usize % region::page::size(), 0);
+        assert_eq!(protection(ptr), Protection::READ_WRITE);
+        assert_eq!(protection(end), Protection::NONE);
+        assert_eq!(protection(unsafe { ptr.sub(region::page::size()) }), Protection::NONE);
+        unsafe { pool.deallocate(NonNull::new(ptr).unwrap(), layout) };
+    }
+
+    #[test]
+    fn test_exhausted_and_oversized() {
+        let pool = SecurePoolAllocator::new(1, 1).unwrap();
+        let layout = Layout::new::<u64>();
+        let ptr = pool.allocate(layout).unwrap().cast::<u8>();
+        assert!(pool.allocate(layout).is_err());
+        unsafe { pool.deallocate(ptr, layout) };
+        assert!(pool.allocate(Layout::from_size_align(pool.slot_size() + 1, 1).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_oversized_pool_is_rejected() {
+        for (slots, slot_pages) in [(1, usize::MAX), (usize::MAX, 1), (usize::MAX / 2, 2)] {
+            assert!(matches!(SecurePoolAllocator::new(slots, slot_pages), Err(SecurePoolAllocatorError::InvalidSize)));
+        }
+    }
+
+    #[test]
+    fn test_set_access() {
+        let pool = SecurePoolAllocator::new(1, 1).unwrap();
+        let layout = Layout::new::<u64>();
+        let ptr = pool.allocate(layout).unwrap().cast::<u8>();
+        unsafe {
+            pool.set_access(ptr, layout, MemoryAccess::NoAccess).unwrap();
+            assert_eq!(protection(ptr.as_ptr()), Protection::NONE);
+            pool.set_access(ptr, layout, MemoryAccess::ReadOnly).unwrap();
+            assert_eq!(protection(ptr.as_ptr()), Protection::READ);
+            // Freeing a protected slot unprotects it to zero it.
+            pool.set_access(ptr, layout, MemoryAccess::NoAccess).unwrap();
+            pool.deallocate(ptr, layout);
+        }
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_secrete_generic_array_allocates_in_pool() {
+        let pool = SecurePoolAllocator::new(2, 1).unwrap();
+        {
+            let mut secrete = SecreteGenericArray::new_in(GenericArray::from_array([1u8, 2, 3, 4]), &pool).unwrap();
+            assert_eq!(pool.available(), 1);
+            let ptr = {
+                let mut exposed = secrete.expose_mut().unwrap();
+                exposed[0] = 9;
+                assert_eq!(protection(exposed.as_ptr()), Protection::READ_WRITE);
+                exposed.as_ptr()
+            };
+            // The secrete is only accessible while a guard lives.
+            assert_eq!(protection(ptr), Protection::NONE);
+            {
+                let first = secrete.expose().unwrap();
+                let second = secrete.expose().unwrap();
+                assert_eq!(first.as_slice(), &[9, 2, 3, 4]);
+                assert_eq!(protection(ptr), Protection::READ);
+                drop(first);
+                assert_eq!(protection(ptr), Protection::READ);
+                assert_eq!(second.as_slice(), &[9, 2, 3, 4]);
+            }
+            assert_eq!(protection(ptr), Protection::NONE);
+        }
+        assert_eq!(pool.available(), 2);
+    }
+}
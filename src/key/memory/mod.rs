@@ -1,8 +1,39 @@
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::ptr::NonNull;
+
 pub mod secure_generic_array;
-//pub mod alllocator;
+#[cfg(feature = "lock_memory")]
+pub mod alllocator;
 
+/// Access to memory holding secretes, allocators that can't protect memory ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    NoAccess,
+    ReadOnly,
+    ReadWrite,
+}
 
 // Marker trait for all secure allocators, that can be used for secretes.
-pub trait CryptoSecureAllocator {
+// The global allocator is allowed too, it just can't protect the memory.
+pub trait CryptoSecureAllocator: Allocator {
+    /// Changes the access of the memory behind `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` must be currently allocated by this allocator with `layout`,
+    /// and no reference to the memory may be used in a way the new access forbids.
+    unsafe fn set_access(&self, ptr: NonNull<u8>, layout: Layout, access: MemoryAccess) -> Result<(), AllocError> {
+        let _ = (ptr, layout, access);
+        Ok(())
+    }
+}
+
+impl CryptoSecureAllocator for Global {}
 
+impl<TAllocator> CryptoSecureAllocator for &TAllocator
+where
+    TAllocator: CryptoSecureAllocator + ?Sized,
+{
+    unsafe fn set_access(&self, ptr: NonNull<u8>, layout: Layout, access: MemoryAccess) -> Result<(), AllocError> {
+        (**self).set_access(ptr, layout, access)
+    }
 }
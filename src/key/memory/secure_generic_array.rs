@@ -1,19 +1,29 @@
-use std::{alloc::{AllocError, Allocator, Global}, u8};
+use std::alloc::{AllocError, Global, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::Mutex;
 use generic_array::{ArrayLength, GenericArray};
 use rand::{CryptoRng, RngCore};
-use secrecy::{ExposeSecret, ExposeSecretMut, SecretBox};
+use secrecy::{ExposeSecret, ExposeSecretMut};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use super::CryptoSecureAllocator;
+use super::{CryptoSecureAllocator, MemoryAccess};
 
 /// A secure wrapper around a generic array of secrets.
-/// Support custom allocator for Memory protected against swapping and unauthorized access, if nesassary?
-/// When running the application we will just disable swap for the linux system probably, so there is no way for the encryption key to leek in that sense. 
-#[derive(ZeroizeOnDrop)]
-pub struct SecreteGenericArray<T, TLength: ArrayLength>(SecretBox<GenericArray<T, TLength>>)
+/// The array lives in memory from `TAllocator`, use `SecurePoolAllocator` (`lock_memory` feature) for memory
+/// that is locked against swapping and surrounded by guard pages, the global allocator gives no memory specific protection.
+/// The array is zeroed on drop.
+///
+/// The memory is no-access except while an `ExposeGuard` or `ExposeMutGuard` lives, when the allocator supports it.
+/// `ExposeSecret` hands out unguarded references, so it's only implemented for the global allocator.
+pub struct SecreteGenericArray<T, TLength: ArrayLength, TAllocator: CryptoSecureAllocator = Global>
 where
     T: Zeroize,
-    GenericArray<T, TLength>: Zeroize;
+{
+    inner: Box<GenericArray<T, TLength>, TAllocator>,
+    /// Number of live `ExposeGuard`s, the memory is re-protected when the last one is dropped.
+    readers: Mutex<usize>,
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum SecreteGenericArrayError {
@@ -28,31 +38,41 @@ where
 {
     /// Creates a new `SecureGenericArray` with global allocator, no memory specific protection.
     pub fn new(inner: GenericArray<T, TLength>) -> Self {
-        Self::new_in(inner, Global).unwrap()
+        Self {
+            inner: Box::new(inner),
+            readers: Mutex::new(0),
+        }
     }
+}
 
-    /// Creates a new `SecureGenericArray` using a custom allocator.
-    pub fn new_in<TAllocator>(
+impl<T, TLength, TAllocator> SecreteGenericArray<T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    /// Creates a new `SecureGenericArray` in memory from `allocator`.
+    /// Pass a reference to share one allocator between many secretes.
+    pub fn new_in(
         inner: GenericArray<T, TLength>,
-        allocator:TAllocator,
-    ) -> Result<Self, SecreteGenericArrayError>
-    where
-        TAllocator: Allocator + ?CryptoSecureAllocator, // Might be crpyot secure allocator, pls mark it if it's required but also need support for global allocator.
-    {
-    // Wrap the allocated memory in a `SecretBox`.
-        let boxed = Box::new_in(inner, Global);   // TODO: Add support for the actual allocator, currently secrecy only support global allocator. Switch this out for TAllocator.
-        Ok(Self(SecretBox::new(boxed)))
+        allocator: TAllocator,
+    ) -> Result<Self, SecreteGenericArrayError> {
+        let secrete = Self {
+            inner: Box::try_new_in(inner, allocator)?,
+            readers: Mutex::new(0),
+        };
+        secrete.set_access(MemoryAccess::NoAccess)?;
+        Ok(secrete)
     }
 
     /// Generates a new `SecureGenericArray` filled with random bytes.
     /// Use global if you want to use the global allocator.
-    pub fn generate_with_rng<TCryptoRng, TAllocator>(
+    pub fn generate_with_rng<TCryptoRng>(
         rng: &mut TCryptoRng,
-        allocator: &TAllocator
+        allocator: TAllocator
     ) -> Result<Self, SecreteGenericArrayError>
     where
         TCryptoRng: RngCore + CryptoRng,
-        TAllocator: Allocator,
         T: From<u8>, // Add this bound to allow conversion from u8 to T
         T: Default,
     {
@@ -60,36 +80,174 @@ where
         // Create a temporary buffer for random bytes
         let mut bytes = vec![0u8; array.len()];
         rng.fill_bytes(&mut bytes);
-        
+
         // Convert each byte to type T
         for (i, byte) in bytes.iter().enumerate() {
             array[i] = T::from(*byte);
         }
-        
+        bytes.zeroize();
+
         Self::new_in(array, allocator)
     }
+
+    /// Read access to the secrete while the guard lives.
+    pub fn expose(&self) -> Result<ExposeGuard<'_, T, TLength, TAllocator>, SecreteGenericArrayError> {
+        let mut readers = self.readers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *readers == 0 {
+            self.set_access(MemoryAccess::ReadOnly)?;
+        }
+        *readers += 1;
+        Ok(ExposeGuard { secrete: self })
+    }
+
+    /// Read-write access to the secrete while the guard lives.
+    pub fn expose_mut(&mut self) -> Result<ExposeMutGuard<'_, T, TLength, TAllocator>, SecreteGenericArrayError> {
+        debug_assert_eq!(*self.readers.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()), 0);
+        self.set_access(MemoryAccess::ReadWrite)?;
+        Ok(ExposeMutGuard { secrete: self })
+    }
+
+    fn set_access(&self, access: MemoryAccess) -> Result<(), AllocError> {
+        unsafe { Box::allocator(&self.inner).set_access(self.ptr(), Self::layout(), access) }
+    }
+
+    fn ptr(&self) -> NonNull<u8> {
+        NonNull::from(&*self.inner).cast()
+    }
+
+    fn layout() -> Layout {
+        Layout::new::<GenericArray<T, TLength>>()
+    }
+}
+
+/// Read access to a `SecreteGenericArray`, the memory is re-protected when the last guard is dropped.
+pub struct ExposeGuard<'a, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    secrete: &'a SecreteGenericArray<T, TLength, TAllocator>,
+}
+
+impl<T, TLength, TAllocator> Deref for ExposeGuard<'_, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    type Target = GenericArray<T, TLength>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.secrete.inner
+    }
+}
+
+impl<T, TLength, TAllocator> Drop for ExposeGuard<'_, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    fn drop(&mut self) {
+        let mut readers = self.secrete.readers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        debug_assert!(*readers > 0, "Expose guard dropped more often than created");
+        *readers -= 1;
+        if *readers == 0 {
+            let protected = self.secrete.set_access(MemoryAccess::NoAccess);
+            debug_assert!(protected.is_ok(), "Failed to re-protect a secrete");
+        }
+    }
 }
 
+/// Read-write access to a `SecreteGenericArray`, the memory is re-protected when the guard is dropped.
+pub struct ExposeMutGuard<'a, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    secrete: &'a mut SecreteGenericArray<T, TLength, TAllocator>,
+}
+
+impl<T, TLength, TAllocator> Deref for ExposeMutGuard<'_, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    type Target = GenericArray<T, TLength>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.secrete.inner
+    }
+}
+
+impl<T, TLength, TAllocator> DerefMut for ExposeMutGuard<'_, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.secrete.inner
+    }
+}
+
+impl<T, TLength, TAllocator> Drop for ExposeMutGuard<'_, T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    fn drop(&mut self) {
+        let protected = self.secrete.set_access(MemoryAccess::NoAccess);
+        debug_assert!(protected.is_ok(), "Failed to re-protect a secrete");
+    }
+}
 
+impl<T, TLength, TAllocator> Drop for SecreteGenericArray<T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+    fn drop(&mut self) {
+        debug_assert_eq!(*self.readers.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()), 0);
+        // The memory is protected, it has to be writable to be zeroed.
+        let _ = self.set_access(MemoryAccess::ReadWrite);
+        self.inner.zeroize();
+    }
+}
 
-impl<T, TLength> From<GenericArray<T, TLength>> for SecreteGenericArray<T, TLength> 
+impl<T, TLength, TAllocator> ZeroizeOnDrop for SecreteGenericArray<T, TLength, TAllocator>
+where
+    T: Zeroize,
+    TLength: ArrayLength,
+    TAllocator: CryptoSecureAllocator,
+{
+}
+
+
+impl<T, TLength> From<GenericArray<T, TLength>> for SecreteGenericArray<T, TLength>
 where
 T: Zeroize,
 TLength: ArrayLength, {
     fn from(value: GenericArray<T, TLength>) -> Self {
-       Self (SecretBox::new(Box::new(value)))
+       Self::new(value)
     }
 }
 
 
 /// Implement `ExposeSecret` for read-only access to the secret.
+/// Global memory is never protected, use `expose` for memory from a protecting allocator.
 impl<T, TLength> ExposeSecret<GenericArray<T, TLength>> for SecreteGenericArray<T, TLength>
 where
     T: Zeroize,
     TLength: ArrayLength,
 {
     fn expose_secret(&self) -> &GenericArray<T, TLength> {
-        self.0.expose_secret()
+        &self.inner
     }
 }
 
@@ -100,6 +258,23 @@ where
     TLength: ArrayLength,
 {
     fn expose_secret_mut(&mut self) -> &mut GenericArray<T, TLength> {
-        self.0.expose_secret_mut()
+        &mut self.inner
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expose_guards() {
+        let mut secrete = SecreteGenericArray::new(GenericArray::from_array([1u8, 2, 3]));
+        {
+            let first = secrete.expose().unwrap();
+            let second = secrete.expose().unwrap();
+            assert_eq!(first.as_slice(), second.as_slice());
+        }
+        secrete.expose_mut().unwrap()[0] = 7;
+        assert_eq!(secrete.expose_secret().as_slice(), &[7, 2, 3]);
+    }
+}
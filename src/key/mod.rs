@@ -1,17 +1,33 @@
 use generic_array::typenum::IsGreaterOrEqual;
 use generic_array::{ArrayLength, GenericArray};
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use uuid::Uuid;
 use zeroize::Zeroize;
 
 pub mod derived_key;
 pub mod master_key;
 pub mod nonce_generator;
+pub mod nonce_reservation;
 pub mod master_key_builder;
 pub mod memory;
 pub mod kdf;
+pub mod key_hierarchy;
+pub mod envelope;
+pub mod recovery_share;
 
 pub struct Nonce<TNonceLength: ArrayLength>(GenericArray<u8, TNonceLength>);
 
+impl<TNonceLength: ArrayLength> Nonce<TNonceLength> {
+    pub fn new(nonce: GenericArray<u8, TNonceLength>) -> Self {
+        Self(nonce)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
 /// A trait for generating nonce's of a specified length,
 /// This trait is for non-deterministic nonce generation only.
 pub trait NonceGenerator<TNonceLength>
@@ -39,9 +55,38 @@ pub trait UniqueNonce {
 
 }
 
+/// Identifies the key an object was encrypted with, without revealing anything about the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyId(pub Uuid);
+
+impl KeyId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
 
-#[derive(thiserror::Error, Debug)]
-pub enum MasterKeyErrors {
 
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MasterKeyErrors {
+    #[error("Invalid secret sharing parameters, threshold {threshold} of limit {limit}")]
+    InvalidParams { threshold: usize, limit: usize },
+    #[error("Not enough shares, {required} required but got {actual}")]
+    NotEnoughShares { required: usize, actual: usize },
+    #[error("Password hash is missing the hash output")]
+    MissingHash,
+    #[error("Password hash must be 256 bits (32 bytes), got {0} bytes")]
+    InvalidHashLength(usize),
+    #[error("Password hash is not a valid scalar")]
+    InvalidScalar,
+    #[error("Failed to split the secret")]
+    SplitFailed,
+    #[error("Failed to combine the shares")]
+    CombineFailed,
+    #[error("Share {0} does not match the commitments")]
+    InvalidShare(usize),
+    #[error("Share commitments are malformed")]
+    InvalidCommitment,
+    #[error(transparent)]
+    RecoveryShare(#[from] recovery_share::RecoveryShareError),
 }
 
@@ -1,12 +1,23 @@
-use generic_array::{typenum::IsGreaterOrEqual, ArrayLength, GenericArray};
+use std::marker::PhantomData;
+
+use generic_array::typenum::{IsGreaterOrEqual, U12, U8};
+use generic_array::GenericArray;
 use zeroize::Zeroize;
 
 use crate::bucket::bucket_guid::BucketGuid;
+use crate::encryption::cipher::RandomNonceSafe;
 
 use super::{Nonce, NonceGenerator};
 
-/// 96-bit random sequential generator.
-/// Generates a random cryptographically secure nonce.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum NonceGeneratorError {
+    #[error("Nonce counter is exhausted, the key must be rotated")]
+    Exhausted,
+}
+
+/// 96-bit random sequential generator, 4 byte counter followed by 8 random bytes.
+/// Only for 96-bit nonces, for a single writer per key, concurrent writers should use `RandomNonceGenerator`.
+/// Fails instead of wrapping the counter, the last counter value `u32::MAX` is never used.
 #[derive(Zeroize)]
 pub struct RandomSequential92BitNonceGenerator<TCryptoRng> where
 TCryptoRng: rand::CryptoRng,
@@ -16,25 +27,59 @@ TCryptoRng: rand::RngCore
     pub csprng: TCryptoRng,
 }
 
-impl<TCryptoRng, TNonceLength> NonceGenerator<TNonceLength> for RandomSequential92BitNonceGenerator<TCryptoRng> where 
-TCryptoRng: rand::CryptoRng, 
+impl<TCryptoRng> RandomSequential92BitNonceGenerator<TCryptoRng> where
+TCryptoRng: rand::CryptoRng,
 TCryptoRng: rand::RngCore,
-TNonceLength: ArrayLength,
-TNonceLength: IsGreaterOrEqual<generic_array::typenum::U8>
 {
-    fn next(&mut self) -> Nonce<TNonceLength> {
+    pub fn try_next(&mut self) -> Result<Nonce<U12>, NonceGeneratorError> {
+        let next_counter = self.counter.checked_add(1).ok_or(NonceGeneratorError::Exhausted)?;
         let mut nonce = GenericArray::default();
         // Fill fist 4 bytes with the counter
         nonce[0..4].copy_from_slice(&self.counter.to_be_bytes());
         // Fill the last 8 bytes with the random u64
         nonce[4..12].copy_from_slice(&self.csprng.next_u64().to_be_bytes());
-        self.counter += 1;
-        Nonce (nonce)
+        self.counter = next_counter;
+        Ok(Nonce (nonce))
     }
 }
 
+/// Fully random nonces, the safe default for many clients writing with the same key without coordinating.
+/// Only implemented for ciphers where random nonces are safe (`RandomNonceSafe`), XChaCha20-Poly1305 and AES-256-GCM-SIV,
+/// so using it with AES-GCM is a compile error.
+pub struct RandomNonceGenerator<TCipher, TCryptoRng> where
+TCryptoRng: rand::CryptoRng,
+TCryptoRng: rand::RngCore
+{
+    pub csprng: TCryptoRng,
+    cipher: PhantomData<TCipher>,
+}
 
+impl<TCipher, TCryptoRng> RandomNonceGenerator<TCipher, TCryptoRng> where
+TCipher: RandomNonceSafe,
+TCryptoRng: rand::CryptoRng,
+TCryptoRng: rand::RngCore
+{
+    pub fn new(csprng: TCryptoRng) -> Self {
+        Self { csprng, cipher: PhantomData }
+    }
+}
+
+impl<TCipher, TCryptoRng> NonceGenerator<TCipher::NonceLength> for RandomNonceGenerator<TCipher, TCryptoRng> where
+TCipher: RandomNonceSafe,
+TCipher::NonceLength: IsGreaterOrEqual<U8>,
+TCryptoRng: rand::CryptoRng,
+TCryptoRng: rand::RngCore
+{
+    fn next(&mut self) -> Nonce<TCipher::NonceLength> {
+        let mut nonce = GenericArray::default();
+        self.csprng.fill_bytes(&mut nonce);
+        Nonce(nonce)
+    }
+}
 
+/// 96-bit nonces counting up from a seed derived from the bucket.
+/// The counter isn't persisted, a restarted writer repeats nonces, only use it with AES-256-GCM-SIV or for a single process lifetime.
+/// Use `ReservedSequentialNonceGenerator` for counters that survive restarts.
 pub struct DeterministicHashSequentialNonceGenerator {
     pub seed: u128,
 }
@@ -48,19 +93,49 @@ impl DeterministicHashSequentialNonceGenerator {
     }
 }
 
-impl<TNonceLength> NonceGenerator<TNonceLength> for DeterministicHashSequentialNonceGenerator 
-where 
-TNonceLength: ArrayLength,
-TNonceLength: IsGreaterOrEqual<generic_array::typenum::U8> {
-    
-    fn next(&mut self) -> Nonce<TNonceLength> {
+impl NonceGenerator<U12> for DeterministicHashSequentialNonceGenerator {
+    fn next(&mut self) -> Nonce<U12> {
         let mut nonce = GenericArray::default();
-        // Use first 8 bytes of seed for first part of nonce
-        nonce[0..8].copy_from_slice(&(self.seed as u64).to_be_bytes());
-        // Use second 8 bytes of seed for second part
-        nonce[8..16].copy_from_slice(&((self.seed >> 64) as u64).to_be_bytes());
+        // The low 96 bits of the seed, so the counter changes every nonce.
+        nonce.copy_from_slice(&self.seed.to_be_bytes()[4..16]);
         // Increment seed for next nonce
         self.seed = self.seed.wrapping_add(1);
         Nonce(nonce)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::rngs::OsRng;
+
+    use crate::encryption::cipher::{Aes256GcmSivCipher, XChaCha20Poly1305Cipher};
+
+    use super::*;
+
+    #[test]
+    fn test_random_nonce_generator_lengths() {
+        let mut xchacha = RandomNonceGenerator::<XChaCha20Poly1305Cipher, _>::new(OsRng);
+        let nonces: HashSet<Vec<u8>> = (0..64).map(|_| xchacha.next().as_slice().to_vec()).collect();
+        assert_eq!(nonces.len(), 64);
+        assert!(nonces.iter().all(|nonce| nonce.len() == 24));
+
+        let mut siv = RandomNonceGenerator::<Aes256GcmSivCipher, _>::new(OsRng);
+        assert_eq!(siv.next().as_slice().len(), 12);
+    }
+
+    #[test]
+    fn test_sequential_generators_write_96_bits() {
+        let mut random = RandomSequential92BitNonceGenerator { counter: u32::MAX - 1, csprng: OsRng };
+        let last = random.try_next().unwrap();
+        assert_eq!(&last.as_slice()[0..4], &(u32::MAX - 1).to_be_bytes());
+        // Never wraps around to counter values that were already used.
+        assert_eq!(random.try_next().err(), Some(NonceGeneratorError::Exhausted));
+        assert_eq!(random.try_next().err(), Some(NonceGeneratorError::Exhausted));
+
+        let mut deterministic = DeterministicHashSequentialNonceGenerator { seed: (1 << 96) | 41 };
+        assert_eq!(deterministic.next().as_slice(), &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 41]);
+        assert_eq!(deterministic.next().as_slice(), &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 42]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use generic_array::typenum::U12;
+use generic_array::GenericArray;
+
+use super::Nonce;
+
+/// Number of nonces reserved per checkpoint.
+pub const NONCE_RESERVATION_BLOCK_SIZE: u64 = 1 << 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum NonceReservationError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Stored nonce counter is corrupted")]
+    Corrupted,
+    #[error("Nonce counter is exhausted, rotate the key")]
+    Exhausted,
+    #[error("Reservation block size must be at least 1")]
+    InvalidBlockSize,
+}
+
+/// Durable storage for the nonce counter high-water mark, the first counter value that hasn't been reserved.
+pub trait NonceCounterStore {
+    /// Returns the stored high-water mark, `None` if nothing has been reserved yet.
+    fn load(&self) -> Result<Option<u64>, NonceReservationError>;
+
+    /// Durably stores the high-water mark, it must have reached disk when this returns.
+    fn store(&mut self, high_water_mark: u64) -> Result<(), NonceReservationError>;
+}
+
+/// Stores the high-water mark as 8 big-endian bytes in a file.
+/// Writes go to a temporary file that is synced and renamed over the old one, so a crash leaves either the old or the new mark.
+pub struct FileNonceCounterStore {
+    path: PathBuf,
+}
+
+impl FileNonceCounterStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+impl NonceCounterStore for FileNonceCounterStore {
+    fn load(&self) -> Result<Option<u64>, NonceReservationError> {
+        match fs::read(&self.path) {
+            Ok(bytes) => {
+                let bytes: [u8; 8] = bytes.try_into().map_err(|_| NonceReservationError::Corrupted)?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn store(&mut self, high_water_mark: u64) -> Result<(), NonceReservationError> {
+        let temporary_path = self.temporary_path();
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&high_water_mark.to_be_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+        // Sync the directory so the rename itself survives a crash.
+        #[cfg(unix)]
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// 96-bit counter nonces that survive restarts, 4 byte writer prefix followed by a 8 byte counter.
+///
+/// Counter values are reserved in blocks, the end of a block is stored before any nonce from it is handed out.
+/// A restarted generator continues from the stored mark, skipping what's left of the block it crashed in,
+/// so a nonce is never handed out twice, as long as only one generator uses the store at a time.
+pub struct ReservedSequentialNonceGenerator<TStore: NonceCounterStore> {
+    store: TStore,
+    prefix: [u8; 4],
+    counter: u64,
+    reserved_until: u64,
+    block_size: u64,
+}
+
+impl<TStore: NonceCounterStore> ReservedSequentialNonceGenerator<TStore> {
+    /// Resumes from the high-water mark in `store`, with blocks of `NONCE_RESERVATION_BLOCK_SIZE`.
+    pub fn new(store: TStore, prefix: [u8; 4]) -> Result<Self, NonceReservationError> {
+        Self::with_block_size(store, prefix, NONCE_RESERVATION_BLOCK_SIZE)
+    }
+
+    /// Smaller blocks write more often but waste fewer nonces on restart.
+    pub fn with_block_size(store: TStore, prefix: [u8; 4], block_size: u64) -> Result<Self, NonceReservationError> {
+        if block_size == 0 {
+            return Err(NonceReservationError::InvalidBlockSize);
+        }
+        let high_water_mark = store.load()?.unwrap_or(0);
+        Ok(Self {
+            store,
+            prefix,
+            counter: high_water_mark,
+            // Nothing is reserved by this instance yet, the first nonce reserves a block.
+            reserved_until: high_water_mark,
+            block_size,
+        })
+    }
+
+    /// Generates the next nonce, reserving a new block first when the current one is used up.
+    pub fn try_next(&mut self) -> Result<Nonce<U12>, NonceReservationError> {
+        if self.counter == self.reserved_until {
+            let reserved_until = self
+                .counter
+                .checked_add(self.block_size)
+                .ok_or(NonceReservationError::Exhausted)?;
+            self.store.store(reserved_until)?;
+            self.reserved_until = reserved_until;
+        }
+        let mut nonce = GenericArray::default();
+        nonce[0..4].copy_from_slice(&self.prefix);
+        nonce[4..12].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Ok(Nonce(nonce))
+    }
+
+    /// Counter value of the next nonce.
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("nonce-counter-{}", Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_resumes_past_reserved_block_after_kill() {
+        let file = TempFile::new();
+        let mut seen = HashSet::new();
+
+        let mut generator = ReservedSequentialNonceGenerator::with_block_size(FileNonceCounterStore::new(&file.0), [1, 2, 3, 4], 8).unwrap();
+        for _ in 0..10 {
+            assert!(seen.insert(generator.try_next().unwrap().as_slice().to_vec()));
+        }
+        // Killed in the middle of the second block, nothing runs on shutdown.
+        std::mem::forget(generator);
+
+        let mut restarted = ReservedSequentialNonceGenerator::with_block_size(FileNonceCounterStore::new(&file.0), [1, 2, 3, 4], 8).unwrap();
+        assert_eq!(restarted.counter(), 16);
+        for _ in 0..20 {
+            assert!(seen.insert(restarted.try_next().unwrap().as_slice().to_vec()));
+        }
+        assert_eq!(FileNonceCounterStore::new(&file.0).load().unwrap(), Some(40));
+    }
+
+    #[test]
+    fn test_nonce_layout() {
+        let file = TempFile::new();
+        let mut generator = ReservedSequentialNonceGenerator::new(FileNonceCounterStore::new(&file.0), [9, 9, 9, 9]).unwrap();
+        assert_eq!(generator.try_next().unwrap().as_slice(), &[9, 9, 9, 9, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(generator.try_next().unwrap().as_slice(), &[9, 9, 9, 9, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(FileNonceCounterStore::new(&file.0).load().unwrap(), Some(NONCE_RESERVATION_BLOCK_SIZE));
+    }
+
+    #[test]
+    fn test_corrupted_and_exhausted() {
+        let file = TempFile::new();
+        fs::write(&file.0, [1, 2, 3]).unwrap();
+        assert!(matches!(
+            ReservedSequentialNonceGenerator::new(FileNonceCounterStore::new(&file.0), [0; 4]),
+            Err(NonceReservationError::Corrupted)
+        ));
+
+        fs::write(&file.0, (u64::MAX - 1).to_be_bytes()).unwrap();
+        let mut generator = ReservedSequentialNonceGenerator::with_block_size(FileNonceCounterStore::new(&file.0), [0; 4], 2).unwrap();
+        assert!(matches!(generator.try_next(), Err(NonceReservationError::Exhausted)));
+    }
+}
@@ -0,0 +1,386 @@
+/*
+* Recovery shares, a `SecreteShare` with the metadata needed to put it back together, in a format humans can transcribe.
+*
+* Binary layout of version 1, all integers are big-endian:
+*   version       u8
+*   set id        u16       random id shared by every share of one split, so shares of different splits are never mixed
+*   threshold     u8        shares required to reconstruct the secret
+*   index         u8        share identifier, the x coordinate of the share
+*   value length  u8
+*   value         value length bytes
+*   checksum      4 bytes   first 4 bytes of the BLAKE3 hash of everything before it
+*
+* Text renderings, every one of them detects typos before the share is used:
+* - `to_words`, BIP39-style, 11 bits per word from the BIP39 English word list.
+* - `to_mnemonic`, SLIP-39-like, 10 bits per word from the first 1024 BIP39 English words, followed by an RS1024 checksum.
+* - `to_base32`, Crockford base32 with a check symbol, in hyphen separated groups of four.
+*/
+use std::fmt;
+
+use bip39::Language;
+use rand::{CryptoRng, Rng, RngCore};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::encoding::crockford::{self, CrockfordError};
+use crate::encoding::{from_groups, rs1024, to_groups};
+
+use super::master_key_builder::SecreteShare;
+
+pub const RECOVERY_SHARE_VERSION: u8 = 1;
+const CHECKSUM_LENGTH: usize = 4;
+/// Version, set id, threshold, index and value length.
+const HEADER_LENGTH: usize = 6;
+const MNEMONIC_CUSTOMIZATION: &[u8] = b"bucketdrive recovery share";
+const BASE32_GROUP_LENGTH: usize = 4;
+
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct RecoveryShare {
+    pub set_id: u16,
+    pub threshold: u8,
+    pub index: u8,
+    pub value: Vec<u8>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryShareError {
+    #[error("Share is truncated")]
+    Truncated,
+    #[error("Unsupported share version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Share checksum does not match, the share contains a typo")]
+    ChecksumMismatch,
+    #[error("Share has trailing data")]
+    TrailingData,
+    #[error("Share is empty or longer than 255 bytes")]
+    InvalidShare,
+    #[error("Threshold must be between 1 and 255")]
+    InvalidThreshold,
+    #[error("Unknown word {word:?} at position {position}")]
+    UnknownWord { position: usize, word: String },
+    #[error("Mnemonic has invalid padding")]
+    InvalidPadding,
+    #[error(transparent)]
+    Base32(#[from] CrockfordError),
+    #[error("Shares come from different splits, set {expected} and set {actual}")]
+    MixedSets { expected: u16, actual: u16 },
+    #[error("Shares of set {set_id} disagree on the threshold")]
+    MixedThresholds { set_id: u16 },
+    #[error("Share {0} was given more than once")]
+    DuplicateIndex(u8),
+    #[error("Not enough shares, {required} required but got {actual}")]
+    NotEnoughShares { required: usize, actual: usize },
+}
+
+/// Never print the share value, it's secret.
+impl fmt::Debug for RecoveryShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecoveryShare")
+            .field("set_id", &self.set_id)
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    blake3::hash(bytes).as_bytes()[..CHECKSUM_LENGTH].try_into().unwrap()
+}
+
+impl RecoveryShare {
+    pub fn generate_set_id<TCryptoRng>(csprng: &mut TCryptoRng) -> u16
+    where
+        TCryptoRng: RngCore + CryptoRng,
+    {
+        csprng.gen::<u16>()
+    }
+
+    /// Checks that `shares` come from one split and meet its threshold, then unwraps them for `MasterKeyBuilder::combine`.
+    /// Shares of different splits would otherwise combine into a wrong key without any error.
+    pub fn to_secrete_shares(shares: &[RecoveryShare]) -> Result<Vec<SecreteShare>, RecoveryShareError> {
+        let first = shares.first().ok_or(RecoveryShareError::NotEnoughShares { required: 1, actual: 0 })?;
+        let mut indexes = Vec::with_capacity(shares.len());
+        for share in shares {
+            if share.set_id != first.set_id {
+                return Err(RecoveryShareError::MixedSets { expected: first.set_id, actual: share.set_id });
+            }
+            if share.threshold != first.threshold {
+                return Err(RecoveryShareError::MixedThresholds { set_id: first.set_id });
+            }
+            // The same share twice doesn't count towards the threshold.
+            if indexes.contains(&share.index) {
+                return Err(RecoveryShareError::DuplicateIndex(share.index));
+            }
+            indexes.push(share.index);
+        }
+        if shares.len() < first.threshold as usize {
+            return Err(RecoveryShareError::NotEnoughShares {
+                required: first.threshold as usize,
+                actual: shares.len(),
+            });
+        }
+        Ok(shares.iter().map(RecoveryShare::to_secrete_share).collect())
+    }
+
+    /// Wraps a share from `MasterKeyBuilder`, the first byte of a share is its index.
+    pub fn new(set_id: u16, threshold: usize, secrete_share: &SecreteShare) -> Result<Self, RecoveryShareError> {
+        let threshold = u8::try_from(threshold)
+            .ok()
+            .filter(|threshold| *threshold > 0)
+            .ok_or(RecoveryShareError::InvalidThreshold)?;
+        let (index, value) = secrete_share.share.split_first().ok_or(RecoveryShareError::InvalidShare)?;
+        if value.is_empty() || value.len() > u8::MAX as usize {
+            return Err(RecoveryShareError::InvalidShare);
+        }
+        Ok(Self {
+            set_id,
+            threshold,
+            index: *index,
+            value: value.to_vec(),
+        })
+    }
+
+    pub fn to_secrete_share(&self) -> SecreteShare {
+        let mut share = Vec::with_capacity(1 + self.value.len());
+        share.push(self.index);
+        share.extend_from_slice(&self.value);
+        SecreteShare { share }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.value.len() + CHECKSUM_LENGTH);
+        bytes.push(RECOVERY_SHARE_VERSION);
+        bytes.extend_from_slice(&self.set_id.to_be_bytes());
+        bytes.push(self.threshold);
+        bytes.push(self.index);
+        // `new` guarantees the value fits.
+        bytes.push(self.value.len() as u8);
+        bytes.extend_from_slice(&self.value);
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Parses a share and returns the bytes after it.
+    fn parse(bytes: &[u8]) -> Result<(Self, &[u8]), RecoveryShareError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(RecoveryShareError::Truncated);
+        }
+        if bytes[0] != RECOVERY_SHARE_VERSION {
+            return Err(RecoveryShareError::UnsupportedVersion(bytes[0]));
+        }
+        let value_length = bytes[5] as usize;
+        let length = HEADER_LENGTH + value_length + CHECKSUM_LENGTH;
+        if bytes.len() < length {
+            return Err(RecoveryShareError::Truncated);
+        }
+        let (share, rest) = bytes.split_at(length);
+        let (content, share_checksum) = share.split_at(length - CHECKSUM_LENGTH);
+        if checksum(content) != share_checksum {
+            return Err(RecoveryShareError::ChecksumMismatch);
+        }
+        if value_length == 0 {
+            return Err(RecoveryShareError::InvalidShare);
+        }
+        if content[3] == 0 {
+            return Err(RecoveryShareError::InvalidThreshold);
+        }
+        Ok((
+            Self {
+                set_id: u16::from_be_bytes([content[1], content[2]]),
+                threshold: content[3],
+                index: content[4],
+                value: content[HEADER_LENGTH..].to_vec(),
+            },
+            rest,
+        ))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RecoveryShareError> {
+        let (share, rest) = Self::parse(bytes)?;
+        if !rest.is_empty() {
+            return Err(RecoveryShareError::TrailingData);
+        }
+        Ok(share)
+    }
+
+    /// Word encodings pad to whole words, the padding decodes to at most one extra zero byte.
+    fn from_padded_bytes(bytes: &[u8]) -> Result<Self, RecoveryShareError> {
+        let (share, rest) = Self::parse(bytes)?;
+        if rest.len() > 1 || rest.iter().any(|byte| *byte != 0) {
+            return Err(RecoveryShareError::TrailingData);
+        }
+        Ok(share)
+    }
+
+    /// BIP39-style word list, 11 bits per word.
+    pub fn to_words(&self) -> String {
+        let word_list = Language::English.word_list();
+        to_groups(&self.to_bytes(), 11)
+            .into_iter()
+            .map(|group| word_list[group as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn from_words(words: &str) -> Result<Self, RecoveryShareError> {
+        let groups = words
+            .split_whitespace()
+            .enumerate()
+            .map(|(position, word)| {
+                Language::English
+                    .find_word(&word.to_lowercase())
+                    .ok_or_else(|| RecoveryShareError::UnknownWord { position, word: word.to_string() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_padded_bytes(&from_groups(&groups, 11).ok_or(RecoveryShareError::InvalidPadding)?)
+    }
+
+    /// SLIP-39-like mnemonic, 10 bits per word followed by a 3 word RS1024 checksum.
+    pub fn to_mnemonic(&self) -> String {
+        let word_list = Language::English.word_list();
+        let mut groups = to_groups(&self.to_bytes(), 10);
+        groups.extend_from_slice(&rs1024::create_checksum(MNEMONIC_CUSTOMIZATION, &groups));
+        groups
+            .into_iter()
+            .map(|group| word_list[group as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, RecoveryShareError> {
+        let groups = mnemonic
+            .split_whitespace()
+            .enumerate()
+            .map(|(position, word)| {
+                Language::English
+                    .find_word(&word.to_lowercase())
+                    .filter(|group| *group < 1024)
+                    .ok_or_else(|| RecoveryShareError::UnknownWord { position, word: word.to_string() })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !rs1024::verify_checksum(MNEMONIC_CUSTOMIZATION, &groups) {
+            return Err(RecoveryShareError::ChecksumMismatch);
+        }
+        let data = &groups[..groups.len() - rs1024::CHECKSUM_LENGTH];
+        Self::from_padded_bytes(&from_groups(data, 10).ok_or(RecoveryShareError::InvalidPadding)?)
+    }
+
+    /// Crockford base32 with a check symbol, e.g. `0G00-...-ABC~`.
+    pub fn to_base32(&self) -> String {
+        crockford::encode_with_check(&self.to_bytes())
+            .as_bytes()
+            .chunks(BASE32_GROUP_LENGTH)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub fn from_base32(encoded: &str) -> Result<Self, RecoveryShareError> {
+        Self::from_bytes(&crockford::decode_with_check(encoded)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share() -> RecoveryShare {
+        let mut share = vec![2];
+        share.extend((0..32).map(|byte| byte * 5));
+        RecoveryShare::new(0xBEEF, 2, &SecreteShare { share }).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let share = share();
+        assert_eq!(RecoveryShare::from_bytes(&share.to_bytes()).unwrap(), share);
+        assert_eq!(RecoveryShare::from_words(&share.to_words()).unwrap(), share);
+        assert_eq!(RecoveryShare::from_mnemonic(&share.to_mnemonic()).unwrap(), share);
+        assert_eq!(RecoveryShare::from_base32(&share.to_base32()).unwrap(), share);
+        assert_eq!(share.to_secrete_share().share[0], 2);
+        assert_eq!(share.to_secrete_share().share[1..], share.value[..]);
+    }
+
+    #[test]
+    fn test_transcription_is_forgiving() {
+        let share = share();
+        assert_eq!(RecoveryShare::from_words(&share.to_words().to_uppercase()).unwrap(), share);
+        assert_eq!(RecoveryShare::from_base32(&share.to_base32().to_lowercase().replace('-', " ")).unwrap(), share);
+    }
+
+    #[test]
+    fn test_typos_are_detected() {
+        let share = share();
+
+        // Word 8 lies within the share value, so only the checksum can catch it.
+        let words = share.to_words();
+        let mut words: Vec<&str> = words.split(' ').collect();
+        words[8] = if words[8] == "abandon" { "ability" } else { "abandon" };
+        assert_eq!(RecoveryShare::from_words(&words.join(" ")), Err(RecoveryShareError::ChecksumMismatch));
+        words[8] = "bucketdrive";
+        assert_eq!(
+            RecoveryShare::from_words(&words.join(" ")),
+            Err(RecoveryShareError::UnknownWord { position: 8, word: "bucketdrive".to_string() })
+        );
+
+        let mnemonic = share.to_mnemonic();
+        let mut words: Vec<&str> = mnemonic.split(' ').collect();
+        words.swap(5, 6);
+        assert_eq!(RecoveryShare::from_mnemonic(&words.join(" ")), Err(RecoveryShareError::ChecksumMismatch));
+
+        let mut base32: Vec<char> = share.to_base32().chars().collect();
+        base32[7] = if base32[7] == 'A' { 'B' } else { 'A' };
+        assert!(RecoveryShare::from_base32(&base32.into_iter().collect::<String>()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_shares() {
+        assert_eq!(
+            RecoveryShare::new(1, 0, &SecreteShare { share: vec![1, 2] }),
+            Err(RecoveryShareError::InvalidThreshold)
+        );
+        assert_eq!(RecoveryShare::new(1, 2, &SecreteShare { share: vec![1] }), Err(RecoveryShareError::InvalidShare));
+
+        let bytes = share().to_bytes();
+        assert_eq!(RecoveryShare::from_bytes(&bytes[..bytes.len() - 1]), Err(RecoveryShareError::Truncated));
+        let mut invalid = bytes.clone();
+        invalid[0] = 2;
+        assert_eq!(RecoveryShare::from_bytes(&invalid), Err(RecoveryShareError::UnsupportedVersion(2)));
+        let mut invalid = bytes.clone();
+        invalid.push(0);
+        assert_eq!(RecoveryShare::from_bytes(&invalid), Err(RecoveryShareError::TrailingData));
+    }
+
+    #[test]
+    fn test_shares_of_one_set_only() {
+        let share = |set_id: u16, index: u8| {
+            let mut share = vec![index];
+            share.extend([index; 32]);
+            RecoveryShare::new(set_id, 2, &SecreteShare { share }).unwrap()
+        };
+        assert_eq!(RecoveryShare::to_secrete_shares(&[share(7, 1), share(7, 3)]).unwrap().len(), 2);
+        assert_eq!(
+            RecoveryShare::to_secrete_shares(&[share(7, 1), share(8, 2)]).err(),
+            Some(RecoveryShareError::MixedSets { expected: 7, actual: 8 })
+        );
+        assert_eq!(
+            RecoveryShare::to_secrete_shares(&[share(7, 1)]).err(),
+            Some(RecoveryShareError::NotEnoughShares { required: 2, actual: 1 })
+        );
+        assert_eq!(
+            RecoveryShare::to_secrete_shares(&[share(7, 1), share(7, 1)]).err(),
+            Some(RecoveryShareError::DuplicateIndex(1))
+        );
+        let mut other_threshold = share(7, 2);
+        other_threshold.threshold = 3;
+        assert_eq!(
+            RecoveryShare::to_secrete_shares(&[share(7, 1), other_threshold]).err(),
+            Some(RecoveryShareError::MixedThresholds { set_id: 7 })
+        );
+    }
+
+    #[test]
+    fn test_debug_hides_value() {
+        assert_eq!(format!("{:?}", share()), "RecoveryShare { set_id: 48879, threshold: 2, index: 2, .. }");
+    }
+}
@@ -17,6 +17,8 @@ pub mod region;
 pub mod middleware;
 #[cfg(feature = "key")]
 pub mod key;
+#[cfg(feature = "key")]
+pub mod encryption;
 pub mod storage_engine;
 pub mod bucket;
 pub mod share;
@@ -26,6 +28,7 @@ pub mod account;
 pub mod metric;
 pub mod search;
 pub mod validation;
+pub mod encoding;
 
 
 /// Theses are all the supported encoding for files that are uploaded or downloaded.
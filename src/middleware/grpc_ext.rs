@@ -10,7 +10,7 @@ use crate::client::middleware::ratelimit::RatelimitMetadata;
 use crate::client::middleware::user_agent::UserAgent;
 use crate::middleware::types::UserAgent;
 use crate::token::access_token::AccessToken;
-use crate::token::idempotency_token::IdempotencyToken;
+use crate::token::idempotency_token::{IdempotencyToken, IDEMPOTENCY_TOKEN_HEADER};
 use super::{RequestBuilderAuthorizationMetadataExt, RequestBuilderAuthorizationMetadataSetterExt, RequestBuilderContentTypeMetadataExt, RequestBuilderContentTypeMetadataSetterExt, RequestBuilderIdempotencyTokenMetadataSetterExt, ResponseRatelimitHeaderExtractorExt, ResponseUserAgentHeaderExtractorExt};
 
 
@@ -70,7 +70,8 @@ impl <T> RequestBuilderIdempotencyTokenMetadataSetterExt for Request<T> {
     const IDEMPOTENCY_TOKEN_KEY: &'static HeaderName = &();
 
     fn set_idempotency_token(&mut self, idempotency_token: &IdempotencyToken) -> Result<(), Self::Error> {
-        todo!()
+        self.metadata_mut().insert(IDEMPOTENCY_TOKEN_HEADER, idempotency_token.to_metadata_value());
+        Ok(())
     }
 
 }
@@ -11,7 +11,7 @@ pub mod types;
 pub const AUTHORIZATION_VALUE_MAX_LENGTH: usize = 120;
 pub const CONTENT_TYPE_VALUE_MAX_LENGTH: usize = 12;
 pub const CONTENT_ENCODING_VALUE_MAX_LENGTH: usize = 16;
-pub const IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH: usize = 32;
+pub use crate::token::idempotency_token::IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH;
 pub const USER_AGENT_VALUE_MAX_LENGTH: usize = 16;
 pub const RATE_LIMIT_VALUE_MAX_LENGTH: usize = 32;
 pub const SIGNATURE_VALUE_MAX_LENGTH: usize = 2048;
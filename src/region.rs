@@ -76,7 +76,7 @@ pub enum Region {
     SouthAmericaEast,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
 pub struct DatacenterRegion {
     region: Region,
     availability_zone: Box<str>,
@@ -93,18 +93,14 @@ impl FromStr for DatacenterRegion {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.splitn(2, '-').collect();
-        if parts.len() != 2 {
-            return Err(());
-        }
+        // The region itself contains a hyphen, the availability zone is after the last one.
+        let (region_str, availability_zone) = s.rsplit_once('-').ok_or(())?;
 
         // Parse the region string to the corresponding enum variant
-        let region_str = parts[0];
         let region = region_str.parse().map_err(|_| ())?;
 
         // Check the availability_zone length and convert to Box<str>
-        let availability_zone = parts[1];
-        if availability_zone.len() > AVAILABILITY_ZONE_MAX_LENGTH {
+        if availability_zone.is_empty() || availability_zone.len() > AVAILABILITY_ZONE_MAX_LENGTH {
             return Err(());
         }
 
@@ -145,4 +141,10 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_round_trip() {
+        let region = DatacenterRegion::from_str("eu-central-1").unwrap();
+        assert_eq!(region.region, Region::EuropeCentral);
+        assert_eq!(region.to_string(), "eu-central-1");
+    }
 }
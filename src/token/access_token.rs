@@ -1,7 +1,10 @@
 use crate::token::bearer_token::BearerToken;
+use crate::token::capability_token::CapabilityToken;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AccessToken {
     PersonalToken(BearerToken),
     ApiToken(BearerToken),
+    /// Scoped token that carries its own claims, see `CapabilityToken`.
+    CapabilityToken(CapabilityToken),
 }
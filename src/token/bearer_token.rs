@@ -15,7 +15,7 @@ impl TryFrom<&str> for BearerToken {
 
 impl From<JwtToken> for BearerToken {
     fn from(value: JwtToken) -> Self {
-        Self(value)
+        Self(value.into_string())
     }
 }
 
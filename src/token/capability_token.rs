@@ -0,0 +1,379 @@
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ed25519_compact::{KeyPair, PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use zeroize::Zeroize;
+
+use crate::bucket::bucket_guid::BucketGuid;
+use crate::bucket::bucket_path::BucketRelativePath;
+use crate::bucket::bucket_permission::BucketPermissionFlags;
+use crate::region::DatacenterRegion;
+
+/// Prefix of the encoded token, the digit is the format version.
+pub const CAPABILITY_TOKEN_PREFIX: &str = "bdcap1.";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CapabilityTokenError {
+    #[error("Token must start with {}", CAPABILITY_TOKEN_PREFIX)]
+    InvalidPrefix,
+    #[error("Invalid base64url encoding: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invalid token encoding: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error("Token has no blocks")]
+    NoBlocks,
+    #[error("Signature of block {0} is invalid")]
+    InvalidSignature(usize),
+    #[error("Block {0} grants more than the block before it")]
+    NotAttenuated(usize),
+    #[error("Token holder proof doesn't match the last block")]
+    InvalidProof,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token doesn't grant the requested access")]
+    Denied,
+}
+
+/// Permissions on one bucket, optionally only on `path_prefix` and below it, compared by whole segments.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketCapability {
+    pub bucket: BucketGuid,
+    pub permissions: BucketPermissionFlags,
+    pub path_prefix: Option<BucketRelativePath>,
+}
+
+impl BucketCapability {
+    fn is_subset_of(&self, other: &BucketCapability) -> bool {
+        self.bucket == other.bucket
+            && other.permissions.contains(self.permissions)
+            && match (&self.path_prefix, &other.path_prefix) {
+                (_, None) => true,
+                (Some(prefix), Some(other_prefix)) => prefix.starts_with(other_prefix),
+                (None, Some(_)) => false,
+            }
+    }
+}
+
+/// What a capability token grants, every block of a token carries its own claims.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityClaims {
+    pub buckets: Vec<BucketCapability>,
+    /// Regions the token can be used in, `None` for every region.
+    pub regions: Option<Vec<DatacenterRegion>>,
+    #[serde(with = "time::serde::timestamp")]
+    pub expires_at: OffsetDateTime,
+}
+
+impl CapabilityClaims {
+    /// Whether these claims grant nothing `other` doesn't grant.
+    pub fn is_subset_of(&self, other: &CapabilityClaims) -> bool {
+        let regions = match (&self.regions, &other.regions) {
+            (_, None) => true,
+            (Some(regions), Some(other_regions)) => regions.iter().all(|region| other_regions.contains(region)),
+            (None, Some(_)) => false,
+        };
+        regions
+            && self.expires_at <= other.expires_at
+            && self
+                .buckets
+                .iter()
+                .all(|bucket| other.buckets.iter().any(|other_bucket| bucket.is_subset_of(other_bucket)))
+    }
+
+    /// Whether these claims grant `permissions` on `path` in `bucket`, from `region`.
+    pub fn allows(
+        &self,
+        bucket: &BucketGuid,
+        permissions: BucketPermissionFlags,
+        path: &BucketRelativePath,
+        region: &DatacenterRegion,
+    ) -> bool {
+        let region_allowed = match &self.regions {
+            Some(regions) => regions.contains(region),
+            None => true,
+        };
+        region_allowed
+            && self.buckets.iter().any(|capability| {
+                &capability.bucket == bucket
+                    && capability.permissions.contains(permissions)
+                    && match &capability.path_prefix {
+                        Some(prefix) => path.starts_with(prefix),
+                        None => true,
+                    }
+            })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CapabilityBlock {
+    claims: CapabilityClaims,
+    /// Public key that signs the next block, its secret key is handed to the holder.
+    next_key: [u8; PublicKey::BYTES],
+    signature: Vec<u8>,
+}
+
+impl CapabilityBlock {
+    fn new(
+        claims: CapabilityClaims,
+        signing_key: &SecretKey,
+        previous_signature: &[u8],
+    ) -> Result<(Self, KeyPair), CapabilityTokenError> {
+        let next = KeyPair::generate();
+        let next_key = *next.pk;
+        let signature = signing_key
+            .sign(Self::signed_message(&claims, &next_key, previous_signature)?, None)
+            .to_vec();
+        Ok((Self { claims, next_key, signature }, next))
+    }
+
+    /// The previous signature chains the blocks, so they can't be reordered or spliced between tokens.
+    fn signed_message(
+        claims: &CapabilityClaims,
+        next_key: &[u8; PublicKey::BYTES],
+        previous_signature: &[u8],
+    ) -> Result<Vec<u8>, CapabilityTokenError> {
+        Ok(bincode::serialize(&(previous_signature, claims, next_key))?)
+    }
+}
+
+/// An Ed25519 signed capability token, the claims are inside the token so it can be checked without a lookup.
+///
+/// The first block is signed by the issuer's root key. Every block names a fresh key pair that signs the next block,
+/// the holder gets the secret key of the last one. A holder can attenuate offline, by appending a block that grants
+/// a subset of the last block and passing on the new secret key, but can never widen what the token grants.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityToken {
+    blocks: Vec<CapabilityBlock>,
+    /// Secret key of the last block's `next_key`.
+    proof: Vec<u8>,
+}
+
+impl CapabilityToken {
+    /// Mints a token signed by the issuer's `root_key`.
+    pub fn mint(claims: CapabilityClaims, root_key: &SecretKey) -> Result<Self, CapabilityTokenError> {
+        let (block, next) = CapabilityBlock::new(claims, root_key, &[])?;
+        Ok(Self {
+            blocks: vec![block],
+            proof: next.sk.to_vec(),
+        })
+    }
+
+    /// Derives a token that grants `claims`, which must be a subset of what this token grants.
+    pub fn attenuate(&self, claims: CapabilityClaims) -> Result<Self, CapabilityTokenError> {
+        let last = self.blocks.last().ok_or(CapabilityTokenError::NoBlocks)?;
+        if !claims.is_subset_of(&last.claims) {
+            return Err(CapabilityTokenError::NotAttenuated(self.blocks.len()));
+        }
+        let signing_key = SecretKey::from_slice(&self.proof).map_err(|_| CapabilityTokenError::InvalidProof)?;
+        let (block, next) = CapabilityBlock::new(claims, &signing_key, &last.signature)?;
+        let mut blocks = self.blocks.clone();
+        blocks.push(block);
+        Ok(Self {
+            blocks,
+            proof: next.sk.to_vec(),
+        })
+    }
+
+    /// Verifies the chain of blocks against the issuer's `root_key`, and returns the claims the token grants.
+    pub fn verify(&self, root_key: &PublicKey, now: OffsetDateTime) -> Result<&CapabilityClaims, CapabilityTokenError> {
+        let mut signing_key = *root_key;
+        let mut previous: Option<&CapabilityBlock> = None;
+        for (index, block) in self.blocks.iter().enumerate() {
+            let message =
+                CapabilityBlock::signed_message(&block.claims, &block.next_key, previous.map_or(&[], |block| &block.signature))?;
+            let signature = Signature::from_slice(&block.signature).map_err(|_| CapabilityTokenError::InvalidSignature(index))?;
+            signing_key
+                .verify(message, &signature)
+                .map_err(|_| CapabilityTokenError::InvalidSignature(index))?;
+            if previous.is_some_and(|previous| !block.claims.is_subset_of(&previous.claims)) {
+                return Err(CapabilityTokenError::NotAttenuated(index));
+            }
+            signing_key = PublicKey::new(block.next_key);
+            previous = Some(block);
+        }
+
+        let last = previous.ok_or(CapabilityTokenError::NoBlocks)?;
+        let proof = SecretKey::from_slice(&self.proof).map_err(|_| CapabilityTokenError::InvalidProof)?;
+        if proof.public_key() != signing_key {
+            return Err(CapabilityTokenError::InvalidProof);
+        }
+        if last.claims.expires_at <= now {
+            return Err(CapabilityTokenError::Expired);
+        }
+        Ok(&last.claims)
+    }
+
+    /// Verifies the token and checks it grants `permissions` on `path` in `bucket`, from `region`.
+    pub fn authorize(
+        &self,
+        root_key: &PublicKey,
+        now: OffsetDateTime,
+        bucket: &BucketGuid,
+        permissions: BucketPermissionFlags,
+        path: &BucketRelativePath,
+        region: &DatacenterRegion,
+    ) -> Result<(), CapabilityTokenError> {
+        if self.verify(root_key, now)?.allows(bucket, permissions, path, region) {
+            Ok(())
+        } else {
+            Err(CapabilityTokenError::Denied)
+        }
+    }
+
+    /// Claims of the last block, unverified.
+    pub fn claims(&self) -> Option<&CapabilityClaims> {
+        self.blocks.last().map(|block| &block.claims)
+    }
+}
+
+impl Drop for CapabilityToken {
+    fn drop(&mut self) {
+        self.proof.zeroize();
+    }
+}
+
+impl Debug for CapabilityToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CapabilityToken")
+            .field("claims", &self.claims())
+            .field("blocks", &self.blocks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Display for CapabilityToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = bincode::serialize(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}{}", CAPABILITY_TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+    }
+}
+
+impl FromStr for CapabilityToken {
+    type Err = CapabilityTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.strip_prefix(CAPABILITY_TOKEN_PREFIX).ok_or(CapabilityTokenError::InvalidPrefix)?;
+        Ok(bincode::deserialize(&URL_SAFE_NO_PAD.decode(encoded)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    fn now() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()
+    }
+
+    fn region(region: &str) -> DatacenterRegion {
+        region.parse().unwrap()
+    }
+
+    fn path(path: &str) -> BucketRelativePath {
+        path.parse().unwrap()
+    }
+
+    fn claims(bucket: &BucketGuid) -> CapabilityClaims {
+        CapabilityClaims {
+            buckets: vec![BucketCapability {
+                bucket: bucket.clone(),
+                permissions: BucketPermissionFlags::VIEW | BucketPermissionFlags::READ | BucketPermissionFlags::WRITE,
+                path_prefix: None,
+            }],
+            regions: None,
+            expires_at: now() + Duration::days(30),
+        }
+    }
+
+    #[test]
+    fn test_mint_attenuate_and_authorize() {
+        let root = KeyPair::generate();
+        let bucket = BucketGuid::generate();
+        let token = CapabilityToken::mint(claims(&bucket), &root.sk).unwrap();
+        let eu = region("eu-central-1");
+        token
+            .authorize(&root.pk, now(), &bucket, BucketPermissionFlags::WRITE, &path("/builds/1.tar"), &eu)
+            .unwrap();
+
+        let ci_claims = CapabilityClaims {
+            buckets: vec![BucketCapability {
+                bucket: bucket.clone(),
+                permissions: BucketPermissionFlags::READ,
+                path_prefix: Some(path("/builds")),
+            }],
+            regions: Some(vec![eu.clone()]),
+            expires_at: now() + Duration::hours(1),
+        };
+        let ci = token.attenuate(ci_claims).unwrap();
+        // Survives encoding, nothing but the token is needed to check it.
+        let ci: CapabilityToken = ci.to_string().parse().unwrap();
+        ci.authorize(&root.pk, now(), &bucket, BucketPermissionFlags::READ, &path("/builds/1.tar"), &eu)
+            .unwrap();
+        for (permissions, path, region) in [
+            (BucketPermissionFlags::WRITE, "/builds/1.tar", eu.clone()),
+            (BucketPermissionFlags::READ, "/secrets/key", eu.clone()),
+            // A sibling that only shares the characters of the prefix.
+            (BucketPermissionFlags::READ, "/builds-secret/x", eu.clone()),
+            (BucketPermissionFlags::READ, "/builds/1.tar", self::region("us-east-1")),
+        ] {
+            assert!(matches!(
+                ci.authorize(&root.pk, now(), &bucket, permissions, &self::path(path), &region),
+                Err(CapabilityTokenError::Denied)
+            ));
+        }
+        // '..' can't be used to leave the prefix, such a path doesn't parse.
+        assert!("/builds/../secrets/key".parse::<BucketRelativePath>().is_err());
+        assert!(matches!(
+            ci.verify(&root.pk, now() + Duration::hours(2)),
+            Err(CapabilityTokenError::Expired)
+        ));
+        assert!(matches!(
+            ci.verify(&KeyPair::generate().pk, now()),
+            Err(CapabilityTokenError::InvalidSignature(0))
+        ));
+    }
+
+    #[test]
+    fn test_attenuation_cannot_widen() {
+        let root = KeyPair::generate();
+        let bucket = BucketGuid::generate();
+        let token = CapabilityToken::mint(claims(&bucket), &root.sk).unwrap();
+
+        let mut wider = claims(&bucket);
+        wider.buckets[0].permissions |= BucketPermissionFlags::DELETE_BUCKET;
+        assert!(matches!(token.attenuate(wider.clone()), Err(CapabilityTokenError::NotAttenuated(1))));
+        let mut other_bucket = claims(&BucketGuid::generate());
+        other_bucket.expires_at = now();
+        assert!(matches!(token.attenuate(other_bucket), Err(CapabilityTokenError::NotAttenuated(1))));
+
+        // A sibling of the prefix isn't below it.
+        let mut builds = claims(&bucket);
+        builds.buckets[0].path_prefix = Some(path("/builds"));
+        let builds = token.attenuate(builds).unwrap();
+        let mut sibling = claims(&bucket);
+        sibling.buckets[0].path_prefix = Some(path("/builds-secret"));
+        assert!(matches!(builds.attenuate(sibling), Err(CapabilityTokenError::NotAttenuated(2))));
+        let mut nested = claims(&bucket);
+        nested.buckets[0].path_prefix = Some(path("/builds/nightly"));
+        assert!(builds.attenuate(nested).is_ok());
+
+        // A holder that forges a wider block with its own key is still caught.
+        let mut forged = token.clone();
+        let signing_key = SecretKey::from_slice(&forged.proof).unwrap();
+        let (block, next) = CapabilityBlock::new(wider, &signing_key, &forged.blocks[0].signature).unwrap();
+        forged.blocks.push(block);
+        forged.proof = next.sk.to_vec();
+        assert!(matches!(forged.verify(&root.pk, now()), Err(CapabilityTokenError::NotAttenuated(1))));
+
+        // Dropping the proof of the last block.
+        let mut stolen = token.attenuate(claims(&bucket)).unwrap();
+        stolen.proof = token.proof.clone();
+        assert!(matches!(stolen.verify(&root.pk, now()), Err(CapabilityTokenError::InvalidProof)));
+    }
+}
@@ -1 +1,329 @@
-pub struct IdempotencyToken(pub String);
\ No newline at end of file
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http::HeaderValue;
+use rand::{CryptoRng, RngCore};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use tonic::metadata::{Ascii, MetadataValue};
+use uuid::Uuid;
+
+/// Longest accepted token, a simple UUID is 32 characters.
+pub const IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH: usize = 32;
+
+/// Header (and gRPC metadata key) the token is sent in.
+pub const IDEMPOTENCY_TOKEN_HEADER: &str = "idempotency-key";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyTokenError {
+    #[error("Idempotency token can't be empty")]
+    Empty,
+    #[error("Idempotency token is {0} characters, the maximum is {max}", max = IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH)]
+    TooLong(usize),
+    #[error("Idempotency token contains the invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// A client chosen token that makes retrying a request safe, the server runs the request once per token.
+/// Only `A-Z a-z 0-9 - _ . ~` are allowed, so the token is always a valid header value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SerializeDisplay, DeserializeFromStr)]
+pub struct IdempotencyToken(String);
+
+impl IdempotencyToken {
+    /// Time-ordered token, a UUIDv7 in simple format.
+    pub fn generate() -> Self {
+        Self(Uuid::now_v7().simple().to_string())
+    }
+
+    /// Random 128-bit token, base64url encoded. Doesn't leak when it was created.
+    pub fn generate_random<TCryptoRng: RngCore + CryptoRng>(rng: &mut TCryptoRng) -> Self {
+        let mut bytes = [0u8; 16];
+        rng.fill_bytes(&mut bytes);
+        Self(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Value of the `idempotency-key` header.
+    pub fn to_header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0).expect("Idempotency tokens only contain header safe characters")
+    }
+
+    /// Value of the `idempotency-key` gRPC metadata entry.
+    pub fn to_metadata_value(&self) -> MetadataValue<Ascii> {
+        MetadataValue::from_str(&self.0).expect("Idempotency tokens only contain header safe characters")
+    }
+}
+
+/// Sends the idempotency token with a request.
+pub trait IdempotencyTokenRequestExt {
+    fn with_idempotency_token(self, idempotency_token: &IdempotencyToken) -> Self;
+}
+
+impl IdempotencyTokenRequestExt for reqwest::RequestBuilder {
+    fn with_idempotency_token(self, idempotency_token: &IdempotencyToken) -> Self {
+        self.header(IDEMPOTENCY_TOKEN_HEADER, idempotency_token.to_header_value())
+    }
+}
+
+impl<T> IdempotencyTokenRequestExt for tonic::Request<T> {
+    fn with_idempotency_token(mut self, idempotency_token: &IdempotencyToken) -> Self {
+        self.metadata_mut().insert(IDEMPOTENCY_TOKEN_HEADER, idempotency_token.to_metadata_value());
+        self
+    }
+}
+
+impl Display for IdempotencyToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for IdempotencyToken {
+    type Err = IdempotencyTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(IdempotencyTokenError::Empty);
+        }
+        if s.len() > IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH {
+            return Err(IdempotencyTokenError::TooLong(s.len()));
+        }
+        if let Some(invalid) = s
+            .chars()
+            .find(|char| !(char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | '.' | '~')))
+        {
+            return Err(IdempotencyTokenError::InvalidCharacter(invalid));
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl TryFrom<&str> for IdempotencyToken {
+    type Error = IdempotencyTokenError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Tokens are scoped to the user that sent them, so one user can't replay another user's response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey {
+    pub user_id: Uuid,
+    pub token: IdempotencyToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyOutcome<TResponse> {
+    /// First time the key is seen, the caller runs the request and calls `complete` or `abandon`.
+    New,
+    /// The first request with the key is still running, the retry should be rejected (409).
+    InProgress,
+    /// The request already ran, replay its response instead of running it again.
+    Completed(TResponse),
+}
+
+/// Remembers which requests already ran, for the dedup window.
+pub trait IdempotencyStore<TResponse> {
+    /// Claims the key when it's new, otherwise returns what happened to the first request.
+    fn begin(&self, key: &IdempotencyKey) -> IdempotencyOutcome<TResponse>;
+
+    /// Stores the response of the first request, later requests with the key get it.
+    fn complete(&self, key: &IdempotencyKey, response: TResponse);
+
+    /// Releases the key of a request that failed without side effects, so it can be retried.
+    fn abandon(&self, key: &IdempotencyKey);
+}
+
+enum IdempotencyEntry<TResponse> {
+    InProgress,
+    Completed(TResponse),
+}
+
+/// Fewest entries before `begin` sweeps expired keys of other requests.
+const MIN_SWEEP_LEN: usize = 1024;
+
+struct IdempotencyEntries<TResponse> {
+    map: HashMap<IdempotencyKey, (Instant, IdempotencyEntry<TResponse>)>,
+    /// Expired keys are swept once the map grows to this length, so each `begin` is amortized O(1).
+    sweep_len: usize,
+}
+
+/// In-memory store, entries expire `ttl` after the request started.
+/// Only deduplicates requests that reach the same process.
+pub struct InMemoryIdempotencyStore<TResponse> {
+    ttl: Duration,
+    entries: Mutex<IdempotencyEntries<TResponse>>,
+}
+
+impl<TResponse: Clone> InMemoryIdempotencyStore<TResponse> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(IdempotencyEntries {
+                map: HashMap::new(),
+                sweep_len: MIN_SWEEP_LEN,
+            }),
+        }
+    }
+
+    /// Number of stored keys, expired keys are only removed once `begin` sweeps or reuses them.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn begin_at(&self, key: &IdempotencyKey, now: Instant) -> IdempotencyOutcome<TResponse> {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let expired = |started: &Instant| now.duration_since(*started) >= self.ttl;
+        match entries.map.get(key) {
+            Some((started, IdempotencyEntry::InProgress)) if !expired(started) => return IdempotencyOutcome::InProgress,
+            Some((started, IdempotencyEntry::Completed(response))) if !expired(started) => {
+                return IdempotencyOutcome::Completed(response.clone())
+            }
+            _ => {}
+        }
+
+        if entries.map.len() >= entries.sweep_len {
+            entries.map.retain(|_, (started, _)| !expired(started));
+            entries.sweep_len = (entries.map.len() * 2).max(MIN_SWEEP_LEN);
+        }
+        entries.map.insert(key.clone(), (now, IdempotencyEntry::InProgress));
+        IdempotencyOutcome::New
+    }
+}
+
+impl<TResponse: Clone> IdempotencyStore<TResponse> for InMemoryIdempotencyStore<TResponse> {
+    fn begin(&self, key: &IdempotencyKey) -> IdempotencyOutcome<TResponse> {
+        self.begin_at(key, Instant::now())
+    }
+
+    fn complete(&self, key: &IdempotencyKey, response: TResponse) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Only the first response is kept, an expired key is replaced by the next `begin`.
+        if let Some((_, entry @ IdempotencyEntry::InProgress)) = entries.map.get_mut(key) {
+            *entry = IdempotencyEntry::Completed(response);
+        }
+    }
+
+    fn abandon(&self, key: &IdempotencyKey) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((_, IdempotencyEntry::InProgress)) = entries.map.get(key) {
+            entries.map.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn test_generate_and_parse() {
+        for token in [IdempotencyToken::generate(), IdempotencyToken::generate_random(&mut OsRng)] {
+            assert!(token.as_str().len() <= IDEMPOTENCY_TOKEN_VALUE_MAX_LENGTH);
+            assert_eq!(token.as_str().parse::<IdempotencyToken>().unwrap(), token);
+        }
+        assert_eq!("".parse::<IdempotencyToken>(), Err(IdempotencyTokenError::Empty));
+        assert_eq!("a".repeat(33).parse::<IdempotencyToken>(), Err(IdempotencyTokenError::TooLong(33)));
+        assert_eq!("upload 1".parse::<IdempotencyToken>(), Err(IdempotencyTokenError::InvalidCharacter(' ')));
+        assert_eq!("upload\r\n".parse::<IdempotencyToken>(), Err(IdempotencyTokenError::InvalidCharacter('\r')));
+    }
+
+    #[test]
+    fn test_request_injection() {
+        let token = IdempotencyToken::generate();
+        let http_request = reqwest::Client::new()
+            .post("http://localhost/upload")
+            .with_idempotency_token(&token)
+            .build()
+            .unwrap();
+        assert_eq!(http_request.headers().get(IDEMPOTENCY_TOKEN_HEADER), Some(&token.to_header_value()));
+
+        let grpc_request = tonic::Request::new(()).with_idempotency_token(&token);
+        assert_eq!(grpc_request.metadata().get(IDEMPOTENCY_TOKEN_HEADER).unwrap().to_str().unwrap(), token.as_str());
+    }
+
+    #[test]
+    fn test_retried_upload_runs_once() {
+        let store = InMemoryIdempotencyStore::new(Duration::from_secs(60));
+        let key = IdempotencyKey {
+            user_id: Uuid::new_v4(),
+            token: IdempotencyToken::generate(),
+        };
+        let mut objects_created = 0;
+        let mut upload = || match store.begin(&key) {
+            IdempotencyOutcome::New => {
+                objects_created += 1;
+                store.complete(&key, format!("object-{}", objects_created));
+                format!("object-{}", objects_created)
+            }
+            IdempotencyOutcome::Completed(response) => response,
+            IdempotencyOutcome::InProgress => unreachable!(),
+        };
+        assert_eq!(upload(), "object-1");
+        assert_eq!(upload(), "object-1");
+        assert_eq!(objects_created, 1);
+
+        // Another user with the same token is a different request.
+        let other_user = IdempotencyKey {
+            user_id: Uuid::new_v4(),
+            token: key.token.clone(),
+        };
+        assert_eq!(store.begin(&other_user), IdempotencyOutcome::New);
+        assert_eq!(store.begin(&other_user), IdempotencyOutcome::InProgress);
+        store.abandon(&other_user);
+        assert_eq!(store.begin(&other_user), IdempotencyOutcome::New);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let store = InMemoryIdempotencyStore::new(Duration::from_secs(60));
+        let key = IdempotencyKey {
+            user_id: Uuid::new_v4(),
+            token: IdempotencyToken::generate(),
+        };
+        let start = Instant::now();
+        assert_eq!(store.begin_at(&key, start), IdempotencyOutcome::New);
+        store.complete(&key, 1);
+        assert_eq!(store.begin_at(&key, start + Duration::from_secs(59)), IdempotencyOutcome::Completed(1));
+        assert_eq!(store.begin_at(&key, start + Duration::from_secs(60)), IdempotencyOutcome::New);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_expired_entries_are_swept() {
+        let store = InMemoryIdempotencyStore::<u32>::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let key = || IdempotencyKey {
+            user_id: Uuid::new_v4(),
+            token: IdempotencyToken::generate(),
+        };
+        for _ in 0..MIN_SWEEP_LEN {
+            assert_eq!(store.begin_at(&key(), start), IdempotencyOutcome::New);
+        }
+        // Still in the window, nothing is swept and the map grows.
+        assert_eq!(store.begin_at(&key(), start + Duration::from_secs(59)), IdempotencyOutcome::New);
+        assert_eq!(store.len(), MIN_SWEEP_LEN + 1);
+
+        // Expired keys are only swept once the map doubled since the last sweep.
+        for _ in MIN_SWEEP_LEN + 1..2 * MIN_SWEEP_LEN {
+            assert_eq!(store.begin_at(&key(), start + Duration::from_secs(59)), IdempotencyOutcome::New);
+        }
+        assert_eq!(store.len(), 2 * MIN_SWEEP_LEN);
+        assert_eq!(store.begin_at(&key(), start + Duration::from_secs(60)), IdempotencyOutcome::New);
+        assert_eq!(store.len(), MIN_SWEEP_LEN + 1);
+    }
+}
@@ -1 +1,442 @@
-pub type JwtToken = String;
\ No newline at end of file
+use std::fmt::{self, Display};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ed25519_compact::{PublicKey, SecretKey, Signature};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::bucket::bucket_guid::BucketGuid;
+use crate::bucket::bucket_permission::BucketPermissionFlags;
+
+/// Shortest HS256 secret accepted, the size of the SHA-256 output.
+pub const HS256_MIN_KEY_LENGTH: usize = 32;
+
+/// Default clock-skew tolerance for `exp`, `nbf` and `iat`.
+pub const DEFAULT_LEEWAY: Duration = Duration::seconds(60);
+
+#[derive(thiserror::Error, Debug)]
+pub enum JwtError {
+    #[error("Token must have 3 dot separated parts")]
+    Malformed,
+    #[error("Invalid base64url encoding: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Token is signed with {actual}, the key is for {expected}")]
+    AlgorithmMismatch { expected: JwtAlgorithm, actual: JwtAlgorithm },
+    #[error("HS256 key must be at least {} bytes", HS256_MIN_KEY_LENGTH)]
+    InvalidKey,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token is not valid yet")]
+    NotYetValid,
+    #[error("Token was issued in the future")]
+    IssuedInFuture,
+    #[error("Token is not meant for this audience")]
+    InvalidAudience,
+    #[error("Token is not issued by the expected issuer")]
+    InvalidIssuer,
+}
+
+/// Supported signature algorithms, `none` is never accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+pub enum JwtAlgorithm {
+    EdDSA,
+    HS256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtHeader {
+    pub alg: JwtAlgorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    /// Id of the key the token is signed with, for key rotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+/// The `aud` claim is either a single string or an array of strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    pub fn contains(&self, audience: &str) -> bool {
+        match self {
+            Audience::Single(value) => value == audience,
+            Audience::Multiple(values) => values.iter().any(|value| value == audience),
+        }
+    }
+}
+
+/// Permissions the token grants on one bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketScope {
+    pub bucket: BucketGuid,
+    pub permissions: BucketPermissionFlags,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtClaims {
+    /// The user the token is issued to.
+    pub sub: Uuid,
+    #[serde(with = "time::serde::timestamp")]
+    pub exp: OffsetDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "time::serde::timestamp::option")]
+    pub nbf: Option<OffsetDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "time::serde::timestamp::option")]
+    pub iat: Option<OffsetDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<BucketScope>,
+}
+
+impl JwtClaims {
+    /// Whether the token grants every permission in `permissions` on `bucket`.
+    pub fn has_scope(&self, bucket: &BucketGuid, permissions: BucketPermissionFlags) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| &scope.bucket == bucket && scope.permissions.contains(permissions))
+    }
+}
+
+pub enum JwtSigningKey {
+    EdDSA(SecretKey),
+    HS256(Zeroizing<Vec<u8>>),
+}
+
+pub enum JwtVerificationKey {
+    EdDSA(PublicKey),
+    HS256(Zeroizing<Vec<u8>>),
+}
+
+impl JwtSigningKey {
+    pub fn algorithm(&self) -> JwtAlgorithm {
+        match self {
+            JwtSigningKey::EdDSA(_) => JwtAlgorithm::EdDSA,
+            JwtSigningKey::HS256(_) => JwtAlgorithm::HS256,
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, JwtError> {
+        match self {
+            JwtSigningKey::EdDSA(secret_key) => Ok(secret_key.sign(message, None).to_vec()),
+            JwtSigningKey::HS256(secret) => Ok(hs256(secret, message)?.finalize().into_bytes().to_vec()),
+        }
+    }
+}
+
+impl JwtVerificationKey {
+    pub fn algorithm(&self) -> JwtAlgorithm {
+        match self {
+            JwtVerificationKey::EdDSA(_) => JwtAlgorithm::EdDSA,
+            JwtVerificationKey::HS256(_) => JwtAlgorithm::HS256,
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), JwtError> {
+        match self {
+            JwtVerificationKey::EdDSA(public_key) => {
+                let signature = Signature::from_slice(signature).map_err(|_| JwtError::InvalidSignature)?;
+                public_key.verify(message, &signature).map_err(|_| JwtError::InvalidSignature)
+            }
+            // Constant time comparison.
+            JwtVerificationKey::HS256(secret) => hs256(secret, message)?
+                .verify_slice(signature)
+                .map_err(|_| JwtError::InvalidSignature),
+        }
+    }
+}
+
+fn hs256(secret: &[u8], message: &[u8]) -> Result<Hmac<Sha256>, JwtError> {
+    if secret.len() < HS256_MIN_KEY_LENGTH {
+        return Err(JwtError::InvalidKey);
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| JwtError::InvalidKey)?;
+    mac.update(message);
+    Ok(mac)
+}
+
+/// What is checked besides the signature, `exp` is always checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JwtValidation {
+    /// Clock-skew tolerance for `exp`, `nbf` and `iat`.
+    pub leeway: Duration,
+    /// When set the `aud` claim must contain it.
+    pub audience: Option<String>,
+    /// When set the `iss` claim must be equal to it.
+    pub issuer: Option<String>,
+}
+
+impl Default for JwtValidation {
+    fn default() -> Self {
+        Self {
+            leeway: DEFAULT_LEEWAY,
+            audience: None,
+            issuer: None,
+        }
+    }
+}
+
+impl JwtValidation {
+    /// The leeway is applied to `now`, saturating, so a claim at the edge of the date range can't overflow.
+    pub fn validate(&self, claims: &JwtClaims, now: OffsetDateTime) -> Result<(), JwtError> {
+        let earliest = now.saturating_sub(self.leeway);
+        let latest = now.saturating_add(self.leeway);
+        if claims.exp <= earliest {
+            return Err(JwtError::Expired);
+        }
+        if claims.nbf.is_some_and(|nbf| nbf > latest) {
+            return Err(JwtError::NotYetValid);
+        }
+        if claims.iat.is_some_and(|iat| iat > latest) {
+            return Err(JwtError::IssuedInFuture);
+        }
+        if let Some(audience) = &self.audience {
+            if !claims.aud.as_ref().is_some_and(|aud| aud.contains(audience)) {
+                return Err(JwtError::InvalidAudience);
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(JwtError::InvalidIssuer);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A signed JWT (JWS compact serialization), only constructed by signing or by verifying a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JwtToken {
+    pub header: JwtHeader,
+    pub claims: JwtClaims,
+    encoded: String,
+}
+
+impl JwtToken {
+    pub fn sign(claims: JwtClaims, key: &JwtSigningKey, kid: Option<String>) -> Result<Self, JwtError> {
+        let header = JwtHeader {
+            alg: key.algorithm(),
+            typ: Some("JWT".to_string()),
+            kid,
+        };
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = key.sign(signing_input.as_bytes())?;
+        let encoded = format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature));
+        Ok(Self { header, claims, encoded })
+    }
+
+    /// Verifies the signature and validates the claims against the current time.
+    pub fn verify(token: &str, key: &JwtVerificationKey, validation: &JwtValidation) -> Result<Self, JwtError> {
+        Self::verify_at(token, key, validation, OffsetDateTime::now_utc())
+    }
+
+    /// Verifies the signature and validates the claims against `now`.
+    /// The algorithm comes from the key, a header naming another algorithm is rejected.
+    pub fn verify_at(
+        token: &str,
+        key: &JwtVerificationKey,
+        validation: &JwtValidation,
+        now: OffsetDateTime,
+    ) -> Result<Self, JwtError> {
+        let (signing_input, signature) = token.rsplit_once('.').ok_or(JwtError::Malformed)?;
+        let (header, claims) = signing_input.split_once('.').ok_or(JwtError::Malformed)?;
+        if claims.contains('.') {
+            return Err(JwtError::Malformed);
+        }
+
+        let header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+        if header.alg != key.algorithm() {
+            return Err(JwtError::AlgorithmMismatch {
+                expected: key.algorithm(),
+                actual: header.alg,
+            });
+        }
+        key.verify(signing_input.as_bytes(), &URL_SAFE_NO_PAD.decode(signature)?)?;
+
+        let claims: JwtClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+        validation.validate(&claims, now)?;
+        Ok(Self {
+            header,
+            claims,
+            encoded: token.to_string(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.encoded
+    }
+
+    pub fn into_string(self) -> String {
+        self.encoded
+    }
+}
+
+impl Display for JwtToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_compact::{KeyPair, Seed};
+    use time::PrimitiveDateTime;
+
+    use super::*;
+
+    fn claims(now: OffsetDateTime) -> JwtClaims {
+        JwtClaims {
+            sub: Uuid::new_v4(),
+            exp: now + Duration::minutes(5),
+            nbf: Some(now),
+            iat: Some(now),
+            iss: Some("bucketdrive.co".to_string()),
+            aud: Some(Audience::Multiple(vec!["storage".to_string(), "search".to_string()])),
+            scopes: vec![BucketScope {
+                bucket: BucketGuid::generate(),
+                permissions: BucketPermissionFlags::VIEW | BucketPermissionFlags::READ,
+            }],
+        }
+    }
+
+    fn hs256_key() -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(vec![7u8; HS256_MIN_KEY_LENGTH])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let key_pair = KeyPair::from_seed(Seed::default());
+        let validation = JwtValidation {
+            audience: Some("storage".to_string()),
+            issuer: Some("bucketdrive.co".to_string()),
+            ..Default::default()
+        };
+        for (signing_key, verification_key) in [
+            (JwtSigningKey::EdDSA(key_pair.sk.clone()), JwtVerificationKey::EdDSA(key_pair.pk)),
+            (JwtSigningKey::HS256(hs256_key()), JwtVerificationKey::HS256(hs256_key())),
+        ] {
+            let claims = claims(now);
+            let token = JwtToken::sign(claims.clone(), &signing_key, Some("key-1".to_string())).unwrap();
+            let verified = JwtToken::verify_at(token.as_str(), &verification_key, &validation, now).unwrap();
+            assert_eq!(verified, token);
+            assert!(verified.claims.has_scope(&claims.scopes[0].bucket, BucketPermissionFlags::READ));
+            assert!(!verified.claims.has_scope(&claims.scopes[0].bucket, BucketPermissionFlags::WRITE));
+        }
+    }
+
+    #[test]
+    fn test_tampered_and_confused_algorithm() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let token = JwtToken::sign(claims(now), &JwtSigningKey::HS256(hs256_key()), None).unwrap();
+        let validation = JwtValidation::default();
+
+        let (header, rest) = token.as_str().split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged_claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims(now)).unwrap());
+        let forged = format!("{}.{}.{}", header, forged_claims, signature);
+        assert!(matches!(
+            JwtToken::verify_at(&forged, &JwtVerificationKey::HS256(hs256_key()), &validation, now),
+            Err(JwtError::InvalidSignature)
+        ));
+
+        let key_pair = KeyPair::from_seed(Seed::default());
+        assert!(matches!(
+            JwtToken::verify_at(token.as_str(), &JwtVerificationKey::EdDSA(key_pair.pk), &validation, now),
+            Err(JwtError::AlgorithmMismatch { expected: JwtAlgorithm::EdDSA, actual: JwtAlgorithm::HS256 })
+        ));
+        assert!(matches!(
+            JwtToken::verify_at("a.b", &JwtVerificationKey::HS256(hs256_key()), &validation, now),
+            Err(JwtError::Malformed)
+        ));
+        assert!(matches!(
+            JwtToken::sign(claims(now), &JwtSigningKey::HS256(Zeroizing::new(vec![1; 8])), None),
+            Err(JwtError::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn test_time_claims_with_leeway() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let key = JwtSigningKey::HS256(hs256_key());
+        let verification_key = JwtVerificationKey::HS256(hs256_key());
+        let validation = JwtValidation::default();
+        let token = JwtToken::sign(claims(now), &key, None).unwrap();
+
+        let just_expired = now + Duration::minutes(5) + Duration::seconds(30);
+        assert!(JwtToken::verify_at(token.as_str(), &verification_key, &validation, just_expired).is_ok());
+        let expired = now + Duration::minutes(7);
+        assert!(matches!(
+            JwtToken::verify_at(token.as_str(), &verification_key, &validation, expired),
+            Err(JwtError::Expired)
+        ));
+
+        let skewed = now - Duration::seconds(30);
+        assert!(JwtToken::verify_at(token.as_str(), &verification_key, &validation, skewed).is_ok());
+        let early = now - Duration::minutes(2);
+        assert!(matches!(
+            JwtToken::verify_at(token.as_str(), &verification_key, &validation, early),
+            Err(JwtError::NotYetValid)
+        ));
+
+        let wrong_audience = JwtValidation {
+            audience: Some("billing".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            JwtToken::verify_at(token.as_str(), &verification_key, &wrong_audience, now),
+            Err(JwtError::InvalidAudience)
+        ));
+    }
+
+    #[test]
+    fn test_time_claims_at_the_edge_of_the_date_range() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let validation = JwtValidation::default();
+
+        let mut extreme = claims(now);
+        extreme.exp = PrimitiveDateTime::MAX.assume_utc();
+        extreme.nbf = Some(PrimitiveDateTime::MIN.assume_utc());
+        extreme.iat = Some(PrimitiveDateTime::MIN.assume_utc());
+        assert!(validation.validate(&extreme, now).is_ok());
+
+        let huge_leeway = JwtValidation {
+            leeway: Duration::MAX,
+            ..Default::default()
+        };
+        assert!(huge_leeway.validate(&extreme, now).is_ok());
+        assert!(huge_leeway.validate(&claims(now), now).is_ok());
+
+        let mut expired = claims(now);
+        expired.exp = PrimitiveDateTime::MIN.assume_utc();
+        assert!(matches!(validation.validate(&expired, now), Err(JwtError::Expired)));
+
+        let mut not_yet_valid = claims(now);
+        not_yet_valid.exp = PrimitiveDateTime::MAX.assume_utc();
+        not_yet_valid.nbf = Some(PrimitiveDateTime::MAX.assume_utc());
+        assert!(matches!(validation.validate(&not_yet_valid, now), Err(JwtError::NotYetValid)));
+
+        let mut issued_in_future = claims(now);
+        issued_in_future.iat = Some(PrimitiveDateTime::MAX.assume_utc());
+        assert!(matches!(validation.validate(&issued_in_future, now), Err(JwtError::IssuedInFuture)));
+    }
+}
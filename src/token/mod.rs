@@ -1,4 +1,5 @@
 pub mod access_token;
 pub mod idempotency_token;
 pub mod bearer_token;
-pub mod jwt_token;
\ No newline at end of file
+pub mod jwt_token;
+pub mod capability_token;
\ No newline at end of file